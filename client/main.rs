@@ -127,6 +127,39 @@ fn mask_cursor(vnc_in_format: vnc::PixelFormat, in_pixels: Vec<u8>, mask_pixels:
     (out_format, out_cursor.into_inner())
 }
 
+fn save_screenshot(vnc: &mut vnc::Client, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // RGB888, so that every pixel is stored as B, G, R, X.
+    if vnc.format() != FORMAT_MAP[0].1 {
+        vnc.set_format(FORMAT_MAP[0].1)?;
+    }
+    vnc.set_encodings(&[vnc::Encoding::Zrle, vnc::Encoding::CopyRect, vnc::Encoding::Raw])?;
+    let (rect, pixels, _) = vnc.capture_frame()?;
+
+    let mut rgb = Vec::with_capacity(rect.area() * 3);
+    for pixel in pixels.chunks(4) {
+        rgb.extend_from_slice(&[pixel[2], pixel[1], pixel[0]])
+    }
+
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, rect.width as u32, rect.height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&rgb)?;
+    info!("saved {}x{} screenshot to {}", rect.width, rect.height, path);
+    Ok(())
+}
+
+fn set_encodings(vnc: &mut vnc::Client, qemu_hacks: bool) {
+    if qemu_hacks {
+        vnc.set_encodings(&[vnc::Encoding::Zrle, vnc::Encoding::DesktopSize]).unwrap()
+    } else {
+        vnc.set_encodings(&[
+            vnc::Encoding::Zrle, vnc::Encoding::CopyRect, vnc::Encoding::Raw,
+            vnc::Encoding::Cursor, vnc::Encoding::DesktopSize
+        ]).unwrap()
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -156,6 +189,13 @@ fn main() {
         .arg(Arg::with_name("QEMU-HACKS")
                 .help("hack around QEMU/XenHVM's braindead VNC server")
                 .long("heinous-qemu-hacks"))
+        .arg(Arg::with_name("SCREENSHOT")
+                .help("save one frame to a PNG file and exit")
+                .long("screenshot")
+                .takes_value(true))
+        .arg(Arg::with_name("RECONNECT")
+                .help("keep reconnecting when the connection is lost")
+                .long("reconnect"))
         .get_matches();
 
     let host = matches.value_of("HOST").unwrap();
@@ -165,59 +205,68 @@ fn main() {
     let exclusive = matches.is_present("EXCLUSIVE");
     let view_only = matches.is_present("VIEW-ONLY");
     let qemu_hacks = matches.is_present("QEMU-HACKS");
+    let screenshot = matches.value_of("SCREENSHOT");
+    let reconnect = matches.is_present("RECONNECT");
 
-    let sdl_context = sdl2::init().unwrap();
-    let sdl_video = sdl_context.video().unwrap();
-    let mut sdl_timer = sdl_context.timer().unwrap();
-    let mut sdl_events = sdl_context.event_pump().unwrap();
-
-    info!("connecting to {}:{}", host, port);
-    let stream =
-        match std::net::TcpStream::connect((host, port)) {
-            Ok(stream) => stream,
-            Err(error) => {
-                error!("cannot connect to {}:{}: {}", host, port, error);
-                std::process::exit(1)
-            }
-        };
-
-    let mut vnc =
-        match vnc::Client::from_tcp_stream(stream, !exclusive, |methods| {
-            debug!("available authentication methods: {:?}", methods);
-            for method in methods {
-                match method {
-                    vnc::client::AuthMethod::None =>
-                        return Some(vnc::client::AuthChoice::None),
-                    vnc::client::AuthMethod::Password => {
-                        return match password {
-                            None => None,
-                            Some(ref password) => {
-                                let mut key = [0; 8];
-                                for (i, byte) in password.bytes().enumerate() {
-                                    if i == 8 { break }
-                                    key[i] = byte
-                                }
-                                Some(vnc::client::AuthChoice::Password(key))
+    let builder = vnc::client::Builder::new().shared(!exclusive);
+    let policy = vnc::reconnect::RetryPolicy {
+        max_attempts: if reconnect { 0 } else { 1 },
+        ..vnc::reconnect::RetryPolicy::default()
+    };
+    let auth = |methods: &[vnc::client::AuthMethod]| {
+        debug!("available authentication methods: {:?}", methods);
+        for method in methods {
+            match method {
+                vnc::client::AuthMethod::None =>
+                    return Some(vnc::client::AuthChoice::None),
+                vnc::client::AuthMethod::Password => {
+                    return match password {
+                        None => None,
+                        Some(ref password) => {
+                            let mut key = [0; 8];
+                            for (i, byte) in password.bytes().enumerate() {
+                                if i == 8 { break }
+                                key[i] = byte
                             }
+                            Some(vnc::client::AuthChoice::Password(key))
                         }
+                    }
+                },
+                vnc::client::AuthMethod::AppleRemoteDesktop =>
+                    match (username, password) {
+                        (Some(username), Some(password)) =>
+                            return Some(vnc::client::AuthChoice::AppleRemoteDesktop(
+                                username.to_owned(), password.to_owned()
+                            )),
+                        _ =>
+                            ()
                     },
-                    vnc::client::AuthMethod::AppleRemoteDesktop =>
-                        match (username, password) {
-                            (Some(username), Some(password)) =>
-                                return Some(vnc::client::AuthChoice::AppleRemoteDesktop(
-                                    username.to_owned(), password.to_owned()
-                                )),
-                            _ =>
-                                ()
-                        },
-                    _ => ()
-                }
+                vnc::client::AuthMethod::Tight => {
+                    let inner = match password {
+                        None => vnc::client::AuthChoice::None,
+                        Some(ref password) => {
+                            let mut key = [0; 8];
+                            for (i, byte) in password.bytes().enumerate() {
+                                if i == 8 { break }
+                                key[i] = byte
+                            }
+                            vnc::client::AuthChoice::Password(key)
+                        }
+                    };
+                    return Some(vnc::client::AuthChoice::Tight(Box::new(inner)))
+                },
+                _ => ()
             }
-            None
-        }) {
+        }
+        None
+    };
+
+    info!("connecting to {}:{}", host, port);
+    let mut vnc =
+        match vnc::reconnect::connect_with_retry((host, port), &builder, &auth, &policy) {
             Ok(vnc) => vnc,
             Err(error) => {
-                error!("cannot initialize VNC session: {}", error);
+                error!("cannot connect to {}:{}: {}", host, port, error);
                 std::process::exit(1)
             }
         };
@@ -225,6 +274,20 @@ fn main() {
     let (mut width, mut height) = vnc.size();
     info!("connected to \"{}\", {}x{} framebuffer", vnc.name(), width, height);
 
+    if let Some(path) = screenshot {
+        if let Err(error) = save_screenshot(&mut vnc, path) {
+            error!("cannot save screenshot to {}: {}", path, error);
+            std::process::exit(1)
+        }
+        let _ = vnc.disconnect();
+        return
+    }
+
+    let sdl_context = sdl2::init().unwrap();
+    let sdl_video = sdl_context.video().unwrap();
+    let mut sdl_timer = sdl_context.timer().unwrap();
+    let mut sdl_events = sdl_context.event_pump().unwrap();
+
     let mut vnc_format = vnc.format();
     info!("received {:?}", vnc_format);
 
@@ -242,14 +305,7 @@ fn main() {
         };
     info!("rendering to a {:?} texture", sdl_format);
 
-    if qemu_hacks {
-        vnc.set_encodings(&[vnc::Encoding::Zrle, vnc::Encoding::DesktopSize]).unwrap()
-    } else {
-        vnc.set_encodings(&[
-            vnc::Encoding::Zrle, vnc::Encoding::CopyRect, vnc::Encoding::Raw,
-            vnc::Encoding::Cursor, vnc::Encoding::DesktopSize
-        ]).unwrap()
-    }
+    set_encodings(&mut vnc, qemu_hacks);
 
     let window = sdl_video.window(&format!("{} - {}:{} - RVNC", vnc.name(), host, port),
                                   width as u32, height as u32).build().unwrap();
@@ -263,7 +319,7 @@ fn main() {
     let mut cursor_rect = None;
     let (mut hotspot_x, mut hotspot_y) = (0u16, 0u16);
 
-    let mut mouse_buttons = 0u8;
+    let mut mouse_buttons = vnc::PointerButtons::NONE;
     let (mut mouse_x,   mut mouse_y)   = (0u16, 0u16);
 
     let mut key_ctrl = false;
@@ -282,14 +338,21 @@ fn main() {
 
         renderer.present();
 
+        let mut disconnected = false;
         for event in vnc.poll_iter() {
             use vnc::client::Event;
 
             match event {
+                Event::Disconnected(None) if reconnect => {
+                    disconnected = true;
+                    break
+                }
                 Event::Disconnected(None) => break 'running,
                 Event::Disconnected(Some(error)) => {
                     error!("server disconnected: {:?}", error);
-                    break 'running
+                    if !reconnect { break 'running }
+                    disconnected = true;
+                    break
                 }
                 Event::Resize(new_width, new_height) => {
                     width  = new_width;
@@ -373,6 +436,33 @@ fn main() {
             if sdl_timer.ticks() - ticks > FRAME_MS { continue 'running }
         }
 
+        if disconnected {
+            info!("reconnecting to {}:{}", host, port);
+            // The format is known by now, so the server never sends pixels in another one.
+            let builder = builder.clone().initial_format(vnc_format);
+            vnc = match vnc::reconnect::connect_with_retry((host, port), &builder, &auth,
+                                                           &policy) {
+                Ok(vnc) => vnc,
+                Err(error) => {
+                    error!("cannot reconnect to {}:{}: {}", host, port, error);
+                    break 'running
+                }
+            };
+            set_encodings(&mut vnc, qemu_hacks);
+
+            if vnc.size() != (width, height) {
+                let (new_width, new_height) = vnc.size();
+                width  = new_width;
+                height = new_height;
+                renderer.window_mut().unwrap().set_size(width as u32, height as u32);
+                screen = renderer.create_texture_streaming(
+                    sdl_format, (width as u32, height as u32)).unwrap();
+            }
+            vnc.request_update(vnc::Rect { left: 0, top: 0, width, height },
+                               false).unwrap();
+            continue 'running
+        }
+
         match cursor_rect {
             Some(cursor_rect) =>
                 renderer.copy(&screen, Some(cursor_rect), Some(cursor_rect)),
@@ -437,9 +527,7 @@ fn main() {
                     }
                 },
                 Event::TextInput { text, .. } => {
-                    let chr = 0x01000000 + text.chars().next().unwrap() as u32;
-                    vnc.send_key_event(true, chr).unwrap();
-                    vnc.send_key_event(false, chr).unwrap()
+                    vnc.type_text(&text).unwrap()
                 }
                 Event::MouseMotion { x, y, .. } => {
                     mouse_x = x as u16;
@@ -455,12 +543,12 @@ fn main() {
                     mouse_y = y as u16;
                     let mouse_button =
                         match mouse_btn {
-                            Mouse::Left       => 0x01,
-                            Mouse::Middle     => 0x02,
-                            Mouse::Right      => 0x04,
-                            Mouse::X1         => 0x20,
-                            Mouse::X2         => 0x40,
-                            Mouse::Unknown(_) => 0x00
+                            Mouse::Left       => vnc::PointerButtons::LEFT,
+                            Mouse::Middle     => vnc::PointerButtons::MIDDLE,
+                            Mouse::Right      => vnc::PointerButtons::RIGHT,
+                            Mouse::X1         => vnc::PointerButtons(0x20),
+                            Mouse::X2         => vnc::PointerButtons(0x40),
+                            Mouse::Unknown(_) => vnc::PointerButtons::NONE
                         };
                     match event {
                         Event::MouseButtonDown { .. } => mouse_buttons |= mouse_button,
@@ -469,14 +557,9 @@ fn main() {
                     };
                     vnc.send_pointer_event(mouse_buttons, mouse_x, mouse_y).unwrap()
                 },
-                Event::MouseWheel { y, .. } => {
-                    if y == 1 {
-                        vnc.send_pointer_event(mouse_buttons | 0x08, mouse_x, mouse_y).unwrap();
-                        vnc.send_pointer_event(mouse_buttons, mouse_x, mouse_y).unwrap();
-                    } else if y == -1 {
-                        vnc.send_pointer_event(mouse_buttons | 0x10, mouse_x, mouse_y).unwrap();
-                        vnc.send_pointer_event(mouse_buttons, mouse_x, mouse_y).unwrap();
-                    }
+                Event::MouseWheel { x, y, .. } => {
+                    let clamp = |delta: i32| delta.max(-127).min(127) as i8;
+                    vnc.send_scroll(clamp(x), clamp(y), mouse_x, mouse_y).unwrap()
                 }
                 Event::ClipboardUpdate { .. } => {
                     vnc.update_clipboard(&sdl_video.clipboard().clipboard_text().unwrap()).unwrap()
@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vnc::PixelFormat;
+
+const FORMATS: [PixelFormat; 3] = [
+    PixelFormat {
+        bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+        red_max: 255, green_max: 255, blue_max: 255,
+        red_shift: 16, green_shift: 8, blue_shift: 0
+    },
+    PixelFormat {
+        bits_per_pixel: 16, depth: 16, big_endian: true, true_colour: true,
+        red_max: 31, green_max: 63, blue_max: 31,
+        red_shift: 11, green_shift: 5, blue_shift: 0
+    },
+    PixelFormat {
+        bits_per_pixel: 8, depth: 8, big_endian: false, true_colour: false,
+        red_max: 0, green_max: 0, blue_max: 0,
+        red_shift: 0, green_shift: 0, blue_shift: 0
+    },
+];
+
+// The first byte picks the pixel format, the rest is the update.
+fuzz_target!(|data: &[u8]| {
+    if let Some((&format, update)) = data.split_first() {
+        let _ = vnc::client::decode_frame(&FORMATS[format as usize % FORMATS.len()], update);
+    }
+});
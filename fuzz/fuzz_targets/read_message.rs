@@ -0,0 +1,9 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vnc::protocol::{self, Message};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = protocol::S2C::read_from(&mut &data[..]);
+    let _ = protocol::C2S::read_from(&mut &data[..]);
+    let _ = protocol::ServerInit::read_from(&mut &data[..]);
+});
@@ -73,6 +73,8 @@ fn main() {
                     continue
                 }
             };
+        let (width, height) = proxy.size();
+        info!("proxying \"{}\", {}x{} framebuffer", proxy.name(), width, height);
 
         match proxy.join() {
             Ok(()) => info!("session ended"),
@@ -1,14 +1,28 @@
-use std::io::{Read, Write};
-use std::net::{TcpStream, Shutdown};
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use std::io::{self, Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, Shutdown};
 use std::thread;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
-use byteorder::{BigEndian, ReadBytesExt};
-use crate::{zrle, protocol, Rect, Colour, Error, Result};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::{io::{AsRawFd, RawFd}, net::UnixStream};
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, Receiver, SendError,
+                      TryRecvError};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crate::{zrle, hextile, protocol, keysym, Rect, Screen, PointerButtons, Colour, Error, Result};
 use protocol::Message;
-use crate::security::des;
+use crate::security::vnc_auth_response;
 #[cfg(feature = "apple-auth")]
 use security::apple_auth;
+#[cfg(feature = "rsa-aes")]
+use crate::security::rsa_aes::{self, Session};
+
+// Without the rsa-aes feature, no security type encrypts the connection.
+#[cfg(not(feature = "rsa-aes"))]
+enum Session {}
 
 #[derive(Debug)]
 #[non_exhaustive]
@@ -16,6 +30,9 @@ pub enum AuthMethod {
     None,
     Password,
     AppleRemoteDesktop,
+    Tight,
+    // Either of the RSA-AES security types; only offered with the rsa-aes feature.
+    RsaAes,
     /* more to come */
 }
 
@@ -25,25 +42,105 @@ pub enum AuthChoice {
     None,
     Password([u8; 8]),
     AppleRemoteDesktop(String, String),
+    // Only None and Password can be used inside of Tight.
+    Tight(Box<AuthChoice>),
+    // Username and password. The username is only sent if the server asks for it, and
+    // the whole session is encrypted if the server offers that.
+    RsaAes(String, String),
     /* more to come */
 }
 
 #[derive(Debug)]
 pub enum Event {
     Disconnected(Option<Error>),
+    // From the plain DesktopSize pseudo-encoding, which can't tell a resize started by
+    // the server from one requested with Client::set_desktop_size, nor report failure.
     Resize(u16, u16),
+    // From ExtendedDesktopSize. `reason` is 0 if the server resized the framebuffer,
+    // 1 if this client requested it and 2 if another client did; `status` is 0 unless
+    // the request failed. If an update has both, only this is sent and not Resize.
+    ScreenLayout { width: u16, height: u16, reason: u16, status: u16, screens: Vec<Screen> },
     SetColourMap { first_colour: u16, colours: Vec<Colour> },
     PutPixels(Rect, Vec<u8>),
+    // Every PutPixels of an update at once, in order, without those that a later one
+    // completely covers; only sent instead of PutPixels if Builder::coalesce_frames is set.
+    Frame(Vec<(Rect, Vec<u8>)>),
     CopyPixels { src: Rect, dst: Rect },
     EndOfFrame,
     SetCursor { size: (u16, u16), hotspot: (u16, u16), pixels: Vec<u8>, mask_bits: Vec<u8> },
+    // The server moved the pointer, e.g. because an application warped it.
+    CursorPosition { x: u16, y: u16 },
+    DesktopName(String),
+    // Bit 0 is Scroll Lock, bit 1 Num Lock and bit 2 Caps Lock.
+    LedState(u8),
+    // The server confirmed the ExtendedKeyEvent pseudo-encoding; see
+    // Client::send_extended_key_event.
+    ExtendedKeyEventSupported,
+    // The server switched between absolute (false) and relative (true) pointer motion;
+    // see Client::set_relative_pointer.
+    RelativePointer(bool),
     Clipboard(String),
     Bell,
+    ContinuousUpdatesEnded,
+    // The pixel format was switched by Client::set_format; sent right before the events
+    // of the first message received after the switch.
+    FormatChanged(protocol::PixelFormat),
+    // The server did something it shouldn't have, but the connection can go on;
+    // whatever it sent was ignored.
+    ProtocolWarning(String),
+    // Sent right before EndOfFrame if Builder::emit_stats is set. `bytes` includes
+    // the message and rectangle headers; `decode_time` is the time from the start of
+    // the message to its end, including any time spent waiting for the data to arrive.
+    FrameStats {
+        rectangles:  usize,
+        bytes:       usize,
+        encodings:   Vec<protocol::Encoding>,
+        decode_time: Duration,
+    },
+}
+
+// Counts the events that are in the channel but have not been received yet,
+// since a Receiver cannot be asked that without taking one out.
+enum EventChannel {
+    Unbounded(Sender<Event>),
+    Bounded(SyncSender<Event>),
+}
+
+struct EventSender {
+    sender:  EventChannel,
+    pending: Arc<AtomicUsize>,
+    #[cfg(unix)]
+    wakeup:  UnixStream,
+}
+
+impl EventSender {
+    fn send(&self, event: Event) -> std::result::Result<(), SendError<Event>> {
+        // Incremented first, so that the receiving side never sees the counter go below zero.
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst);
+        let result = match self.sender {
+            EventChannel::Unbounded(ref sender) => sender.send(event),
+            EventChannel::Bounded(ref sender) => sender.send(event),
+        };
+        result.map_err(|error| { self.pending.fetch_sub(1, Ordering::SeqCst); error })?;
+        // Only the first of a batch of events needs to wake the application up; if the
+        // socket buffer is somehow full, it is already readable, which is all that matters.
+        #[cfg(unix)]
+        {
+            if pending == 0 {
+                let _ = (&self.wakeup).write(&[0]);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = pending;
+        Ok(())
+    }
 }
 
 impl Event {
-    fn pump(mut stream: TcpStream, format: Arc<Mutex<protocol::PixelFormat>>,
-            tx_events: &mut Sender<Event>) -> Result<()> {
+    fn pump<R: Read>(mut stream: CountingReader<R>, format: Arc<Mutex<protocol::PixelFormat>>,
+                     native_endian: Arc<AtomicBool>, rgba_output: Arc<AtomicBool>,
+                     paused: Arc<(Mutex<bool>, Condvar)>,
+                     mut decoder: Decoder, tx_events: &mut EventSender) -> Result<()> {
         macro_rules! send {
             ($chan:expr, $data:expr) => ({
                 match $chan.send($data) {
@@ -53,10 +150,14 @@ impl Event {
             })
         }
 
-        let mut zrle_decoder = zrle::Decoder::new();
+        let mut last_format = *format.lock().unwrap();
+        // Only needed for RGBA output; Client keeps its own copy for Client::palette.
+        let mut palette = Vec::new();
         loop {
+            let bytes_before = decoder.counters.bytes_received.load(Ordering::Relaxed);
             let packet =
-                match protocol::S2C::read_from(&mut stream) {
+                match protocol::S2C::read_limited(&mut stream, decoder.max_length)
+                        .map_err(|error| stream.disconnected(error)) {
                     Ok(packet) => packet,
                     Err(Error::Disconnected) => {
                         send!(tx_events, Event::Disconnected(None));
@@ -64,80 +165,83 @@ impl Event {
                     },
                     Err(error) => return Err(error)
                 };
-            debug!("<- {:?}", packet);
+            log_message!("<-", packet);
+
+            // See Client::pause. The rectangles of an update are left unread until resumed.
+            {
+                let (ref lock, ref resumed) = *paused;
+                let mut paused = lock.lock().unwrap();
+                while *paused {
+                    paused = resumed.wait(paused).unwrap()
+                }
+            }
 
             let format = *format.lock().unwrap();
+            if format != last_format {
+                last_format = format;
+                send!(tx_events, Event::FormatChanged(format))
+            }
             match packet {
-                protocol::S2C::SetColourMapEntries { first_colour, colours } => {
+                protocol::S2C::SetColourMapEntries { .. } if format.true_colour => {
+                    // Nothing would use the palette; more likely than not, the server
+                    // and the client disagree about the pixel format.
+                    warn!("ignoring a colour map sent for a true colour format");
+                    send!(tx_events, Event::ProtocolWarning(
+                        String::from("colour map sent for a true colour format")))
+                },
+                protocol::S2C::SetColourMapEntries { first_colour, mut colours } => {
+                    // A buggy server could send entries past index 65535, which don't exist.
+                    let valid = 0x10000 - first_colour as usize;
+                    if colours.len() > valid {
+                        warn!("ignoring {} colour map entries past the end",
+                              colours.len() - valid);
+                        colours.truncate(valid)
+                    }
+                    let end = first_colour as usize + colours.len();
+                    if palette.len() < end {
+                        palette.resize(end, Colour { red: 0, green: 0, blue: 0 })
+                    }
+                    palette[first_colour as usize..end].copy_from_slice(&colours);
                     send!(tx_events, Event::SetColourMap {
                         first_colour, colours,
                     })
                 },
                 protocol::S2C::FramebufferUpdate { count } => {
-                    for _ in 0..count {
-                        let rectangle = protocol::Rectangle::read_from(&mut stream)?;
-                        debug!("<- {:?}", rectangle);
-
-                        let dst = Rect {
-                            left:   rectangle.x_position,
-                            top:    rectangle.y_position,
-                            width:  rectangle.width,
-                            height: rectangle.height
-                        };
-                        match rectangle.encoding {
-                            protocol::Encoding::Raw => {
-                                let length = (rectangle.width as usize) *
-                                             (rectangle.height as usize) *
-                                             (format.bits_per_pixel as usize / 8);
-                                let mut pixels = Vec::with_capacity(length);
-                                unsafe { pixels.set_len(length as usize) }
-                                stream.read_exact(&mut pixels)?;
-                                debug!("<- ...pixels");
-                                send!(tx_events, Event::PutPixels(dst, pixels))
-                            },
-                            protocol::Encoding::CopyRect => {
-                                let copy_rect = protocol::CopyRect::read_from(&mut stream)?;
-                                let src = Rect {
-                                    left:   copy_rect.src_x_position,
-                                    top:    copy_rect.src_y_position,
-                                    width:  rectangle.width,
-                                    height: rectangle.height
-                                };
-                                send!(tx_events, Event::CopyPixels { src, dst })
-                            },
-                            protocol::Encoding::Zrle => {
-                                let length = stream.read_u32::<BigEndian>()?;
-                                let mut data = Vec::with_capacity(length as usize);
-                                unsafe { data.set_len(length as usize) }
-                                stream.read_exact(&mut data)?;
-                                debug!("<- ...compressed pixels");
-                                let result = zrle_decoder.decode(format, dst, &data,
-                                    |tile, pixels| {
-                                        Ok(tx_events.send(Event::PutPixels(tile, pixels)).is_ok())
-                                    })?;
-                                if !result { break }
-                            }
-                            protocol::Encoding::Cursor => {
-                                let mut pixels    = vec![0; (rectangle.width as usize) *
-                                                            (rectangle.height as usize) *
-                                                            (format.bits_per_pixel as usize / 8)];
-                                stream.read_exact(&mut pixels)?;
-                                let mut mask_bits = vec![0; ((rectangle.width as usize + 7) / 8) *
-                                                            (rectangle.height as usize)];
-                                stream.read_exact(&mut mask_bits)?;
-                                send!(tx_events, Event::SetCursor {
-                                    size:      (rectangle.width, rectangle.height),
-                                    hotspot:   (rectangle.x_position, rectangle.y_position),
-                                    pixels,
-                                    mask_bits,
-                                })
-                            },
-                            protocol::Encoding::DesktopSize => {
-                                send!(tx_events,
-                                    Event::Resize(rectangle.width, rectangle.height))
-                            }
-                            _ => return Err(Error::Unexpected("encoding"))
+                    let started_at = Instant::now();
+                    let rgba_output = rgba_output.load(Ordering::Relaxed);
+                    decoder.set_native_endian(native_endian.load(Ordering::Relaxed) &&
+                                              !rgba_output);
+                    let palette = &palette;
+                    let decoded = decode_rectangles(&mut stream, count, format, &mut decoder,
+                                                    &mut |event| {
+                        let event = match event {
+                            Event::PutPixels(rect, pixels) if rgba_output =>
+                                Event::PutPixels(rect, rgba_pixels(format, palette, &pixels)),
+                            Event::Frame(rects) if rgba_output =>
+                                Event::Frame(rects.into_iter().map(|(rect, pixels)|
+                                    (rect, rgba_pixels(format, palette, &pixels))).collect()),
+                            event => event
                         };
+                        tx_events.send(event).is_ok()
+                    });
+                    match decoded.map_err(|error| stream.disconnected(error)) {
+                        Ok(true) => (),
+                        Ok(false) => break,
+                        Err(Error::Disconnected) => {
+                            send!(tx_events, Event::Disconnected(None));
+                            break
+                        },
+                        Err(error) => return Err(error)
+                    }
+
+                    if let Some(ref mut encodings) = decoder.encodings {
+                        let bytes_after = decoder.counters.bytes_received.load(Ordering::Relaxed);
+                        send!(tx_events, Event::FrameStats {
+                            rectangles:  count as usize,
+                            bytes:       (bytes_after - bytes_before) as usize,
+                            encodings:   mem::take(encodings),
+                            decode_time: started_at.elapsed(),
+                        })
                     }
 
                     send!(tx_events, Event::EndOfFrame);
@@ -145,7 +249,9 @@ impl Event {
                 protocol::S2C::Bell =>
                     send!(tx_events, Event::Bell),
                 protocol::S2C::CutText(text) =>
-                    send!(tx_events, Event::Clipboard(text))
+                    send!(tx_events, Event::Clipboard(text)),
+                protocol::S2C::EndOfContinuousUpdates =>
+                    send!(tx_events, Event::ContinuousUpdatesEnded)
             }
         }
 
@@ -153,48 +259,755 @@ impl Event {
     }
 }
 
-pub struct Client {
-    stream:  TcpStream,
-    events:  Receiver<Event>,
-    name:    String,
-    size:    (u16, u16),
-    format:  Arc<Mutex<protocol::PixelFormat>>
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    pub bytes_received:       u64,
+    pub frames:               u64,
+    pub raw_rectangles:       u64,
+    pub copy_rect_rectangles: u64,
+    pub zrle_rectangles:      u64,
+    // Cursor, DesktopSize and other pseudo-encodings.
+    pub pseudo_rectangles:    u64,
+    // Time spent decompressing, which excludes waiting for the data to arrive.
+    pub decode_time:          Duration,
 }
 
-impl Client {
-    pub fn from_tcp_stream<Auth>(mut stream: TcpStream, shared: bool,
-                                 auth: Auth) -> Result<Client>
-            where Auth: FnOnce(&[AuthMethod]) -> Option<AuthChoice> {
-        let version = protocol::Version::read_from(&mut stream)?;
-        debug!("<- Version::{:?}", version);
-        debug!("-> Version::{:?}", version);
-        protocol::Version::write_to(&version, &mut stream)?;
+// Updated by the event thread with relaxed atomic increments and read by Client::stats,
+// so a snapshot may be a few increments out of date, but never blocks the pump.
+#[derive(Debug, Default)]
+struct Counters {
+    bytes_received:       AtomicU64,
+    frames:               AtomicU64,
+    raw_rectangles:       AtomicU64,
+    copy_rect_rectangles: AtomicU64,
+    zrle_rectangles:      AtomicU64,
+    pseudo_rectangles:    AtomicU64,
+    decode_nanos:         AtomicU64,
+}
 
-        let security_types = match version {
-            protocol::Version::Rfb33 => {
-                let security_type = protocol::SecurityType::read_from(&mut stream)?;
-                debug!("<- SecurityType::{:?}", security_type);
-                if security_type == protocol::SecurityType::Invalid {
-                    vec![]
-                } else {
-                    vec![security_type]
+impl Counters {
+    fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ClientStats {
+        ClientStats {
+            bytes_received:       self.bytes_received.load(Ordering::Relaxed),
+            frames:               self.frames.load(Ordering::Relaxed),
+            raw_rectangles:       self.raw_rectangles.load(Ordering::Relaxed),
+            copy_rect_rectangles: self.copy_rect_rectangles.load(Ordering::Relaxed),
+            zrle_rectangles:      self.zrle_rectangles.load(Ordering::Relaxed),
+            pseudo_rectangles:    self.pseudo_rectangles.load(Ordering::Relaxed),
+            decode_time:          Duration::from_nanos(self.decode_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+struct CountingReader<R> {
+    inner:    R,
+    counters: Arc<Counters>,
+    // Whether the server has closed the connection.
+    eof:      bool,
+}
+
+impl<R> CountingReader<R> {
+    // The message parsers only return Error::Disconnected if the connection is closed
+    // between messages; closed in the middle of one, it means the same thing. Once the
+    // server closed the connection, any EOF error is due to that, and not e.g. to
+    // a truncated ZRLE stream, since all of its data is read before it is decompressed.
+    fn disconnected(&self, error: Error) -> Error {
+        match error {
+            Error::Io(ref error) if self.eof && error.kind() == io::ErrorKind::UnexpectedEof =>
+                Error::Disconnected,
+            error => error
+        }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count == 0 && !buf.is_empty() {
+            self.eof = true
+        }
+        Counters::add(&self.counters.bytes_received, count as u64);
+        Ok(count)
+    }
+}
+
+// Decodes rectangles with one particular encoding, typically an experimental or proprietary
+// one, for Builder::register_decoder or Decoder::register. `decode` must read exactly the
+// rectangle's data from `reader` and pass what it decoded to `emit`, returning false
+// as soon as `emit` does.
+pub trait RectangleDecoder: Send {
+    fn decode(&mut self, rect: Rect, reader: &mut dyn Read, format: protocol::PixelFormat,
+              emit: &mut dyn FnMut(Event) -> bool) -> Result<bool>;
+}
+
+// Every connection needs its own decoder state, so a Builder keeps factories.
+#[derive(Clone)]
+struct DecoderFactory(Arc<dyn Fn() -> Box<dyn RectangleDecoder> + Send + Sync>);
+
+impl std::fmt::Debug for DecoderFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("DecoderFactory")
+    }
+}
+
+// State kept between framebuffer updates; ZRLE uses a single zlib stream for the whole
+// connection, so one Decoder must see every update from a given server in order.
+pub struct Decoder {
+    zrle:          zrle::Decoder,
+    max_length:    usize,
+    native_endian: bool,
+    coalesce_frames: bool,
+    strict_encodings: bool,
+    counters:      Arc<Counters>,
+    custom:        Vec<(protocol::Encoding, Box<dyn RectangleDecoder>)>,
+    // The encodings of the rectangles in the current update, for Event::FrameStats.
+    encodings:     Option<Vec<protocol::Encoding>>,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder { Decoder::new() }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::with_max_length(protocol::DEFAULT_MAX_LENGTH)
+    }
+
+    // See Builder::max_message_length.
+    pub fn with_max_length(max_length: usize) -> Decoder {
+        Decoder {
+            zrle:          zrle::Decoder::new(),
+            max_length,
+            native_endian: false,
+            coalesce_frames: false,
+            strict_encodings: true,
+            counters:      Arc::new(Counters::default()),
+            custom:        Vec::new(),
+            encodings:     None,
+        }
+    }
+
+    // Bytes received are only counted by a Client, which owns the stream.
+    pub fn stats(&self) -> ClientStats { self.counters.snapshot() }
+
+    // See Client::set_native_endian.
+    pub fn set_native_endian(&mut self, native_endian: bool) {
+        self.native_endian = native_endian
+    }
+
+    // Rectangles with `encoding` are passed to `decoder`, even if the encoding is built in.
+    pub fn register(&mut self, encoding: protocol::Encoding,
+                    decoder: Box<dyn RectangleDecoder>) {
+        self.custom.retain(|&(other, _)| other != encoding);
+        self.custom.push((encoding, decoder))
+    }
+
+    // See Builder::coalesce_frames.
+    pub fn set_coalesce_frames(&mut self, coalesce_frames: bool) {
+        self.coalesce_frames = coalesce_frames
+    }
+
+    // See Builder::strict_encodings.
+    pub fn set_strict_encodings(&mut self, strict_encodings: bool) {
+        self.strict_encodings = strict_encodings
+    }
+}
+
+fn convert_pixels(native_endian: bool, format: protocol::PixelFormat, pixels: &mut [u8]) {
+    if native_endian && format.big_endian != cfg!(target_endian = "big") {
+        let bpp = format.bits_per_pixel as usize / 8;
+        if bpp > 1 {
+            for pixel in pixels.chunks_mut(bpp) { pixel.reverse() }
+        }
+    }
+}
+
+// Converts `pixels` in `format` to RGBA8888; see Client::set_rgba_output. Indices past
+// the end of `palette` are black.
+fn rgba_pixels(format: protocol::PixelFormat, palette: &[Colour], pixels: &[u8]) -> Vec<u8> {
+    let bpp = format.bits_per_pixel as usize / 8;
+    let scale = |value: u16, max: u16|
+        if max == 0 { 0 } else { (value as u32 * 255 / max as u32) as u8 };
+    let mut rgba = Vec::with_capacity(pixels.len() / bpp * 4);
+    for pixel in pixels.chunks_exact(bpp) {
+        let shift_in = |value: u32, &byte: &u8| value << 8 | byte as u32;
+        let value = if format.big_endian { pixel.iter().fold(0, shift_in) }
+                    else { pixel.iter().rev().fold(0, shift_in) };
+        let (red, green, blue) =
+            if format.true_colour {
+                let (red, green, blue) = format.extract_rgb(value);
+                (scale(red, format.red_max), scale(green, format.green_max),
+                 scale(blue, format.blue_max))
+            } else {
+                let colour = palette.get(value as usize)
+                    .unwrap_or(&Colour { red: 0, green: 0, blue: 0 });
+                ((colour.red >> 8) as u8, (colour.green >> 8) as u8, (colour.blue >> 8) as u8)
+            };
+        rgba.extend_from_slice(&[red, green, blue, 255])
+    }
+    rgba
+}
+
+// Reads one FramebufferUpdate message from `reader` and passes the contents of each of its
+// rectangles to `callback` as PutPixels, CopyPixels, SetCursor, Resize or ScreenLayout events.
+// This is what the event thread of a Client does, but without a thread or a channel.
+// Returns false if `callback` returned false and decoding was stopped early.
+pub fn decode_update<R, F>(reader: &mut R, format: protocol::PixelFormat,
+                           decoder: &mut Decoder, mut callback: F) -> Result<bool>
+        where R: Read, F: FnMut(Event) -> bool {
+    match protocol::S2C::read_limited(reader, decoder.max_length)? {
+        protocol::S2C::FramebufferUpdate { count } =>
+            decode_rectangles(reader, count, format, decoder, &mut callback),
+        _ => Err(Error::Unexpected("message type"))
+    }
+}
+
+// Decodes a FramebufferUpdate held in memory, starting right after the message type and
+// padding, i.e. with the number of rectangles, and returns its pixels. Other rectangles
+// are checked but dropped, and trailing data is an error. Since every length is bounded
+// by the size of `data`, this is suitable for fuzzing.
+pub fn decode_frame(format: &protocol::PixelFormat, data: &[u8])
+                   -> Result<Vec<(Rect, Vec<u8>)>> {
+    let mut reader = data;
+    let count = reader.read_u16::<BigEndian>()?;
+    let mut decoder = Decoder::with_max_length(data.len());
+    let mut rects = Vec::new();
+    decode_rectangles(&mut reader, count, *format, &mut decoder, &mut |event| {
+        if let Event::PutPixels(rect, pixels) = event { rects.push((rect, pixels)) }
+        true
+    })?;
+    if !reader.is_empty() {
+        return Err(Error::Unexpected("trailing data"))
+    }
+    Ok(rects)
+}
+
+fn flush_frame<F>(frame: &mut Vec<(Rect, Vec<u8>)>, callback: &mut F) -> bool
+        where F: FnMut(Event) -> bool {
+    frame.is_empty() || callback(Event::Frame(mem::take(frame)))
+}
+
+fn decode_rectangles<R, F>(stream: &mut R, count: u16, format: protocol::PixelFormat,
+                           decoder: &mut Decoder, callback: &mut F) -> Result<bool>
+        where R: Read, F: FnMut(Event) -> bool {
+    if !decoder.coalesce_frames {
+        return decode_each_rectangle(stream, count, format, decoder, callback)
+    }
+
+    // Anything other than pixels flushes the frame first, since e.g. CopyPixels depends
+    // on every PutPixels before it having been applied.
+    let mut frame = Vec::new();
+    let decoded = decode_each_rectangle(stream, count, format, decoder, &mut |event| {
+        match event {
+            Event::PutPixels(rect, pixels) => {
+                frame.retain(|(earlier, _): &(Rect, Vec<u8>)|
+                    rect.intersect(earlier) != Some(*earlier));
+                frame.push((rect, pixels));
+                true
+            }
+            event => flush_frame(&mut frame, callback) && callback(event)
+        }
+    })?;
+    Ok(decoded && flush_frame(&mut frame, callback))
+}
+
+fn decode_each_rectangle<R, F>(stream: &mut R, count: u16, format: protocol::PixelFormat,
+                               decoder: &mut Decoder, callback: &mut F) -> Result<bool>
+        where R: Read, F: FnMut(Event) -> bool {
+    macro_rules! send {
+        ($data:expr) => ({
+            if !callback($data) { return Ok(false) }
+        })
+    }
+
+    // The format may come from the application rather than the server.
+    format.check()?;
+    let max_length = decoder.max_length;
+    let native_endian = decoder.native_endian;
+    let strict_encodings = decoder.strict_encodings;
+    let counters = decoder.counters.clone();
+    // Servers may describe the same resize with both DesktopSize and ExtendedDesktopSize,
+    // so a Resize is held back until the next rectangle shows there is no ScreenLayout.
+    let mut pending_resize = None;
+    let mut screen_layout_sent = false;
+    for _ in 0..count {
+        let rectangle = protocol::Rectangle::read_from(stream)?;
+        debug!("<- {:?}", rectangle);
+        if rectangle.encoding == protocol::Encoding::ExtendedDesktopSize {
+            pending_resize = None
+        } else if let Some(resize) = pending_resize.take() {
+            send!(resize)
+        }
+        if let Some(ref mut encodings) = decoder.encodings {
+            encodings.push(rectangle.encoding)
+        }
+        Counters::add(match rectangle.encoding {
+            protocol::Encoding::Raw => &counters.raw_rectangles,
+            protocol::Encoding::CopyRect => &counters.copy_rect_rectangles,
+            protocol::Encoding::Zrle => &counters.zrle_rectangles,
+            _ => &counters.pseudo_rectangles
+        }, 1);
+
+        let dst = Rect {
+            left:   rectangle.x_position,
+            top:    rectangle.y_position,
+            width:  rectangle.width,
+            height: rectangle.height
+        };
+        let custom = decoder.custom.iter_mut()
+            .find(|&&mut (encoding, _)| encoding == rectangle.encoding);
+        if let Some((_, custom)) = custom {
+            if !custom.decode(dst, stream, format, callback)? { return Ok(false) }
+            continue
+        }
+        match rectangle.encoding {
+            protocol::Encoding::Raw => {
+                let length = protocol::pixel_data_length(
+                    rectangle.width, rectangle.height,
+                    format.bits_per_pixel as usize / 8, max_length)?;
+                // Events are only sent once all of the data has been read, so a connection
+                // dropped in the middle of a rectangle never produces garbage pixels.
+                let mut pixels = vec![0; length];
+                stream.read_exact(&mut pixels)?;
+                debug!("<- ...pixels");
+                convert_pixels(native_endian, format, &mut pixels);
+                send!(Event::PutPixels(dst, pixels))
+            },
+            protocol::Encoding::CopyRect => {
+                let copy_rect = protocol::CopyRect::read_from(stream)?;
+                let src = Rect {
+                    left:   copy_rect.src_x_position,
+                    top:    copy_rect.src_y_position,
+                    width:  rectangle.width,
+                    height: rectangle.height
+                };
+                send!(Event::CopyPixels { src, dst })
+            },
+            protocol::Encoding::Zrle => {
+                let data = protocol::read_bytes(stream, max_length)?;
+                debug!("<- ...compressed pixels");
+                let started_at = Instant::now();
+                let result = decoder.zrle.decode(format, dst, &data,
+                    |tile, mut pixels| {
+                        convert_pixels(native_endian, format, &mut pixels);
+                        Ok(callback(Event::PutPixels(tile, pixels)))
+                    })?;
+                Counters::add(&counters.decode_nanos, started_at.elapsed().as_nanos() as u64);
+                if !result { return Ok(false) }
+            }
+            protocol::Encoding::Hextile if !strict_encodings => {
+                let mut pixels = hextile::decode(stream, format.bits_per_pixel as usize / 8,
+                                                 rectangle.width, rectangle.height, max_length)?;
+                debug!("<- ...hextile pixels");
+                convert_pixels(native_endian, format, &mut pixels);
+                send!(Event::PutPixels(dst, pixels))
+            },
+            protocol::Encoding::Cursor => {
+                let mut pixels    = vec![0; protocol::pixel_data_length(
+                                            rectangle.width, rectangle.height,
+                                            format.bits_per_pixel as usize / 8,
+                                            max_length)?];
+                stream.read_exact(&mut pixels)?;
+                convert_pixels(native_endian, format, &mut pixels);
+                let mut mask_bits = vec![0; protocol::check_length(
+                                            (rectangle.width as usize).div_ceil(8) *
+                                            (rectangle.height as usize),
+                                            max_length)?];
+                stream.read_exact(&mut mask_bits)?;
+                send!(Event::SetCursor {
+                    size:      (rectangle.width, rectangle.height),
+                    hotspot:   (rectangle.x_position, rectangle.y_position),
+                    pixels,
+                    mask_bits,
+                })
+            },
+            protocol::Encoding::XCursor => {
+                let bpp = format.bits_per_pixel as usize / 8;
+                let length = protocol::pixel_data_length(
+                    rectangle.width, rectangle.height, bpp, max_length)?;
+                let mask_length = (rectangle.width as usize).div_ceil(8) * rectangle.height as usize;
+                let (mut pixels, mut mask_bits) = (Vec::with_capacity(length), Vec::new());
+                if length > 0 {
+                    let mut colours = [0; 6];
+                    stream.read_exact(&mut colours)?;
+                    let mut bitmap = vec![0; protocol::check_length(mask_length, max_length)?];
+                    stream.read_exact(&mut bitmap)?;
+                    mask_bits = vec![0; mask_length];
+                    stream.read_exact(&mut mask_bits)?;
+
+                    // Bits set in the bitmap are the primary colour, the rest secondary.
+                    let pixel = |rgb: &[u8]| {
+                        let scale = |value: u8, max: u16| (value as u32 * max as u32 / 255) as u16;
+                        let value = format.pack_rgb(scale(rgb[0], format.red_max),
+                                                    scale(rgb[1], format.green_max),
+                                                    scale(rgb[2], format.blue_max));
+                        let bytes = if format.big_endian { value.to_be_bytes() }
+                                    else { value.to_le_bytes() };
+                        if format.big_endian { bytes[4 - bpp..].to_vec() }
+                        else { bytes[..bpp].to_vec() }
+                    };
+                    let (primary, secondary) = (pixel(&colours[0..3]), pixel(&colours[3..6]));
+                    let stride = (rectangle.width as usize).div_ceil(8);
+                    for y in 0..rectangle.height as usize {
+                        for x in 0..rectangle.width as usize {
+                            let bit = bitmap[y * stride + x / 8] & (0x80 >> (x % 8));
+                            pixels.extend_from_slice(if bit != 0 { &primary } else { &secondary })
+                        }
+                    }
+                    convert_pixels(native_endian, format, &mut pixels);
                 }
+                send!(Event::SetCursor {
+                    size:      (rectangle.width, rectangle.height),
+                    hotspot:   (rectangle.x_position, rectangle.y_position),
+                    pixels,
+                    mask_bits,
+                })
             },
-            _ => {
-                let security_types = protocol::SecurityTypes::read_from(&mut stream)?;
-                debug!("<- {:?}", security_types);
-                security_types.0
+            protocol::Encoding::DesktopSize => {
+                if !screen_layout_sent {
+                    pending_resize = Some(Event::Resize(rectangle.width, rectangle.height))
+                }
+            },
+            protocol::Encoding::CursorPosition => {
+                send!(Event::CursorPosition { x: rectangle.x_position, y: rectangle.y_position })
+            },
+            protocol::Encoding::DesktopName => {
+                let name = protocol::read_bytes(stream, max_length)?;
+                send!(Event::DesktopName(String::from_utf8_lossy(&name).into_owned()))
+            },
+            protocol::Encoding::QemuLedState => {
+                send!(Event::LedState(stream.read_u8()?))
+            },
+            protocol::Encoding::ExtendedKeyEvent => {
+                send!(Event::ExtendedKeyEventSupported)
+            },
+            protocol::Encoding::QemuPointerMotionChange => {
+                send!(Event::RelativePointer(rectangle.x_position == 0))
+            },
+            protocol::Encoding::ExtendedDesktopSize => {
+                let count = stream.read_u8()?;
+                stream.read_exact(&mut [0u8; 3])?;
+                let mut screens = Vec::new();
+                for _ in 0..count {
+                    screens.push(Screen::read_from(stream)?);
+                }
+                send!(Event::ScreenLayout {
+                    width:   rectangle.width,
+                    height:  rectangle.height,
+                    reason:  rectangle.x_position,
+                    status:  rectangle.y_position,
+                    screens,
+                });
+                screen_layout_sent = true
             }
+            _ => return Err(Error::Unexpected("encoding"))
         };
+    }
+    if let Some(resize) = pending_resize {
+        send!(resize)
+    }
+
+    Counters::add(&counters.frames, 1);
+    Ok(true)
+}
+
+// Writes one complete message and pushes it out of the buffer right away.
+fn send_message<W: Write>(writer: &mut W, message: &protocol::C2S) -> Result<()> {
+    message.write_to(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Leaves the message in the buffer until the next flush.
+fn queue_message<W: Write>(writer: &mut W, message: &protocol::C2S) -> Result<()> {
+    message.write_to(writer)?;
+    Ok(())
+}
+
+// Drops duplicates (keeping the first, i.e. most preferred, occurrence) and moves
+// pseudo-encodings after the real ones, which some servers insist on.
+fn normalize_encodings(encodings: &[protocol::Encoding], pseudo_encodings: &[protocol::Encoding])
+                      -> Result<Vec<protocol::Encoding>> {
+    use crate::protocol::Encoding;
+
+    if encodings.is_empty() {
+        return Err(Error::Unexpected("empty encoding list"))
+    }
+
+    let mut result: Vec<Encoding> = Vec::new();
+    for &encoding in encodings.iter().chain(pseudo_encodings) {
+        let conflicting = result.iter().find(|&&other| match (other, encoding) {
+            (Encoding::CompressionLevel(_), Encoding::CompressionLevel(_)) |
+            (Encoding::JpegQuality(_), Encoding::JpegQuality(_)) => true,
+            _ => other == encoding
+        }).cloned();
+        match conflicting {
+            Some(other) if other != encoding =>
+                warn!("ignoring {:?}, already requested {:?}", encoding, other),
+            Some(_) => (),
+            None => {
+                if let Encoding::Unknown(n) = encoding {
+                    warn!("requesting unknown encoding {}", n)
+                }
+                result.push(encoding)
+            }
+        }
+    }
+    let (mut real, pseudo): (Vec<_>, Vec<_>) =
+        result.into_iter().partition(|encoding| !encoding.is_pseudo());
+    real.extend(pseudo);
+    Ok(real)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClientConfig {
+    // Upper bound on frames requested per second; 0 requests as fast as the server replies.
+    pub max_fps: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig { ClientConfig { max_fps: 60 } }
+}
+
+// The socket, if there is one, is kept around for set_nodelay and disconnect.
+enum Writer {
+    Tcp(TcpStream),
+    Other(Box<dyn Write + Send>),
+    #[cfg(feature = "rsa-aes")]
+    Encrypted(Box<rsa_aes::Writer<Writer>>),
+}
+
+impl Writer {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        match self {
+            Writer::Tcp(stream) => Some(stream),
+            Writer::Other(_) => None,
+            #[cfg(feature = "rsa-aes")]
+            Writer::Encrypted(writer) => writer.get_ref().tcp_stream(),
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Tcp(stream) => stream.write(buf),
+            Writer::Other(writer) => writer.write(buf),
+            #[cfg(feature = "rsa-aes")]
+            Writer::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Tcp(stream) => stream.flush(),
+            Writer::Other(writer) => writer.flush(),
+            #[cfg(feature = "rsa-aes")]
+            Writer::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+// Lets the handshake, which both reads and writes, run on separate halves.
+struct Split<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W> Read for Split<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.reader.read(buf) }
+}
+
+impl<R, W: Write> Write for Split<R, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.writer.write(buf) }
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+#[derive(Debug, Clone)]
+pub struct Builder {
+    shared:      bool,
+    min_version: protocol::Version,
+    max_version: protocol::Version,
+    max_length:  usize,
+    compression_level: Option<u8>,
+    jpeg_quality:      Option<u8>,
+    auth_retry:        bool,
+    handshake_timeout: Option<Duration>,
+    desktop_name:      bool,
+    led_state:         bool,
+    coalesce_frames:   bool,
+    strict_encodings:  bool,
+    event_queue_bound: Option<usize>,
+    custom_decoders:   Vec<(protocol::Encoding, DecoderFactory)>,
+    emit_stats:        bool,
+    initial_format:    Option<protocol::PixelFormat>,
+    rgba_output:       bool,
+}
+
+impl Default for Builder {
+    fn default() -> Builder { Builder::new() }
+}
 
-        if security_types.is_empty() {
-            let reason = String::read_from(&mut stream)?;
-            debug!("<- {:?}", reason);
-            return Err(Error::Server(reason))
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            shared:      true,
+            min_version: protocol::Version::Rfb33,
+            max_version: protocol::Version::Rfb38,
+            max_length:  protocol::DEFAULT_MAX_LENGTH,
+            compression_level: None,
+            jpeg_quality:      None,
+            auth_retry:        false,
+            handshake_timeout: None,
+            desktop_name:      false,
+            led_state:         false,
+            coalesce_frames:   false,
+            strict_encodings:  true,
+            event_queue_bound: None,
+            custom_decoders:   Vec::new(),
+            emit_stats:        false,
+            initial_format:    None,
+            rgba_output:       false,
         }
+    }
+
+    pub fn shared(mut self, shared: bool) -> Builder {
+        self.shared = shared;
+        self
+    }
+
+    // The client replies with the lower of the server's version and this one,
+    // e.g. capping it at RFB 3.3 avoids the security type list entirely.
+    pub fn max_version(mut self, version: protocol::Version) -> Builder {
+        self.max_version = version;
+        self
+    }
+
+    // The handshake fails if the server cannot speak at least this version.
+    pub fn min_version(mut self, version: protocol::Version) -> Builder {
+        self.min_version = version;
+        self
+    }
+
+    // Caps the size of any single variable-length item the server sends (a string,
+    // a rectangle's pixel data, a compressed stream); larger ones are an error.
+    pub fn max_message_length(mut self, max_length: usize) -> Builder {
+        self.max_length = max_length;
+        self
+    }
+
+    // Tight servers pick zlib level and JPEG quality (both 0..=9) from pseudo-encodings,
+    // which are appended to every Client::set_encodings call. Larger values are clamped to 9.
+    pub fn compression_level(mut self, level: u8) -> Builder {
+        self.compression_level = Some(cmp::min(level, 9));
+        self
+    }
+
+    pub fn jpeg_quality(mut self, quality: u8) -> Builder {
+        self.jpeg_quality = Some(cmp::min(quality, 9));
+        self
+    }
+
+    // Servers only send Event::DesktopName and Event::LedState to clients that advertise
+    // the corresponding pseudo-encoding, which these append to every Client::set_encodings call.
+    pub fn desktop_name(mut self, desktop_name: bool) -> Builder {
+        self.desktop_name = desktop_name;
+        self
+    }
+
+    pub fn led_state(mut self, led_state: bool) -> Builder {
+        self.led_state = led_state;
+        self
+    }
+
+    // If authentication fails and the server offers its security types again instead of
+    // closing the connection (RFB 3.7 and later only), `auth` is called again with the
+    // methods that have not failed yet.
+    pub fn auth_retry(mut self, auth_retry: bool) -> Builder {
+        self.auth_retry = auth_retry;
+        self
+    }
+
+    // Delivers the pixels of each update as a single Event::Frame rather than as separate
+    // Event::PutPixels, to avoid e.g. uploading the same area to a GPU several times.
+    pub fn coalesce_frames(mut self, coalesce_frames: bool) -> Builder {
+        self.coalesce_frames = coalesce_frames;
+        self
+    }
 
+    // Some servers ignore SetEncodings and send encodings that were never requested.
+    // When disabled, such rectangles are decoded as long as the crate supports them,
+    // which includes Hextile; only Raw, CopyRect and ZRLE are decoded otherwise.
+    // Unknown encodings still end the connection, since there is no way to tell
+    // how long their data is.
+    pub fn strict_encodings(mut self, strict_encodings: bool) -> Builder {
+        self.strict_encodings = strict_encodings;
+        self
+    }
+
+    // Makes the event thread stop reading from the server once this many events are
+    // waiting to be received, instead of queueing everything in memory; TCP flow control
+    // then slows the server down. An application that stops polling for events (e.g.
+    // because it waits for set_format to return, which needs events) will deadlock.
+    pub fn event_queue_bound(mut self, bound: usize) -> Builder {
+        self.event_queue_bound = Some(bound);
+        self
+    }
+
+    // See Decoder::register; `factory` is called once per connection. The encoding still
+    // has to be passed to Client::set_encodings for the server to use it.
+    pub fn register_decoder<F>(mut self, encoding: protocol::Encoding, factory: F) -> Builder
+            where F: Fn() -> Box<dyn RectangleDecoder> + Send + Sync + 'static {
+        self.custom_decoders.push((encoding, DecoderFactory(Arc::new(factory))));
+        self
+    }
+
+    // Sends an Event::FrameStats with the size and timing of every framebuffer update,
+    // e.g. for a bandwidth display.
+    pub fn emit_stats(mut self, emit_stats: bool) -> Builder {
+        self.emit_stats = emit_stats;
+        self
+    }
+
+    // Sends SetPixelFormat right after the handshake, before anything else; no updates
+    // can be in flight yet, so unlike Client::set_format this has nothing to wait for.
+    pub fn initial_format(mut self, format: protocol::PixelFormat) -> Builder {
+        self.initial_format = Some(format);
+        self
+    }
+
+    // See Client::set_rgba_output; this enables it before the first update can arrive.
+    pub fn rgba_output(mut self, rgba_output: bool) -> Builder {
+        self.rgba_output = rgba_output;
+        self
+    }
+
+    // Fails the handshake with Error::Timeout if the server stops responding
+    // for this long at any point before ServerInit has been received.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Builder {
+        self.handshake_timeout = Some(timeout);
+        self
+    }
+
+    // See Client::accept_reverse.
+    pub fn accept_reverse<Auth>(self, listener: &TcpListener, auth: Auth) -> Result<Client>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        let (stream, address) = listener.accept()?;
+        info!("accepted reverse connection from {}", address);
+        self.connect(stream, auth)
+    }
+
+    fn authenticate<S, Auth>(&self, stream: &mut S, version: protocol::Version,
+                             security_types: &[protocol::SecurityType],
+                             auth: &mut Auth)
+                            -> Result<(protocol::SecurityType, Option<Session>)>
+            where S: Read + Write, Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
         let mut auth_methods = Vec::new();
-        for security_type in security_types {
+        for &security_type in security_types {
             match security_type {
                 protocol::SecurityType::None =>
                     auth_methods.push(AuthMethod::None),
@@ -202,56 +1015,91 @@ impl Client {
                     auth_methods.push(AuthMethod::Password),
                 protocol::SecurityType::AppleRemoteDesktop =>
                     auth_methods.push(AuthMethod::AppleRemoteDesktop),
+                protocol::SecurityType::Tight =>
+                    auth_methods.push(AuthMethod::Tight),
+                #[cfg(feature = "rsa-aes")]
+                protocol::SecurityType::RsaAes |
+                protocol::SecurityType::RsaAesUnencrypted
+                        if !auth_methods.iter().any(|m| matches!(m, AuthMethod::RsaAes)) =>
+                    auth_methods.push(AuthMethod::RsaAes),
                 _ => ()
             }
         }
 
         let auth_choice = auth(&auth_methods).ok_or(Error::AuthenticationUnavailable)?;
 
+        let used_security_type = match auth_choice {
+            AuthChoice::None => protocol::SecurityType::None,
+            AuthChoice::Password(_) => protocol::SecurityType::VncAuthentication,
+            AuthChoice::AppleRemoteDesktop(_, _) => protocol::SecurityType::AppleRemoteDesktop,
+            AuthChoice::Tight(_) => protocol::SecurityType::Tight,
+            AuthChoice::RsaAes(_, _)
+                    if security_types.contains(&protocol::SecurityType::RsaAes) =>
+                protocol::SecurityType::RsaAes,
+            AuthChoice::RsaAes(_, _) => protocol::SecurityType::RsaAesUnencrypted,
+        };
         match version {
             protocol::Version::Rfb33 => (),
             _ => {
-                let used_security_type = match auth_choice {
-                    AuthChoice::None => protocol::SecurityType::None,
-                    AuthChoice::Password(_) => protocol::SecurityType::VncAuthentication,
-                    AuthChoice::AppleRemoteDesktop(_, _) => protocol::SecurityType::AppleRemoteDesktop,
-                };
                 debug!("-> SecurityType::{:?}", used_security_type);
-                protocol::SecurityType::write_to(&used_security_type, &mut stream)?;
-            }
-        }
-
-        match auth_choice {
-            AuthChoice::Password(mut password) => {
-                // Reverse the bits in every byte of password.
-                // DES is 56-bit and as commonly implemented, it takes a 8-octet key
-                // and ignores LSB of every octet; this of course would be bad for
-                // ASCII passwords.
-                //
-                // I've spent *hours* figuring this out.
-                // I hate every single fucker involved in the chain of decisions that
-                // led to this authentication scheme, and doubly so because it is completely
-                // undocumented in what passes for the specification of the RFB protocol.
-                for i in 0..8 {
-                    let c = password[i];
-                    let mut cs = 0u8;
-                    for j in 0..8 { cs |= ((c >> j) & 1) << (7 - j) }
-                    password[i] = cs;
+                protocol::SecurityType::write_to(&used_security_type, stream)?;
+            }
+        }
+
+        let auth_choice = match auth_choice {
+            AuthChoice::Tight(inner) => {
+                let tunnels = protocol::TightCapabilities::read_from(stream)?;
+                debug!("<- tunnels {:?}", tunnels);
+                if !tunnels.0.is_empty() {
+                    // NOTUNNEL is always allowed, even if not listed.
+                    debug!("-> {:?}", protocol::TightCapability::NO_TUNNEL);
+                    stream.write_i32::<BigEndian>(protocol::TightCapability::NO_TUNNEL.code)?;
+                }
+
+                let auth_types = protocol::TightCapabilities::read_from(stream)?;
+                debug!("<- authentication {:?}", auth_types);
+                if auth_types.0.is_empty() {
+                    AuthChoice::None
+                } else {
+                    let capability = match *inner {
+                        AuthChoice::None => protocol::TightCapability::AUTH_NONE,
+                        AuthChoice::Password(_) => protocol::TightCapability::AUTH_VNC,
+                        _ => return Err(Error::AuthenticationUnavailable)
+                    };
+                    if !auth_types.0.iter().any(|auth_type| auth_type.code == capability.code) {
+                        return Err(Error::AuthenticationUnavailable)
+                    }
+                    debug!("-> {:?}", capability);
+                    stream.write_i32::<BigEndian>(capability.code)?;
+                    *inner
                 }
+            },
+            auth_choice => auth_choice
+        };
 
+        let session: Option<Session> = match auth_choice {
+            AuthChoice::Password(password) => {
                 let mut challenge = [0; 16];
                 stream.read_exact(&mut challenge)?;
-                let response = des(&challenge, &password);
+                let response = vnc_auth_response(&challenge, &password);
                 stream.write_all(&response)?;
+                None
             },
             #[cfg(feature = "apple-auth")]
             AuthChoice::AppleRemoteDesktop(ref username, ref password) => {
-                let handshake = protocol::AppleAuthHandshake::read_from(&mut stream)?;
+                let handshake = protocol::AppleAuthHandshake::read_from(stream)?;
                 let response = apple_auth(username, password, &handshake);
-                response.write_to(&mut stream)?;
+                response.write_to(stream)?;
+                None
             },
-            _ => (),
-        }
+            #[cfg(feature = "rsa-aes")]
+            AuthChoice::RsaAes(ref username, ref password) =>
+                Some(rsa_aes::authenticate(stream, username, password)?),
+            #[cfg(not(feature = "rsa-aes"))]
+            AuthChoice::RsaAes(_, _) =>
+                return Err(Error::AuthenticationUnavailable),
+            _ => None,
+        };
 
         let mut skip_security_result = false;
         match &(auth_choice, version) {
@@ -260,102 +1108,602 @@ impl Client {
             _ => ()
         }
 
-        if !skip_security_result {
-            match protocol::SecurityResult::read_from(&mut stream)? {
-                protocol::SecurityResult::Succeeded => (),
-                protocol::SecurityResult::Failed => {
-                    match version {
-                        protocol::Version::Rfb33 |
-                        protocol::Version::Rfb37 =>
-                            return Err(Error::AuthenticationFailure(String::from(""))),
-                        protocol::Version::Rfb38 => {
-                            let reason = String::read_from(&mut stream)?;
-                            debug!("<- {:?}", reason);
-                            return Err(Error::AuthenticationFailure(reason))
-                        }
+        let session = match session {
+            // With RsaAes, everything from here on is encrypted; RsaAesUnencrypted only
+            // protects the credentials.
+            #[cfg(feature = "rsa-aes")]
+            Some(session) if used_security_type == protocol::SecurityType::RsaAes => {
+                let mut stream = session.wrap(&mut *stream);
+                self.read_security_result(&mut stream, version, used_security_type)?;
+                Some(stream.into_session())
+            },
+            _ => {
+                if !skip_security_result {
+                    self.read_security_result(stream, version, used_security_type)?;
+                }
+                None
+            }
+        };
+
+        Ok((used_security_type, session))
+    }
+
+    fn read_security_result<S: Read>(&self, stream: &mut S, version: protocol::Version,
+                                     used_security_type: protocol::SecurityType)
+                                    -> Result<()> {
+        match protocol::SecurityResult::read_from(stream)? {
+            protocol::SecurityResult::Succeeded => Ok(()),
+            protocol::SecurityResult::Failed => {
+                match version {
+                    protocol::Version::Rfb33 |
+                    protocol::Version::Rfb37 =>
+                        Err(Error::AuthenticationFailure {
+                            reason:        String::new(),
+                            security_type: Some(used_security_type)
+                        }),
+                    protocol::Version::Rfb38 => {
+                        let reason = protocol::read_string(stream, self.max_length)?;
+                        debug!("<- {:?}", reason);
+                        Err(Error::AuthenticationFailure {
+                            reason,
+                            security_type: Some(used_security_type)
+                        })
                     }
                 }
             }
         }
+    }
 
-        let client_init = protocol::ClientInit { shared };
+    fn initialize<S: Read + Write>(&self, stream: &mut S,
+                                   used_security_type: protocol::SecurityType)
+                                  -> Result<protocol::ServerInit> {
+        let client_init = protocol::ClientInit { shared: self.shared };
         debug!("-> {:?}", client_init);
-        protocol::ClientInit::write_to(&client_init, &mut stream)?;
+        protocol::ClientInit::write_to(&client_init, stream)?;
 
-        let server_init = protocol::ServerInit::read_from(&mut stream)?;
+        let server_init = protocol::ServerInit::read_from(stream)?;
         debug!("<- {:?}", server_init);
 
-        let format = Arc::new(Mutex::new(server_init.pixel_format));
-
-        let (tx_events, rx_events) = channel();
-        {
-            let stream = stream.try_clone().unwrap();
-            let format = format.clone();
-            thread::spawn(move || {
-                let mut tx_events = tx_events;
-                let error = Event::pump(stream, format, &mut tx_events).err();
-                let _ = tx_events.send(Event::Disconnected(error));
-            });
+        if used_security_type == protocol::SecurityType::Tight {
+            let capabilities = protocol::TightInteractionCapabilities::read_from(stream)?;
+            debug!("<- {:?}", capabilities);
         }
-
-        Ok(Client {
-            stream,
-            events:  rx_events,
-            name:    server_init.name,
-            size:    (server_init.framebuffer_width, server_init.framebuffer_height),
-            format,
-        })
+        Ok(server_init)
     }
 
-    pub fn name(&self) -> &str { &self.name }
-    pub fn size(&self) -> (u16, u16) { self.size }
-    pub fn format(&self) -> protocol::PixelFormat { *self.format.lock().unwrap() }
-
-    pub fn set_encodings(&mut self, encodings: &[protocol::Encoding]) -> Result<()> {
-        let set_encodings = protocol::C2S::SetEncodings(Vec::from(encodings));
-        debug!("-> {:?}", set_encodings);
-        protocol::C2S::write_to(&set_encodings, &mut self.stream)?;
-        Ok(())
+    pub(crate) fn handshake<S, Auth>(&self, stream: &mut S, auth: Auth) ->
+            Result<protocol::ServerInit>
+            where S: Read + Write, Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        match self.handshake_session(stream, auth)? {
+            (server_init, None) => Ok(server_init),
+            // The proxy would have to decrypt and re-encrypt everything.
+            (_, Some(_)) => Err(Error::Unexpected("encrypted session"))
+        }
     }
 
-    pub fn request_update(&mut self, rect: Rect, incremental: bool) -> Result<()> {
-        let update_req = protocol::C2S::FramebufferUpdateRequest {
-            incremental,
-            x_position:  rect.left,
-            y_position:  rect.top,
+    fn handshake_session<S, Auth>(&self, stream: &mut S, mut auth: Auth) ->
+            Result<(protocol::ServerInit, Option<Session>)>
+            where S: Read + Write, Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        let server_version = protocol::Version::read_from(stream)?;
+        debug!("<- Version::{:?}", server_version);
+        let version = cmp::min(server_version, self.max_version);
+        if version < self.min_version {
+            return Err(Error::Unexpected("protocol version"))
+        }
+        debug!("-> Version::{:?}", version);
+        protocol::Version::write_to(&version, stream)?;
+
+        let mut security_types = match version {
+            protocol::Version::Rfb33 => {
+                let protocol::SecurityTypeRfb33(security_type) =
+                    protocol::SecurityTypeRfb33::read_from(stream)?;
+                debug!("<- SecurityType::{:?}", security_type);
+                if security_type == protocol::SecurityType::Invalid {
+                    vec![]
+                } else {
+                    vec![security_type]
+                }
+            },
+            _ => {
+                let security_types = protocol::SecurityTypes::read_from(stream)?;
+                debug!("<- {:?}", security_types);
+                security_types.0
+            }
+        };
+
+        let mut failed_security_types = Vec::new();
+        let (used_security_type, session) = loop {
+            if security_types.is_empty() {
+                let reason = protocol::read_string(stream, self.max_length)?;
+                debug!("<- {:?}", reason);
+                return Err(Error::Server(reason))
+            }
+
+            match self.authenticate(stream, version, &security_types, &mut auth) {
+                // Most servers close the connection after a failure, in which case the failure
+                // is reported as is; some send a fresh list of security types instead.
+                Err(Error::AuthenticationFailure { reason, security_type: Some(security_type) })
+                        if self.auth_retry && version != protocol::Version::Rfb33 => {
+                    failed_security_types.push(security_type);
+                    let failure = Error::AuthenticationFailure {
+                        reason, security_type: Some(security_type)
+                    };
+                    security_types = match protocol::SecurityTypes::read_from(stream) {
+                        Ok(security_types) => security_types.0,
+                        Err(_) => return Err(failure)
+                    };
+                    debug!("<- {:?}", security_types);
+                    if security_types.is_empty() { continue }
+                    security_types.retain(|security_type|
+                        !failed_security_types.contains(security_type));
+                    if security_types.is_empty() {
+                        return Err(failure)
+                    }
+                },
+                result => break result?
+            }
+        };
+
+        match session {
+            None => Ok((self.initialize(stream, used_security_type)?, None)),
+            #[cfg(feature = "rsa-aes")]
+            Some(session) => {
+                let mut stream = session.wrap(&mut *stream);
+                let server_init = self.initialize(&mut stream, used_security_type)?;
+                Ok((server_init, Some(stream.into_session())))
+            },
+            #[cfg(not(feature = "rsa-aes"))]
+            Some(session) => match session {}
+        }
+    }
+
+    pub fn connect<Auth>(self, mut stream: TcpStream, auth: Auth) -> Result<Client>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        // Input events are tiny messages that must not wait for Nagle's algorithm
+        // to coalesce them; see Client::set_nodelay to turn this back off.
+        stream.set_nodelay(true)?;
+
+        stream.set_read_timeout(self.handshake_timeout)?;
+        let (server_init, session) = match self.handshake_session(&mut stream, auth) {
+            Err(Error::Io(ref error))
+                    if error.kind() == io::ErrorKind::WouldBlock ||
+                       error.kind() == io::ErrorKind::TimedOut =>
+                return Err(Error::Timeout),
+            result => result?
+        };
+        stream.set_read_timeout(None)?;
+
+        let reader = stream.try_clone()?;
+        self.start(server_init, session, reader, Writer::Tcp(stream))
+    }
+
+    // Runs the client over any pair of byte streams, e.g. in-memory pipes for testing;
+    // `reader` is moved to the event thread. Builder::handshake_timeout has no effect.
+    pub fn connect_split<R, W, Auth>(self, mut reader: R, mut writer: W, auth: Auth)
+                                    -> Result<Client>
+            where R: Read + Send + 'static, W: Write + Send + 'static,
+                  Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        let (server_init, session) =
+            self.handshake_session(&mut Split { reader: &mut reader, writer: &mut writer },
+                                   auth)?;
+        self.start(server_init, session, reader, Writer::Other(Box::new(writer)))
+    }
+
+    fn start<R>(self, server_init: protocol::ServerInit, session: Option<Session>,
+                reader: R, writer: Writer) -> Result<Client>
+            where R: Read + Send + 'static {
+        let (reader, writer): (Box<dyn Read + Send>, Writer) = match session {
+            None => (Box::new(reader), writer),
+            #[cfg(feature = "rsa-aes")]
+            Some(session) => {
+                let (reader, writer) = session.split(reader, writer);
+                (Box::new(reader), Writer::Encrypted(Box::new(writer)))
+            },
+            #[cfg(not(feature = "rsa-aes"))]
+            Some(session) => match session {}
+        };
+        let mut writer = BufWriter::new(writer);
+        if let Some(format) = self.initial_format {
+            let set_pixel_format = protocol::C2S::SetPixelFormat(format);
+            log_message!("->", set_pixel_format);
+            send_message(&mut writer, &set_pixel_format)?;
+        }
+
+        let format = Arc::new(Mutex::new(self.initial_format
+                                             .unwrap_or(server_init.pixel_format)));
+        let native_endian = Arc::new(AtomicBool::new(false));
+        let rgba_output = Arc::new(AtomicBool::new(self.rgba_output));
+        let paused = Arc::new((Mutex::new(false), Condvar::new()));
+        let counters = Arc::new(Counters::default());
+
+        let (tx_events, rx_events) = match self.event_queue_bound {
+            None => {
+                let (tx_events, rx_events) = channel();
+                (EventChannel::Unbounded(tx_events), rx_events)
+            }
+            Some(bound) => {
+                let (tx_events, rx_events) = sync_channel(bound);
+                (EventChannel::Bounded(tx_events), rx_events)
+            }
+        };
+        let pending = Arc::new(AtomicUsize::new(0));
+        #[cfg(unix)]
+        let (wakeup, tx_wakeup) = UnixStream::pair()?;
+        #[cfg(unix)]
+        {
+            wakeup.set_nonblocking(true)?;
+            tx_wakeup.set_nonblocking(true)?;
+        }
+        {
+            // Every message is read field by field; a rectangle header alone takes five
+            // reads. Buffering turns a FramebufferUpdate of many small rectangles from
+            // several syscalls per rectangle into roughly one per 8 KiB of data.
+            // Bytes are counted as they leave the buffer, so that they can be attributed
+            // to individual messages.
+            let stream = CountingReader {
+                inner:    BufReader::new(reader),
+                counters: counters.clone(),
+                eof:      false,
+            };
+            let pending = pending.clone();
+            let format = format.clone();
+            let native_endian = native_endian.clone();
+            let rgba_output = rgba_output.clone();
+            let paused = paused.clone();
+            let mut decoder = Decoder { counters: counters.clone(),
+                                        coalesce_frames: self.coalesce_frames,
+                                        strict_encodings: self.strict_encodings,
+                                        encodings: if self.emit_stats { Some(Vec::new()) }
+                                                   else { None },
+                                        ..Decoder::with_max_length(self.max_length) };
+            for &(encoding, DecoderFactory(ref factory)) in &self.custom_decoders {
+                decoder.register(encoding, factory())
+            }
+            thread::spawn(move || {
+                let mut tx_events = EventSender {
+                    sender: tx_events,
+                    pending,
+                    #[cfg(unix)]
+                    wakeup: tx_wakeup,
+                };
+                let error = Event::pump(stream, format, native_endian, rgba_output, paused,
+                                        decoder, &mut tx_events).err();
+                let _ = tx_events.send(Event::Disconnected(error));
+            });
+        }
+
+        Ok(Client {
+            stream:  writer,
+            events:  rx_events,
+            name:    server_init.name,
+            size:    (server_init.framebuffer_width, server_init.framebuffer_height),
+            shared:  self.shared,
+            extended_key_event: false,
+            relative_pointer: false,
+            format,
+            native_endian,
+            rgba_output,
+            paused,
+            counters,
+            clipboard_utf8: false,
+            pending,
+            deferred: VecDeque::new(),
+            outstanding_updates: 0,
+            #[cfg(unix)]
+            wakeup,
+            palette: Vec::new(),
+            buttons: 0,
+            pseudo_encodings: self.compression_level.map(protocol::Encoding::CompressionLevel)
+                .into_iter()
+                .chain(self.jpeg_quality.map(protocol::Encoding::JpegQuality))
+                .chain(Some(protocol::Encoding::DesktopName).filter(|_| self.desktop_name))
+                .chain(Some(protocol::Encoding::QemuLedState).filter(|_| self.led_state))
+                .collect(),
+        })
+    }
+}
+
+pub struct Client {
+    stream:  BufWriter<Writer>,
+    events:  Receiver<Event>,
+    name:    String,
+    size:    (u16, u16),
+    shared:  bool,
+    extended_key_event: bool,
+    relative_pointer: bool,
+    format:  Arc<Mutex<protocol::PixelFormat>>,
+    native_endian: Arc<AtomicBool>,
+    rgba_output: Arc<AtomicBool>,
+    paused:  Arc<(Mutex<bool>, Condvar)>,
+    counters: Arc<Counters>,
+    clipboard_utf8: bool,
+    pending: Arc<AtomicUsize>,
+    // Events that set_format had to take out of the channel while waiting for the server;
+    // poll_event hands them out before anything else.
+    deferred: VecDeque<Event>,
+    // FramebufferUpdateRequests sent whose EndOfFrame has not been taken out of the channel.
+    // Servers may answer several requests with one update, so this is only an upper bound.
+    outstanding_updates: usize,
+    #[cfg(unix)]
+    wakeup:  UnixStream,
+    palette: Vec<Colour>,
+    pseudo_encodings: Vec<protocol::Encoding>,
+    buttons: u8,
+}
+
+impl Client {
+    pub fn from_tcp_stream<Auth>(stream: TcpStream, shared: bool,
+                                 auth: Auth) -> Result<Client>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        Builder::new().shared(shared).connect(stream, auth)
+    }
+
+    // See Builder::connect_split.
+    pub fn from_split<R, W, Auth>(reader: R, writer: W, shared: bool,
+                                  auth: Auth) -> Result<Client>
+            where R: Read + Send + 'static, W: Write + Send + 'static,
+                  Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        Builder::new().shared(shared).connect_split(reader, writer, auth)
+    }
+
+    // Waits for a server to make a reverse connection, as in `vncviewer -listen`.
+    // The handshake is the same as usual; only the TCP connection goes the other way.
+    pub fn accept_reverse<Auth>(listener: &TcpListener, auth: Auth) -> Result<Client>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        Builder::new().accept_reverse(listener, auth)
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn size(&self) -> (u16, u16) { self.size }
+    // What was requested in ClientInit; RFB has no way to change it afterwards.
+    pub fn is_shared(&self) -> bool { self.shared }
+    pub fn format(&self) -> protocol::PixelFormat { *self.format.lock().unwrap() }
+
+    // With an indexed (not true colour) format, Raw pixels are indices into this,
+    // accumulated from every Event::SetColourMap received so far.
+    pub fn palette(&self) -> Option<&[Colour]> {
+        if self.palette.is_empty() { None } else { Some(&self.palette) }
+    }
+
+    // When enabled, the pixels in PutPixels and SetCursor events are byte-swapped as needed
+    // to arrive in the host's byte order, whatever format().big_endian says.
+    // Takes effect starting with the next framebuffer update.
+    pub fn set_native_endian(&mut self, native_endian: bool) {
+        self.native_endian.store(native_endian, Ordering::Relaxed)
+    }
+
+    // When enabled, the pixels in PutPixels and Frame events are converted to RGBA8888,
+    // four bytes per pixel in that order with alpha always 255, whatever format() is.
+    // Indexed colours are looked up in palette(). Overrides set_native_endian for those
+    // events, and takes effect starting with the next framebuffer update.
+    pub fn set_rgba_output(&mut self, rgba_output: bool) {
+        self.rgba_output.store(rgba_output, Ordering::Relaxed)
+    }
+
+    // Counts everything received since the handshake completed.
+    pub fn stats(&self) -> ClientStats { self.counters.snapshot() }
+
+    // The RFB specification says clipboard text is Latin-1, but some servers (QEMU among
+    // them) send and expect UTF-8 instead. This non-standard mode treats the text of
+    // CutText messages in both directions as UTF-8; invalid sequences become U+FFFD.
+    pub fn set_clipboard_utf8(&mut self, clipboard_utf8: bool) {
+        self.clipboard_utf8 = clipboard_utf8
+    }
+
+    // Does nothing unless connected over TCP.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        if let Some(stream) = self.stream.get_ref().tcp_stream() {
+            stream.set_nodelay(nodelay)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_encodings(&mut self, encodings: &[protocol::Encoding]) -> Result<()> {
+        let encodings = normalize_encodings(encodings, &self.pseudo_encodings)?;
+        let set_encodings = protocol::C2S::SetEncodings(encodings);
+        log_message!("->", set_encodings);
+        send_message(&mut self.stream, &set_encodings)?;
+        Ok(())
+    }
+
+    pub fn request_update(&mut self, rect: Rect, incremental: bool) -> Result<()> {
+        self.queue_update_request(rect, incremental)?;
+        self.flush()
+    }
+
+    fn queue_update_request(&mut self, mut rect: Rect, incremental: bool) -> Result<()> {
+        if self.is_paused() {
+            trace!("not requesting an update while paused");
+            return Ok(())
+        }
+        let (width, height) = self.size;
+        let left   = cmp::min(rect.left, width);
+        let top    = cmp::min(rect.top, height);
+        let clamped = Rect {
+            left, top,
+            width:  cmp::min(rect.width, width - left),
+            height: cmp::min(rect.height, height - top)
+        };
+        if clamped != rect {
+            warn!("clamping update request {:?} to {:?} framebuffer", rect, self.size);
+            rect = clamped
+        }
+
+        let update_req = protocol::C2S::FramebufferUpdateRequest {
+            incremental,
+            x_position:  rect.left,
+            y_position:  rect.top,
             width:       rect.width,
             height:      rect.height
         };
         trace!("-> {:?}", update_req);
-        protocol::C2S::write_to(&update_req, &mut self.stream)?;
+        queue_message(&mut self.stream, &update_req)?;
+        self.outstanding_updates += 1;
+        Ok(())
+    }
+
+    pub fn request_full_update(&mut self, incremental: bool) -> Result<()> {
+        let (width, height) = self.size;
+        self.request_update(Rect { left: 0, top: 0, width, height }, incremental)
+    }
+
+    // Requests the framebuffer as `tile`x`tile` squares, row by row, so that on a slow
+    // link the top of the screen can be drawn before the rest has arrived.
+    pub fn request_tiled(&mut self, tile: u16, incremental: bool) -> Result<()> {
+        if tile == 0 {
+            return Err(Error::Unexpected("zero tile size"))
+        }
+        let (width, height) = self.size;
+        for top in (0..height).step_by(tile as usize) {
+            for left in (0..width).step_by(tile as usize) {
+                let rect = Rect { left, top,
+                                  width:  cmp::min(tile, width - left),
+                                  height: cmp::min(tile, height - top) };
+                self.queue_update_request(rect, incremental)?;
+            }
+        }
+        self.flush()
+    }
+
+    pub fn set_desktop_size(&mut self, width: u16, height: u16,
+                            screens: &[Screen]) -> Result<()> {
+        let set_desktop_size = protocol::C2S::SetDesktopSize {
+            width,
+            height,
+            screens: Vec::from(screens)
+        };
+        log_message!("->", set_desktop_size);
+        send_message(&mut self.stream, &set_desktop_size)?;
+        Ok(())
+    }
+
+    // Every message is sent right away, except for those queued by the methods sending
+    // many messages at once (type_text, send_scroll and request_tiled), which flush
+    // once at the end.
+    pub fn flush(&mut self) -> Result<()> {
+        self.stream.flush()?;
         Ok(())
     }
 
     pub fn send_key_event(&mut self, down: bool, key: u32) -> Result<()> {
+        self.queue_key_event(down, key)?;
+        self.flush()
+    }
+
+    fn queue_key_event(&mut self, down: bool, key: u32) -> Result<()> {
         let key_event = protocol::C2S::KeyEvent {
             down,
             key,
         };
-        debug!("-> {:?}", key_event);
-        protocol::C2S::write_to(&key_event, &mut self.stream)?;
+        log_message!("->", key_event);
+        queue_message(&mut self.stream, &key_event)
+    }
+
+    // Types `text` by pressing and releasing the keysym of every character in turn,
+    // holding Shift around the characters that need it on a US keyboard. Characters
+    // outside Latin-1 are sent as Unicode keysyms, which servers map onto whatever
+    // keyboard layout they have, if at all; there is no support for input methods,
+    // dead keys or combining characters.
+    pub fn type_text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            let key = keysym::char_to_keysym(c);
+            let shift = keysym::needs_shift(c);
+            if shift { self.queue_key_event(true, keysym::XK_SHIFT_L)? }
+            self.queue_key_event(true, key)?;
+            self.queue_key_event(false, key)?;
+            if shift { self.queue_key_event(false, keysym::XK_SHIFT_L)? }
+        }
+        self.flush()
+    }
+
+    // Only true once Event::ExtendedKeyEventSupported has been received, which requires
+    // Encoding::ExtendedKeyEvent to be passed to set_encodings first.
+    pub fn supports_extended_key_event(&self) -> bool { self.extended_key_event }
+
+    // Sends a key event with the scancode of the physical key as well as the keysym,
+    // which lets the server pick the right key regardless of keyboard layout.
+    // Servers that don't support this disconnect, so it is refused until they confirm.
+    pub fn send_extended_key_event(&mut self, down: bool, key: u32, keycode: u32) -> Result<()> {
+        if !self.extended_key_event {
+            return Err(Error::Unexpected("extended key event without server support"))
+        }
+        let key_event = protocol::C2S::ExtendedKeyEvent { down, key, keycode };
+        log_message!("->", key_event);
+        send_message(&mut self.stream, &key_event)?;
         Ok(())
     }
 
-    pub fn send_pointer_event(&mut self, buttons: u8, x: u16, y: u16) -> Result<()> {
+    pub fn send_pointer_event(&mut self, buttons: PointerButtons, x: u16, y: u16) -> Result<()> {
+        self.send_pointer_event_raw(buttons.bits(), x, y)
+    }
+
+    pub fn send_pointer_event_raw(&mut self, buttons: u8, x: u16, y: u16) -> Result<()> {
+        self.queue_pointer_event(buttons, x, y)?;
+        self.flush()
+    }
+
+    fn queue_pointer_event(&mut self, buttons: u8, x: u16, y: u16) -> Result<()> {
+        self.buttons = buttons;
         let pointer_event = protocol::C2S::PointerEvent {
             button_mask: buttons,
             x_position:  x,
             y_position:  y
         };
-        debug!("-> {:?}", pointer_event);
-        protocol::C2S::write_to(&pointer_event, &mut self.stream)?;
-        Ok(())
+        log_message!("->", pointer_event);
+        queue_message(&mut self.stream, &pointer_event)
+    }
+
+    // Asks QEMU for relative pointer motion, e.g. for games that grab the pointer, by
+    // advertising the QemuPointerMotionChange pseudo-encoding with the next set_encodings.
+    // The server announces which mode it uses with Event::RelativePointer, and may switch
+    // back and forth at any time; in relative mode, send_relative_pointer_event must be
+    // used instead of send_pointer_event.
+    pub fn set_relative_pointer(&mut self, enable: bool) {
+        let encoding = protocol::Encoding::QemuPointerMotionChange;
+        self.pseudo_encodings.retain(|&pseudo| pseudo != encoding);
+        if enable { self.pseudo_encodings.push(encoding) }
+    }
+
+    pub fn is_relative_pointer(&self) -> bool { self.relative_pointer }
+
+    // Sends pointer motion as a delta, which QEMU expects offset by 0x7fff.
+    pub fn send_relative_pointer_event(&mut self, buttons: PointerButtons,
+                                       dx: i16, dy: i16) -> Result<()> {
+        if !self.relative_pointer {
+            return Err(Error::Unexpected("relative pointer event in absolute mode"))
+        }
+        self.send_pointer_event(buttons, (dx as u16).wrapping_add(0x7fff),
+                                (dy as u16).wrapping_add(0x7fff))
+    }
+
+    // Scrolls by clicking the wheel buttons (4/5 up/down, 6/7 left/right) once per step,
+    // with whatever buttons were last sent with send_pointer_event still held.
+    pub fn send_scroll(&mut self, dx: i8, dy: i8, x: u16, y: u16) -> Result<()> {
+        let buttons = self.buttons;
+        let steps = [(dy > 0, PointerButtons::WHEEL_UP, dy),
+                     (dy < 0, PointerButtons::WHEEL_DOWN, dy),
+                     (dx < 0, PointerButtons::WHEEL_LEFT, dx),
+                     (dx > 0, PointerButtons::WHEEL_RIGHT, dx)];
+        for &(active, button, delta) in steps.iter() {
+            if !active { continue }
+            for _ in 0..(delta as i16).abs() {
+                self.queue_pointer_event(buttons | button.bits(), x, y)?;
+                self.queue_pointer_event(buttons, x, y)?;
+            }
+        }
+        self.flush()
     }
 
     pub fn update_clipboard(&mut self, text: &str) -> Result<()> {
-        let cut_text = protocol::C2S::CutText(String::from(text));
-        debug!("-> {:?}", cut_text);
-        protocol::C2S::write_to(&cut_text, &mut self.stream)?;
+        let text = if self.clipboard_utf8 {
+            // Smuggle the UTF-8 bytes through the Latin-1 encoder unchanged.
+            text.bytes().map(|c| c as char).collect()
+        } else {
+            String::from(text)
+        };
+        let cut_text = protocol::C2S::CutText(text);
+        log_message!("->", cut_text);
+        send_message(&mut self.stream, &cut_text)?;
         Ok(())
     }
 
@@ -364,27 +1712,54 @@ impl Client {
     // The ZRLE encoding is self-delimiting and if both the client and server
     // support and use it, there can be no race condition, but we currently don't.
     pub fn set_format(&mut self, format: protocol::PixelFormat) -> Result<()> {
-        // Request (and discard) one full update to try and ensure that there
-        // are no FramebufferUpdate's in the buffers somewhere.
+        if self.is_paused() {
+            return Err(Error::Unexpected("pixel format change while paused"))
+        }
+        // Request one full update to try and ensure that there are no
+        // FramebufferUpdate's in the buffers somewhere.
         // This is not fully robust though (and cannot possibly be).
-        let _ = self.poll_iter().count(); // drain it
-        let framebuffer_rect = Rect { left: 0, top: 0, width: self.size.0, height: self.size.1 };
+        // Everything received meanwhile, including the update itself, is kept for
+        // poll_event, in the old pixel format. The event descriptor is not signalled
+        // for these, so check has_pending afterwards.
+        let mut events = self.drain_events();
+        let disconnected = events.iter().any(|event| matches!(event, Event::Disconnected(_)));
+        self.deferred.extend(events.drain(..));
+        if disconnected {
+            return Err(Error::Disconnected)
+        }
+        let mut framebuffer_rect =
+            Rect { left: 0, top: 0, width: self.size.0, height: self.size.1 };
         self.request_update(framebuffer_rect, false)?;
-        'outer: loop {
-            for event in self.poll_iter() {
-                match event {
-                    Event::PutPixels(rect, _) if rect == framebuffer_rect => break 'outer,
-                    _ => ()
-                }
-            }
+        loop {
+            let event = match self.events.recv() {
+                Ok(event) => self.track_event(event),
+                Err(_) => return Err(Error::Disconnected)
+            };
+            let done = match event {
+                Event::PutPixels(rect, _) => rect == framebuffer_rect,
+                Event::Frame(ref rects) => rects.iter().any(|&(rect, _)| rect == framebuffer_rect),
+                // The server may resize the framebuffer before sending the full update.
+                Event::Resize(..) | Event::ScreenLayout { .. } => {
+                    framebuffer_rect =
+                        Rect { left: 0, top: 0, width: self.size.0, height: self.size.1 };
+                    false
+                },
+                Event::Disconnected(_) => {
+                    self.deferred.push_back(event);
+                    return Err(Error::Disconnected)
+                },
+                _ => false
+            };
+            self.deferred.push_back(event);
+            if done { break }
         }
 
         // Since VNC is fully client-driven, by this point the event thread is stuck
         // waiting for the next message and the server is not sending us anything,
         // so it's safe to switch to the new pixel format.
         let set_pixel_format = protocol::C2S::SetPixelFormat(format);
-        debug!("-> {:?}", set_pixel_format);
-        protocol::C2S::write_to(&set_pixel_format, &mut self.stream)?;
+        log_message!("->", set_pixel_format);
+        send_message(&mut self.stream, &set_pixel_format)?;
         *self.format.lock().unwrap() = format;
 
         Ok(())
@@ -393,20 +1768,291 @@ impl Client {
     #[doc(hidden)]
     pub fn poke_qemu(&mut self) -> Result<()> {
         let set_pixel_format = protocol::C2S::SetPixelFormat(*self.format.lock().unwrap());
-        debug!("-> {:?}", set_pixel_format);
-        protocol::C2S::write_to(&set_pixel_format, &mut self.stream)?;
+        log_message!("->", set_pixel_format);
+        send_message(&mut self.stream, &set_pixel_format)?;
         Ok(())
     }
 
+    // Takes every event that has already arrived out of the queue without blocking.
+    pub fn drain_events(&mut self) -> Vec<Event> {
+        self.poll_iter().collect()
+    }
+
+    // The number of events poll_event would return right now without waiting.
+    pub fn pending_events(&self) -> usize {
+        self.pending.load(Ordering::SeqCst) + self.deferred.len()
+    }
+    pub fn has_pending(&self) -> bool { self.pending_events() > 0 }
+
+    // A descriptor that becomes readable whenever events are pending, to be used with
+    // select(), poll(), epoll and the like; call poll_event until it returns None after
+    // a wakeup, or the descriptor may stay readable. Never read from it directly.
+    #[cfg(unix)]
+    pub fn event_fd(&self) -> RawFd { self.wakeup.as_raw_fd() }
+
+    #[cfg(unix)]
+    fn clear_wakeup(&self) {
+        let mut buffer = [0; 64];
+        while let Ok(count) = (&self.wakeup).read(&mut buffer) {
+            if count == 0 { break }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn clear_wakeup(&self) {}
+
     pub fn poll_event(&mut self) -> Option<Event> {
+        if let Some(event) = self.deferred.pop_front() {
+            return Some(event)
+        }
         match self.events.try_recv() {
-            Err(TryRecvError::Empty) |
+            Err(TryRecvError::Empty) => {
+                // The wakeup for an event can arrive after the event itself was received.
+                self.clear_wakeup();
+                None
+            },
             Err(TryRecvError::Disconnected) => None,
-            Ok(Event::Resize(width, height)) => {
+            Ok(event) => Some(self.track_event(event))
+        }
+    }
+
+    // Drives the whole update cycle: requests a frame, collects events up to and including
+    // EndOfFrame, hands them to `handler`, then requests the next frame no sooner than
+    // config.max_fps allows. A new frame is only requested after the previous one has
+    // arrived, so a slow link or a slow handler never has updates piling up.
+    // Returns when `handler` returns false or the server disconnects, and fails if the
+    // client is paused, including by `handler`, as no frame would ever be requested.
+    pub fn run<F>(&mut self, config: &ClientConfig, mut handler: F) -> Result<()>
+            where F: FnMut(&mut Client, Vec<Event>) -> bool {
+        let frame_time = match config.max_fps {
+            0 => Duration::from_secs(0),
+            max_fps => Duration::from_secs(1) / max_fps
+        };
+
+        let mut incremental = false;
+        loop {
+            if self.is_paused() {
+                return Err(Error::Unexpected("frame loop while paused"))
+            }
+            let requested_at = Instant::now();
+            self.request_full_update(incremental)?;
+            incremental = true;
+
+            let mut events = Vec::new();
+            loop {
+                let event = self.recv_event().unwrap_or(Event::Disconnected(None));
+                match event {
+                    Event::Disconnected(error) => {
+                        if !events.is_empty() {
+                            handler(self, events);
+                        }
+                        return error.map_or(Ok(()), Err)
+                    },
+                    Event::EndOfFrame => {
+                        events.push(Event::EndOfFrame);
+                        break
+                    },
+                    // The new area has to be requested in full.
+                    Event::Resize(..) | Event::ScreenLayout { .. } => {
+                        incremental = false;
+                        events.push(event)
+                    },
+                    event => events.push(event)
+                }
+            }
+
+            if !handler(self, events) {
+                return Ok(())
+            }
+
+            let elapsed = requested_at.elapsed();
+            if elapsed < frame_time {
+                thread::sleep(frame_time - elapsed)
+            }
+        }
+    }
+
+    // Requests the whole framebuffer and assembles the resulting update into a single buffer
+    // in the current pixel format, or RGBA8888 with set_rgba_output. Events other than
+    // pixel data that arrive meanwhile are discarded. Updates answering requests made
+    // earlier are drawn too, but only end the capture if they cover the whole framebuffer.
+    pub fn capture_frame(&mut self) -> Result<(Rect, Vec<u8>, protocol::PixelFormat)> {
+        if self.is_paused() {
+            return Err(Error::Unexpected("frame capture while paused"))
+        }
+        fn copy_rows(dst: &mut [u8], dst_rect: Rect, src: &[u8], src_rect: Rect,
+                     stride: usize, bpp: usize) {
+            let row = dst_rect.width as usize * bpp;
+            for y in 0..dst_rect.height as usize {
+                let dst_start = (dst_rect.top as usize + y) * stride + dst_rect.left as usize * bpp;
+                let src_start = (src_rect.top as usize + y) * src_rect.width as usize * bpp +
+                                src_rect.left as usize * bpp;
+                dst[dst_start..dst_start + row].copy_from_slice(&src[src_start..src_start + row])
+            }
+        }
+
+        fn put_pixels(dst: &mut [u8], covered: &mut [bool], frame: Rect, rect: Rect,
+                      src: &[u8], stride: usize, bpp: usize) {
+            if frame.intersect(&rect) == Some(rect) {
+                let src_rect = Rect::new(0, 0, rect.width, rect.height);
+                copy_rows(dst, rect, src, src_rect, stride, bpp);
+                cover(covered, frame, rect)
+            } else {
+                warn!("rectangle {:?} is outside of the framebuffer", rect)
+            }
+        }
+
+        fn cover(covered: &mut [bool], frame: Rect, rect: Rect) {
+            for y in rect.top as usize..rect.top as usize + rect.height as usize {
+                let start = y * frame.width as usize + rect.left as usize;
+                covered[start..start + rect.width as usize].fill(true)
+            }
+        }
+
+        let format =
+            if self.rgba_output.load(Ordering::Relaxed) {
+                // The closest PixelFormat to RGBA8888; the padding byte is the alpha.
+                protocol::PixelFormat {
+                    bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+                    red_max: 255, green_max: 255, blue_max: 255,
+                    red_shift: 0, green_shift: 8, blue_shift: 16
+                }
+            } else {
+                self.format()
+            };
+        let bpp = format.bits_per_pixel as usize / 8;
+        let mut frame = Rect::new(0, 0, self.size.0, self.size.1);
+        let mut pixels = vec![0; protocol::pixel_data_length(
+                                    frame.width, frame.height, bpp, usize::MAX)?];
+        let mut covered = vec![false; frame.area()];
+
+        self.request_full_update(false)?;
+        // EndOfFrames still to come for requests made before this one.
+        let mut earlier = self.unanswered_updates().saturating_sub(1);
+        let mut complete = true;
+        loop {
+            let event = match self.recv_event() {
+                Some(event) => event,
+                None => return Err(Error::Disconnected)
+            };
+            let stride = frame.width as usize * bpp;
+            match event {
+                Event::Disconnected(error) =>
+                    return Err(error.unwrap_or(Error::Disconnected)),
+                Event::PutPixels(rect, data) =>
+                    put_pixels(&mut pixels, &mut covered, frame, rect, &data, stride, bpp),
+                Event::Frame(rects) =>
+                    for (rect, data) in rects {
+                        put_pixels(&mut pixels, &mut covered, frame, rect, &data, stride, bpp)
+                    },
+                Event::CopyPixels { src, dst } => {
+                    if frame.intersect(&src) == Some(src) && frame.intersect(&dst) == Some(dst) {
+                        let previous = pixels.clone();
+                        copy_rows(&mut pixels, dst, &previous, src, stride, bpp);
+                        cover(&mut covered, frame, dst)
+                    } else {
+                        warn!("rectangle {:?} is outside of the framebuffer", dst)
+                    }
+                },
+                // A server may fold this request into the answer to an earlier one, so
+                // an earlier update that redraws everything is as good as our own.
+                Event::EndOfFrame if complete &&
+                        (earlier == 0 || covered.iter().all(|&covered| covered)) =>
+                    return Ok((frame, pixels, format)),
+                Event::EndOfFrame if complete => {
+                    earlier -= 1;
+                    covered.fill(false)
+                },
+                Event::EndOfFrame => {
+                    // The framebuffer was resized halfway through; ask for all of it again.
+                    self.request_full_update(false)?;
+                    earlier = self.unanswered_updates().saturating_sub(1);
+                    covered.fill(false);
+                    complete = true
+                },
+                _ => ()
+            }
+            if (frame.width, frame.height) != self.size {
+                frame = Rect::new(0, 0, self.size.0, self.size.1);
+                pixels = vec![0; protocol::pixel_data_length(
+                                    frame.width, frame.height, bpp, usize::MAX)?];
+                covered = vec![false; frame.area()];
+                complete = false
+            }
+        }
+    }
+
+    // Like capture_frame, but converted to RGBA whatever the pixel format is, e.g. for
+    // saving a screenshot with the `image` crate.
+    #[cfg(feature = "image")]
+    pub fn capture_image(&mut self) -> Result<image::RgbaImage> {
+        let (rect, pixels, format) = self.capture_frame()?;
+        let rgba = rgba_pixels(format, &self.palette, &pixels);
+        Ok(image::RgbaImage::from_raw(rect.width as u32, rect.height as u32, rgba)
+            .expect("capture_frame returned a buffer of the wrong size"))
+    }
+
+    // Requests whose EndOfFrame has not been handed out yet, counting those set_format
+    // took out of the channel early.
+    fn unanswered_updates(&self) -> usize {
+        self.outstanding_updates +
+            self.deferred.iter().filter(|event| matches!(event, Event::EndOfFrame)).count()
+    }
+
+    // Blocks until the next event, or returns None once the event thread is gone.
+    fn recv_event(&mut self) -> Option<Event> {
+        if let Some(event) = self.deferred.pop_front() {
+            return Some(event)
+        }
+        self.events.recv().ok().map(|event| self.track_event(event))
+    }
+
+    // Every event taken out of the channel passes through here.
+    fn track_event(&mut self, event: Event) -> Event {
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.clear_wakeup()
+        }
+        match event {
+            Event::Resize(width, height) => {
                 self.size = (width, height);
-                Some(Event::Resize(width, height))
+                Event::Resize(width, height)
+            }
+            Event::ScreenLayout { width, height, reason, status, screens } => {
+                if status == 0 {
+                    self.size = (width, height);
+                }
+                Event::ScreenLayout { width, height, reason, status, screens }
+            }
+            Event::SetColourMap { first_colour, colours } => {
+                let end = first_colour as usize + colours.len();
+                if self.palette.len() < end {
+                    self.palette.resize(end, Colour { red: 0, green: 0, blue: 0 })
+                }
+                self.palette[first_colour as usize..end].copy_from_slice(&colours);
+                Event::SetColourMap { first_colour, colours }
+            }
+            Event::ExtendedKeyEventSupported => {
+                self.extended_key_event = true;
+                Event::ExtendedKeyEventSupported
+            }
+            Event::RelativePointer(relative) => {
+                self.relative_pointer = relative;
+                Event::RelativePointer(relative)
+            }
+            Event::EndOfFrame => {
+                self.outstanding_updates = self.outstanding_updates.saturating_sub(1);
+                Event::EndOfFrame
+            }
+            Event::DesktopName(name) => {
+                self.name = name.clone();
+                Event::DesktopName(name)
+            }
+            Event::Clipboard(text) if self.clipboard_utf8 => {
+                // Latin-1 decoding maps every byte to a char, so this recovers the bytes.
+                let bytes = text.chars().map(|c| c as u8).collect::<Vec<u8>>();
+                Event::Clipboard(String::from_utf8_lossy(&bytes).into_owned())
             }
-            Ok(event) => Some(event)
+            event => event
         }
     }
 
@@ -414,12 +2060,45 @@ impl Client {
         EventPollIterator { client: self }
     }
 
+    // Stops the event thread before it handles the next message, and stops sending
+    // update requests, e.g. while the window is minimized; the server then has nothing
+    // to send once any update in flight is done. Input events are still sent. Whatever
+    // the event thread was waiting for when paused is delivered after resuming.
+    pub fn pause(&mut self) {
+        self.set_paused(true)
+    }
+
+    // Lets the event thread continue and requests the whole framebuffer again, since
+    // everything that changed in the meantime was missed.
+    pub fn resume(&mut self) -> Result<()> {
+        self.set_paused(false);
+        self.request_full_update(false)
+    }
+
+    pub fn is_paused(&self) -> bool { *self.paused.0.lock().unwrap() }
+
+    fn set_paused(&mut self, paused: bool) {
+        let (ref lock, ref resumed) = *self.paused;
+        *lock.lock().unwrap() = paused;
+        resumed.notify_all()
+    }
+
     pub fn disconnect(self) -> Result<()> {
-        self.stream.shutdown(Shutdown::Both)?;
+        // Otherwise, the server sees the end of the stream once the writer is dropped.
+        if let Some(stream) = self.stream.get_ref().tcp_stream() {
+            stream.shutdown(Shutdown::Both)?;
+        }
         Ok(())
     }
 }
 
+impl Drop for Client {
+    // Otherwise, a paused event thread would never see the connection closing.
+    fn drop(&mut self) {
+        self.set_paused(false)
+    }
+}
+
 pub struct EventPollIterator<'a> {
     client: &'a mut Client
 }
@@ -429,3 +2108,1885 @@ impl<'a> Iterator for EventPollIterator<'a> {
 
     fn next(&mut self) -> Option<Self::Item> { self.client.poll_event() }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Arc};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use crate::protocol::{self, Message};
+    use crate::{Rect, Colour, PointerButtons, Error};
+    use super::{Client, ClientConfig, Builder, AuthMethod, AuthChoice, Event, Decoder,
+                decode_update};
+
+    fn mock_server<F, T>(server: F) -> (TcpStream, thread::JoinHandle<T>)
+            where F: FnOnce(TcpStream) -> T + Send + 'static, T: Send + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || server(listener.accept().unwrap().0));
+        (TcpStream::connect(address).unwrap(), handle)
+    }
+
+    fn test_format() -> protocol::PixelFormat {
+        protocol::PixelFormat {
+            bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+            red_max: 255, green_max: 255, blue_max: 255,
+            red_shift: 16, green_shift: 8, blue_shift: 0
+        }
+    }
+
+    // Completes an RFB 3.8 handshake with no authentication on the server side.
+    fn handshake<S: Read + Write>(stream: &mut S, width: u16, height: u16,
+                                  format: protocol::PixelFormat) {
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        stream.read_exact(&mut [0; 12]).unwrap();
+        protocol::SecurityTypes(vec![protocol::SecurityType::None]).write_to(stream).unwrap();
+        protocol::SecurityType::read_from(stream).unwrap();
+        protocol::SecurityResult::Succeeded.write_to(stream).unwrap();
+        protocol::ClientInit::read_from(stream).unwrap();
+        protocol::ServerInit {
+            framebuffer_width:  width,
+            framebuffer_height: height,
+            pixel_format:       format,
+            name:               String::from("test")
+        }.write_to(stream).unwrap();
+    }
+
+    fn connect<F, T>(builder: Builder, format: protocol::PixelFormat,
+                     server: F) -> (Client, thread::JoinHandle<T>)
+            where F: FnOnce(TcpStream) -> T + Send + 'static, T: Send + 'static {
+        let (stream, server) = mock_server(move |mut stream| {
+            handshake(&mut stream, 100, 100, format);
+            server(stream)
+        });
+        (builder.connect(stream, |_| Some(AuthChoice::None)).unwrap(), server)
+    }
+
+    fn wait_event(client: &mut Client) -> Event {
+        for _ in 0..5000 {
+            match client.poll_event() {
+                Some(event) => return event,
+                None => thread::sleep(Duration::from_millis(1))
+            }
+        }
+        panic!("no event received")
+    }
+
+    fn write_update(stream: &mut TcpStream, rectangles: &[(protocol::Rectangle, &[u8])]) {
+        protocol::S2C::FramebufferUpdate { count: rectangles.len() as u16 }
+            .write_to(stream).unwrap();
+        for (rectangle, data) in rectangles {
+            rectangle.write_to(stream).unwrap();
+            stream.write_all(data).unwrap();
+        }
+    }
+
+    // One direction of an in-memory byte stream; dropping the writer ends the stream.
+    struct PipeWriter(mpsc::Sender<Vec<u8>>);
+    struct PipeReader(mpsc::Receiver<Vec<u8>>, io::Cursor<Vec<u8>>);
+
+    fn pipe() -> (PipeReader, PipeWriter) {
+        let (tx, rx) = mpsc::channel();
+        (PipeReader(rx, io::Cursor::new(Vec::new())), PipeWriter(tx))
+    }
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf.to_vec()).map_err(|_| io::ErrorKind::BrokenPipe)?;
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            while self.1.position() as usize == self.1.get_ref().len() {
+                match self.0.recv() {
+                    Ok(data) => self.1 = io::Cursor::new(data),
+                    Err(_) => return Ok(0)
+                }
+            }
+            self.1.read(buf)
+        }
+    }
+
+    struct PipeStream(PipeReader, PipeWriter);
+
+    impl Read for PipeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    }
+
+    impl Write for PipeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.1.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { self.1.flush() }
+    }
+
+    #[test]
+    fn from_split() {
+        let (client_reader, server_writer) = pipe();
+        let (server_reader, client_writer) = pipe();
+        let server = thread::spawn(move || {
+            let mut stream = PipeStream(server_reader, server_writer);
+            handshake(&mut stream, 100, 100, test_format());
+            match protocol::C2S::read_from(&mut stream).unwrap() {
+                protocol::C2S::KeyEvent { down: true, key: 0x61 } => (),
+                message => panic!("unexpected {:?}", message)
+            }
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+        });
+
+        let mut client = Client::from_split(client_reader, client_writer, true,
+                                            |_| Some(AuthChoice::None)).unwrap();
+        assert_eq!(client.name(), "test");
+        assert_eq!(client.size(), (100, 100));
+        client.send_key_event(true, 0x61).unwrap();
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+        match wait_event(&mut client) {
+            Event::Disconnected(None) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        client.disconnect().unwrap();
+    }
+
+    // Counts the calls to read or write on the underlying stream.
+    struct CountingStream<S> {
+        inner: S,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl<S: Read> Read for CountingStream<S> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.inner.read(buf)
+        }
+    }
+
+    impl<S: Write> Write for CountingStream<S> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.inner.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+    }
+
+    #[test]
+    fn flush() {
+        let (client_reader, server_writer) = pipe();
+        let (server_reader, client_writer) = pipe();
+        let server = thread::spawn(move || {
+            let mut stream = PipeStream(server_reader, server_writer);
+            handshake(&mut stream, 100, 100, test_format());
+            (0..1 + 22 + 4).map(|_| protocol::C2S::read_from(&mut stream).unwrap()).count()
+        });
+
+        let writes = Arc::new(AtomicUsize::new(0));
+        let writer = CountingStream { inner: client_writer, count: writes.clone() };
+        let mut client = Client::from_split(client_reader, writer, true,
+                                            |_| Some(AuthChoice::None)).unwrap();
+        let handshake_writes = writes.load(Ordering::SeqCst);
+        // Interactive input goes out right away...
+        client.send_pointer_event(PointerButtons::LEFT, 10, 10).unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), handshake_writes + 1);
+        // ...while the 22 key events and 4 pointer events below take one write per call.
+        client.type_text("hello world").unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), handshake_writes + 2);
+        client.send_scroll(0, 2, 10, 10).unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), handshake_writes + 3);
+        assert_eq!(server.join().unwrap(), 27);
+    }
+
+    #[test]
+    fn request_tiled() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            (0..4).map(|_| match protocol::C2S::read_from(&mut stream).unwrap() {
+                protocol::C2S::FramebufferUpdateRequest {
+                    incremental: true, x_position, y_position, width, height
+                } => Rect::new(x_position, y_position, width, height),
+                message => panic!("unexpected {:?}", message)
+            }).collect::<Vec<_>>()
+        });
+        client.request_tiled(64, true).unwrap();
+        let tiles = server.join().unwrap();
+        assert_eq!(tiles, [Rect::new(0, 0, 64, 64), Rect::new(64, 0, 36, 64),
+                           Rect::new(0, 64, 64, 36), Rect::new(64, 64, 36, 36)]);
+        assert_eq!(tiles.iter().map(Rect::area).sum::<usize>(), 100 * 100);
+        assert!(client.request_tiled(0, true).is_err());
+    }
+
+    #[test]
+    fn type_text() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            (0..10).map(|_| match protocol::C2S::read_from(&mut stream).unwrap() {
+                protocol::C2S::KeyEvent { down, key } => (down, key),
+                message => panic!("unexpected {:?}", message)
+            }).collect::<Vec<_>>()
+        });
+        client.type_text("Ab!").unwrap();
+        client.stream.flush().unwrap();
+        assert_eq!(server.join().unwrap(),
+                   [(true, 0xffe1), (true, 0x41), (false, 0x41), (false, 0xffe1),
+                    (true, 0x62), (false, 0x62),
+                    (true, 0xffe1), (true, 0x21), (false, 0x21), (false, 0xffe1)]);
+    }
+
+    fn negotiate(server_version: &'static [u8], builder: Builder) -> Option<protocol::Version> {
+        let (stream, server) = mock_server(move |mut stream| {
+            stream.write_all(server_version).unwrap();
+            let mut client_version = [0; 12];
+            match stream.read_exact(&mut client_version) {
+                Ok(()) => Some(protocol::Version::read_from(&mut &client_version[..]).unwrap()),
+                Err(_) => None
+            }
+        });
+        assert!(builder.connect(stream, |_| Some(AuthChoice::None)).is_err());
+        server.join().unwrap()
+    }
+
+    #[test]
+    fn version_negotiation() {
+        use crate::protocol::Version::*;
+
+        assert_eq!(negotiate(b"RFB 003.008\n", Builder::new()), Some(Rfb38));
+        assert_eq!(negotiate(b"RFB 003.007\n", Builder::new()), Some(Rfb37));
+        assert_eq!(negotiate(b"RFB 003.003\n", Builder::new()), Some(Rfb33));
+        assert_eq!(negotiate(b"RFB 003.008\n", Builder::new().max_version(Rfb33)), Some(Rfb33));
+        assert_eq!(negotiate(b"RFB 003.008\n", Builder::new().max_version(Rfb37)), Some(Rfb37));
+        assert_eq!(negotiate(b"RFB 003.007\n", Builder::new().min_version(Rfb37)), Some(Rfb37));
+        assert_eq!(negotiate(b"RFB 003.003\n", Builder::new().min_version(Rfb37)), None);
+    }
+
+    fn refuse(server_version: &'static [u8]) -> Error {
+        let (stream, server) = mock_server(move |mut stream| {
+            stream.write_all(server_version).unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            match server_version {
+                b"RFB 003.003\n" => stream.write_all(&[0, 0, 0, 0]).unwrap(),
+                _ => stream.write_all(&[0]).unwrap()
+            }
+            String::from("go away").write_to(&mut stream).unwrap();
+        });
+        let error = Builder::new().connect(stream, |_| Some(AuthChoice::None)).err().unwrap();
+        server.join().unwrap();
+        error
+    }
+
+    #[test]
+    fn connection_refused() {
+        for version in &[&b"RFB 003.003\n"[..], b"RFB 003.007\n", b"RFB 003.008\n"] {
+            match refuse(version) {
+                Error::Server(ref reason) if reason == "go away" => (),
+                error => panic!("unexpected {:?}", error)
+            }
+        }
+    }
+
+    #[test]
+    fn buffered_streams() {
+        let (client_reader, server_writer) = pipe();
+        let (server_reader, client_writer) = pipe();
+        let (tx_burst, rx_burst) = mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut stream = PipeStream(server_reader, server_writer);
+            handshake(&mut stream, 100, 100, test_format());
+            let messages = (0..2).map(|_| protocol::C2S::read_from(&mut stream).unwrap())
+                                 .collect::<Vec<_>>();
+            // Twenty messages, all arriving in a single chunk.
+            rx_burst.recv().unwrap();
+            let mut burst = Vec::new();
+            for _ in 0..20 { protocol::S2C::Bell.write_to(&mut burst).unwrap() }
+            stream.write_all(&burst).unwrap();
+            messages
+        });
+
+        let (reads, writes) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let reader = CountingStream { inner: client_reader, count: reads.clone() };
+        let writer = CountingStream { inner: client_writer, count: writes.clone() };
+        let mut client = Client::from_split(reader, writer, true,
+                                            |_| Some(AuthChoice::None)).unwrap();
+
+        let handshake_writes = writes.load(Ordering::SeqCst);
+        client.queue_key_event(true, 0x61).unwrap();
+        client.queue_pointer_event(1, 10, 20).unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), handshake_writes);
+        client.flush().unwrap();
+        assert_eq!(writes.load(Ordering::SeqCst), handshake_writes + 1);
+
+        let handshake_reads = reads.load(Ordering::SeqCst);
+        tx_burst.send(()).unwrap();
+        for _ in 0..20 {
+            match wait_event(&mut client) {
+                Event::Bell => (),
+                event => panic!("unexpected {:?}", event)
+            }
+        }
+        // One read for the burst, and possibly another that is waiting for more data.
+        assert!(reads.load(Ordering::SeqCst) - handshake_reads <= 2);
+
+        match &server.join().unwrap()[..] {
+            [protocol::C2S::KeyEvent { down: true, key: 0x61 },
+             protocol::C2S::PointerEvent { button_mask: 1, x_position: 10, y_position: 20 }] => (),
+            messages => panic!("unexpected {:?}", messages)
+        }
+    }
+
+    #[test]
+    fn nodelay() {
+        fn nodelay(client: &Client) -> bool {
+            client.stream.get_ref().tcp_stream().unwrap().nodelay().unwrap()
+        }
+
+        let (mut client, server) = connect(Builder::new(), test_format(), |stream| stream);
+        assert!(nodelay(&client));
+        client.set_nodelay(false).unwrap();
+        assert!(!nodelay(&client));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn indexed_colour() {
+        let format = protocol::PixelFormat {
+            bits_per_pixel: 8, depth: 8, big_endian: false, true_colour: false,
+            red_max: 0, green_max: 0, blue_max: 0,
+            red_shift: 0, green_shift: 0, blue_shift: 0
+        };
+        let colours = vec![Colour { red: 0, green: 0, blue: 0 },
+                           Colour { red: 0xffff, green: 0x8000, blue: 0 }];
+        let server_colours = colours.clone();
+        let (mut client, server) = connect(Builder::new(), format, move |mut stream| {
+            protocol::S2C::SetColourMapEntries { first_colour: 0, colours: server_colours }
+                .write_to(&mut stream).unwrap();
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 2, height: 1,
+                encoding: protocol::Encoding::Raw
+            }, &[1, 0])]);
+            stream
+        });
+
+        assert_eq!(client.palette(), None);
+        match wait_event(&mut client) {
+            Event::SetColourMap { first_colour: 0, .. } => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(client.palette(), Some(&colours[..]));
+        match wait_event(&mut client) {
+            Event::PutPixels(rect, pixels) => {
+                assert_eq!(rect, Rect { left: 0, top: 0, width: 2, height: 1 });
+                assert_eq!(pixels, vec![1, 0]);
+            },
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    fn read_update_request(stream: &mut TcpStream) -> (bool, Rect) {
+        match protocol::C2S::read_from(stream).unwrap() {
+            protocol::C2S::FramebufferUpdateRequest {
+                incremental, x_position, y_position, width, height
+            } => (incremental, Rect { left: x_position, top: y_position, width, height }),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn request_update() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            (read_update_request(&mut stream), read_update_request(&mut stream))
+        });
+        client.request_update(Rect { left: 50, top: 90, width: 100, height: 5 }, false).unwrap();
+        client.request_full_update(true).unwrap();
+        let (clamped, full) = server.join().unwrap();
+        assert_eq!(clamped, (false, Rect { left: 50, top: 90, width: 50, height: 5 }));
+        assert_eq!(full, (true, Rect { left: 0, top: 0, width: 100, height: 100 }));
+    }
+
+    #[test]
+    fn max_message_length() {
+        let (mut client, server) =
+                connect(Builder::new().max_message_length(100), test_format(), |mut stream| {
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 5, height: 5,
+                encoding: protocol::Encoding::Raw
+            }, &[0; 100])]);
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 6, height: 5,
+                encoding: protocol::Encoding::Raw
+            }, &[0; 120])]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::PutPixels(_, ref pixels) if pixels.len() == 100 => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::EndOfFrame => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::Disconnected(Some(Error::Unexpected("message length"))) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tight_options() {
+        let builder = Builder::new().compression_level(9).jpeg_quality(0);
+        let (mut client, server) = connect(builder, test_format(), |mut stream| {
+            protocol::C2S::read_from(&mut stream).unwrap()
+        });
+        client.set_encodings(&[protocol::Encoding::Tight]).unwrap();
+        match server.join().unwrap() {
+            protocol::C2S::SetEncodings(encodings) =>
+                assert_eq!(encodings, [protocol::Encoding::Tight,
+                                       protocol::Encoding::CompressionLevel(9),
+                                       protocol::Encoding::JpegQuality(0)]),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn tight_options_clamped() {
+        let builder = Builder::new().compression_level(10).jpeg_quality(255);
+        assert_eq!(builder.compression_level, Some(9));
+        assert_eq!(builder.jpeg_quality, Some(9));
+    }
+
+    #[test]
+    fn run() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            let mut requests = Vec::new();
+            for _ in 0..3 {
+                requests.push(read_update_request(&mut stream).0);
+                write_update(&mut stream, &[]);
+            }
+            // Nothing else may be requested once the handler has stopped the loop.
+            assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+            requests
+        });
+
+        let mut frames = 0;
+        client.run(&ClientConfig { max_fps: 0 }, |_, events| {
+            match events.last() {
+                Some(Event::EndOfFrame) => (),
+                event => panic!("unexpected {:?}", event)
+            }
+            frames += 1;
+            frames < 3
+        }).unwrap();
+        client.disconnect().unwrap();
+        assert_eq!(frames, 3);
+        assert_eq!(server.join().unwrap(), [false, true, true]);
+    }
+
+    #[test]
+    fn run_screen_layout() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            assert_eq!(read_update_request(&mut stream), (false, Rect::new(0, 0, 100, 100)));
+            let mut layout = vec![1, 0, 0, 0];
+            crate::Screen { id: 0, x: 0, y: 0, width: 50, height: 40, flags: 0 }
+                .write_to(&mut layout).unwrap();
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 1, y_position: 0, width: 50, height: 40,
+                encoding: protocol::Encoding::ExtendedDesktopSize
+            }, &layout)]);
+            let request = read_update_request(&mut stream);
+            write_update(&mut stream, &[]);
+            request
+        });
+
+        let mut frames = 0;
+        client.run(&ClientConfig { max_fps: 0 }, |_, _| {
+            frames += 1;
+            frames < 2
+        }).unwrap();
+        assert_eq!(server.join().unwrap(), (false, Rect::new(0, 0, 50, 40)));
+    }
+
+    #[test]
+    fn run_paused() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            // One request from resume, and one from run.
+            read_update_request(&mut stream);
+            read_update_request(&mut stream);
+            write_update(&mut stream, &[]);
+            stream
+        });
+        client.pause();
+        match client.run(&ClientConfig { max_fps: 0 }, |_, _| true) {
+            Err(Error::Unexpected("frame loop while paused")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        client.resume().unwrap();
+        let mut frames = 0;
+        match client.run(&ClientConfig { max_fps: 0 }, |client, _| {
+            frames += 1;
+            client.pause();
+            true
+        }) {
+            Err(Error::Unexpected("frame loop while paused")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        assert_eq!(frames, 1);
+        server.join().unwrap();
+    }
+
+    fn refuse_password(server_version: &'static [u8]) -> Error {
+        let (stream, server) = mock_server(move |mut stream| {
+            stream.write_all(server_version).unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            if server_version == b"RFB 003.003\n" {
+                protocol::SecurityTypeRfb33(protocol::SecurityType::VncAuthentication)
+                    .write_to(&mut stream).unwrap();
+            } else {
+                protocol::SecurityTypes(vec![protocol::SecurityType::VncAuthentication])
+                    .write_to(&mut stream).unwrap();
+                protocol::SecurityType::read_from(&mut stream).unwrap();
+            }
+            stream.write_all(&[0; 16]).unwrap();
+            stream.read_exact(&mut [0; 16]).unwrap();
+            protocol::SecurityResult::Failed.write_to(&mut stream).unwrap();
+            if server_version == b"RFB 003.008\n" {
+                String::from("too many attempts").write_to(&mut stream).unwrap();
+            }
+        });
+        let error = Builder::new().connect(stream, |_| Some(AuthChoice::Password([0; 8])))
+            .err().unwrap();
+        server.join().unwrap();
+        error
+    }
+
+    #[test]
+    fn authentication_failure() {
+        match refuse_password(b"RFB 003.003\n") {
+            Error::AuthenticationFailure { ref reason, security_type }
+                    if reason.is_empty() &&
+                       security_type == Some(protocol::SecurityType::VncAuthentication) => (),
+            error => panic!("unexpected {:?}", error)
+        }
+        // 3.7 sends SecurityResult after VNC authentication, but no reason with it.
+        match refuse_password(b"RFB 003.007\n") {
+            Error::AuthenticationFailure { ref reason, security_type }
+                    if reason.is_empty() &&
+                       security_type == Some(protocol::SecurityType::VncAuthentication) => (),
+            error => panic!("unexpected {:?}", error)
+        }
+        match refuse_password(b"RFB 003.008\n") {
+            Error::AuthenticationFailure { ref reason, security_type }
+                    if reason == "too many attempts" &&
+                       security_type == Some(protocol::SecurityType::VncAuthentication) => (),
+            error => panic!("unexpected {:?}", error)
+        }
+    }
+
+    #[test]
+    fn rfb37_no_security_result() {
+        // Unlike 3.8, a 3.7 server goes straight to ClientInit once None is chosen.
+        let (stream, server) = mock_server(|mut stream| {
+            stream.write_all(b"RFB 003.007\n").unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            protocol::SecurityTypes(vec![protocol::SecurityType::None])
+                .write_to(&mut stream).unwrap();
+            protocol::SecurityType::read_from(&mut stream).unwrap();
+            protocol::ClientInit::read_from(&mut stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       test_format(),
+                name:               String::from("rfb37")
+            }.write_to(&mut stream).unwrap();
+        });
+        let client = Builder::new().connect(stream, |_| Some(AuthChoice::None)).unwrap();
+        assert_eq!(client.name(), "rfb37");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn auth_retry() {
+        let (stream, server) = mock_server(|mut stream| {
+            stream.write_all(b"RFB 003.008\n").unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            let security_types = protocol::SecurityTypes(vec![
+                protocol::SecurityType::None, protocol::SecurityType::VncAuthentication
+            ]);
+            security_types.write_to(&mut stream).unwrap();
+            assert_eq!(protocol::SecurityType::read_from(&mut stream).unwrap(),
+                       protocol::SecurityType::None);
+            protocol::SecurityResult::Failed.write_to(&mut stream).unwrap();
+            String::from("try again").write_to(&mut stream).unwrap();
+
+            security_types.write_to(&mut stream).unwrap();
+            assert_eq!(protocol::SecurityType::read_from(&mut stream).unwrap(),
+                       protocol::SecurityType::VncAuthentication);
+            stream.write_all(&[0; 16]).unwrap();
+            stream.read_exact(&mut [0; 16]).unwrap();
+            protocol::SecurityResult::Succeeded.write_to(&mut stream).unwrap();
+            protocol::ClientInit::read_from(&mut stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       test_format(),
+                name:               String::from("test")
+            }.write_to(&mut stream).unwrap();
+        });
+        let mut offered = Vec::new();
+        Builder::new().auth_retry(true).connect(stream, |methods| {
+            offered.push(methods.len());
+            match methods[0] {
+                AuthMethod::None => Some(AuthChoice::None),
+                _ => Some(AuthChoice::Password([0; 8]))
+            }
+        }).unwrap();
+        server.join().unwrap();
+        assert_eq!(offered, [2, 1]);
+    }
+
+    // A FramebufferUpdate body as taken by decode_frame, with a single rectangle.
+    fn frame(width: u16, height: u16, encoding: protocol::Encoding, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0, 1];
+        protocol::Rectangle { x_position: 0, y_position: 0, width, height, encoding }
+            .write_to(&mut frame).unwrap();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn zrle_frame(width: u16, height: u16, tiles: &[u8]) -> Vec<u8> {
+        let mut zrle = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+        zrle.write_all(tiles).unwrap();
+        zrle.flush().unwrap();
+        let mut data = (zrle.get_ref().len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(zrle.get_ref());
+        frame(width, height, protocol::Encoding::Zrle, &data)
+    }
+
+    #[test]
+    fn decode_frame() {
+        let format = test_format();
+        let raw = frame(1, 1, protocol::Encoding::Raw, &[1, 2, 3, 4]);
+        assert_eq!(super::decode_frame(&format, &raw).unwrap(),
+                   [(Rect::new(0, 0, 1, 1), vec![1, 2, 3, 4])]);
+
+        let mut trailing = raw.clone();
+        trailing.push(0);
+        match super::decode_frame(&format, &trailing) {
+            Err(Error::Unexpected("trailing data")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match super::decode_frame(&format, &[0]) {
+            Err(Error::Io(_)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        let format = protocol::PixelFormat { bits_per_pixel: 64, ..format };
+        match super::decode_frame(&format, &raw) {
+            Err(Error::Unexpected("bits per pixel")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn decode_arbitrary_frames() {
+        let formats = [
+            test_format(),
+            protocol::PixelFormat { bits_per_pixel: 16, depth: 16, big_endian: true,
+                                    red_max: 31, green_max: 63, blue_max: 31,
+                                    red_shift: 11, green_shift: 5, blue_shift: 0,
+                                    ..test_format() },
+            protocol::PixelFormat { bits_per_pixel: 8, depth: 8, true_colour: false,
+                                    ..test_format() },
+        ];
+        let encodings = [
+            protocol::Encoding::Raw, protocol::Encoding::CopyRect, protocol::Encoding::Zrle,
+            protocol::Encoding::Cursor, protocol::Encoding::XCursor,
+            protocol::Encoding::DesktopName, protocol::Encoding::ExtendedDesktopSize,
+        ];
+        let mut seed = 1;
+        for i in 0..20000 {
+            // Small rectangles, so that the data has a chance of being long enough.
+            let size = protocol::tests::random_bytes(&mut seed, 2);
+            let (width, height) = (*size.first().unwrap_or(&0) as u16 % 20,
+                                   *size.get(1).unwrap_or(&0) as u16 % 20);
+            let encoding = encodings[i % encodings.len()];
+            let data = if encoding == protocol::Encoding::Zrle && i % 2 == 0 {
+                zrle_frame(width, height, &protocol::tests::random_bytes(&mut seed, 64))
+            } else {
+                frame(width, height, encoding, &protocol::tests::random_bytes(&mut seed, 64))
+            };
+            let _ = super::decode_frame(&formats[i % formats.len()], &data);
+        }
+    }
+
+    // Each of these used to panic, hang or allocate far more than the input size.
+    #[test]
+    fn decode_frame_malformed() {
+        let format = test_format();
+        let inputs = [
+            // Palette of 3 colours, pixel with index 3.
+            (zrle_frame(1, 1, &[0x03, 0, 0, 0, 1, 1, 1, 2, 2, 2, 0xc0]),
+             "ZRLE palette index"),
+            // RLE run of 6 pixels in a 1x1 tile.
+            (zrle_frame(1, 1, &[0x80, 1, 2, 3, 5]), "ZRLE run length"),
+            (frame(100, 100, protocol::Encoding::Raw, &[0; 4]), "message length"),
+            (frame(1, 1, protocol::Encoding::Zrle, &[0xff; 4]), "message length"),
+            (frame(0xffff, 0xffff, protocol::Encoding::Cursor, &[]), "message length"),
+            // 0xffff * 0xffff * 4 does not fit into a 32-bit usize.
+            (frame(0xffff, 0xffff, protocol::Encoding::Raw, &[]), "message length"),
+            (frame(0xffff, 0xffff, protocol::Encoding::XCursor, &[]), "message length"),
+        ];
+        for (input, error) in inputs.iter() {
+            match super::decode_frame(&format, input) {
+                Err(Error::Unexpected(message)) if message == *error => (),
+                result => panic!("unexpected {:?} for {:02x?}", result, input)
+            }
+        }
+
+        // Tiles used to be stepped through with `y + 64`, which wrapped around here.
+        let tall = frame(0, 0xffff, protocol::Encoding::Zrle, &[0; 4]);
+        assert_eq!(super::decode_frame(&format, &tall).unwrap(), []);
+    }
+
+    #[test]
+    fn decode_mixed_update() {
+        let mut zrle = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+        zrle.write_all(&[1, 0x11, 0x22, 0x33]).unwrap();
+        // Servers keep one zlib stream open for the connection and only ever flush it.
+        zrle.flush().unwrap();
+        let zrle = zrle.get_ref().clone();
+
+        let mut update = Vec::new();
+        protocol::S2C::FramebufferUpdate { count: 4 }.write_to(&mut update).unwrap();
+        protocol::Rectangle {
+            x_position: 1, y_position: 2, width: 1, height: 1, encoding: protocol::Encoding::Raw
+        }.write_to(&mut update).unwrap();
+        update.extend_from_slice(&[1, 2, 3, 4]);
+        protocol::Rectangle {
+            x_position: 5, y_position: 6, width: 3, height: 4,
+            encoding: protocol::Encoding::CopyRect
+        }.write_to(&mut update).unwrap();
+        protocol::CopyRect { src_x_position: 7, src_y_position: 8 }
+            .write_to(&mut update).unwrap();
+        protocol::Rectangle {
+            x_position: 0, y_position: 0, width: 2, height: 1, encoding: protocol::Encoding::Zrle
+        }.write_to(&mut update).unwrap();
+        zrle.write_to(&mut update).unwrap();
+        protocol::Rectangle {
+            x_position: 0, y_position: 0, width: 200, height: 100,
+            encoding: protocol::Encoding::DesktopSize
+        }.write_to(&mut update).unwrap();
+
+        let mut events = Vec::new();
+        let mut decoder = Decoder::new();
+        assert!(decode_update(&mut &update[..], test_format(), &mut decoder, |event| {
+            events.push(event);
+            true
+        }).unwrap());
+
+        assert_eq!(events.len(), 4);
+        match events[0] {
+            Event::PutPixels(rect, ref pixels) => {
+                assert_eq!(rect, Rect::new(1, 2, 1, 1));
+                assert_eq!(pixels, &[1, 2, 3, 4]);
+            },
+            ref event => panic!("unexpected {:?}", event)
+        }
+        match events[1] {
+            Event::CopyPixels { src, dst } => {
+                assert_eq!(src, Rect::new(7, 8, 3, 4));
+                assert_eq!(dst, Rect::new(5, 6, 3, 4));
+            },
+            ref event => panic!("unexpected {:?}", event)
+        }
+        match events[2] {
+            Event::PutPixels(rect, ref pixels) => {
+                assert_eq!(rect, Rect::new(0, 0, 2, 1));
+                assert_eq!(pixels, &[0x11, 0x22, 0x33, 0, 0x11, 0x22, 0x33, 0]);
+            },
+            ref event => panic!("unexpected {:?}", event)
+        }
+        match events[3] {
+            Event::Resize(200, 100) => (),
+            ref event => panic!("unexpected {:?}", event)
+        }
+
+        let mut bell = Vec::new();
+        protocol::S2C::Bell.write_to(&mut bell).unwrap();
+        match decode_update(&mut &bell[..], test_format(), &mut decoder, |_| true) {
+            Err(Error::Unexpected("message type")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn desktop_size() {
+        fn decode(rectangles: &[(protocol::Encoding, u16)]) -> Vec<Event> {
+            let mut update = Vec::new();
+            protocol::S2C::FramebufferUpdate { count: rectangles.len() as u16 }
+                .write_to(&mut update).unwrap();
+            for &(encoding, width) in rectangles {
+                protocol::Rectangle {
+                    x_position: 1, y_position: 0, width, height: 1, encoding
+                }.write_to(&mut update).unwrap();
+                match encoding {
+                    protocol::Encoding::Raw =>
+                        update.extend_from_slice(&vec![0; width as usize * 4]),
+                    protocol::Encoding::ExtendedDesktopSize => {
+                        update.extend_from_slice(&[1, 0, 0, 0]);
+                        crate::Screen { id: 0, x: 0, y: 0, width, height: 1, flags: 0 }
+                            .write_to(&mut update).unwrap()
+                    }
+                    _ => ()
+                }
+            }
+
+            let mut events = Vec::new();
+            decode_update(&mut &update[..], test_format(), &mut Decoder::new(), |event| {
+                events.push(event);
+                true
+            }).unwrap();
+            events
+        }
+
+        match &decode(&[(protocol::Encoding::DesktopSize, 200)])[..] {
+            [Event::Resize(200, 1)] => (),
+            events => panic!("unexpected {:?}", events)
+        }
+        match &decode(&[(protocol::Encoding::ExtendedDesktopSize, 200)])[..] {
+            [Event::ScreenLayout { width: 200, height: 1, reason: 1, status: 0, screens }]
+                if screens.len() == 1 => (),
+            events => panic!("unexpected {:?}", events)
+        }
+        for &(first, second) in &[
+            (protocol::Encoding::DesktopSize, protocol::Encoding::ExtendedDesktopSize),
+            (protocol::Encoding::ExtendedDesktopSize, protocol::Encoding::DesktopSize),
+        ] {
+            match &decode(&[(first, 200), (second, 200)])[..] {
+                [Event::ScreenLayout { width: 200, .. }] => (),
+                events => panic!("unexpected {:?}", events)
+            }
+        }
+        // Pixels following a resize are for the new size, so the order is kept.
+        match &decode(&[(protocol::Encoding::DesktopSize, 200), (protocol::Encoding::Raw, 2),
+                        (protocol::Encoding::DesktopSize, 300)])[..] {
+            [Event::Resize(200, 1), Event::PutPixels(..), Event::Resize(300, 1)] => (),
+            events => panic!("unexpected {:?}", events)
+        }
+    }
+
+    #[test]
+    fn capture_frame() {
+        let (stream, server) = mock_server(|mut stream| {
+            handshake(&mut stream, 2, 2, test_format());
+            assert_eq!(read_update_request(&mut stream), (false, Rect::new(0, 0, 2, 2)));
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 2, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[1, 2, 3, 0, 4, 5, 6, 0]),
+                (protocol::Rectangle {
+                    x_position: 1, y_position: 1, width: 1, height: 1,
+                    encoding: protocol::Encoding::CopyRect
+                }, &[0, 0, 0, 0]),
+            ]);
+            stream
+        });
+        let mut client = Builder::new().connect(stream, |_| Some(AuthChoice::None)).unwrap();
+        let (rect, pixels, format) = client.capture_frame().unwrap();
+        assert_eq!(rect, Rect::new(0, 0, 2, 2));
+        assert_eq!(format, test_format());
+        assert_eq!(pixels, [1, 2, 3, 0, 4, 5, 6, 0,
+                            0, 0, 0, 0, 1, 2, 3, 0]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn capture_frame_after_request() {
+        let raw = |x_position, y_position, width, height| protocol::Rectangle {
+            x_position, y_position, width, height, encoding: protocol::Encoding::Raw
+        };
+        // The earlier incremental update only has the top row; the server then either
+        // answers the capture request separately or folds it into the earlier update.
+        for &coalesce in &[false, true] {
+            let (stream, server) = mock_server(move |mut stream| {
+                handshake(&mut stream, 2, 2, test_format());
+                assert_eq!(read_update_request(&mut stream), (true, Rect::new(0, 0, 2, 2)));
+                assert_eq!(read_update_request(&mut stream), (false, Rect::new(0, 0, 2, 2)));
+                if coalesce {
+                    write_update(&mut stream, &[(raw(0, 0, 2, 2), &[1; 16])]);
+                } else {
+                    write_update(&mut stream, &[(raw(0, 0, 2, 1), &[9; 8])]);
+                    write_update(&mut stream, &[(raw(0, 0, 2, 2), &[1; 16])]);
+                }
+                stream
+            });
+            let mut client = Builder::new().connect(stream, |_| Some(AuthChoice::None)).unwrap();
+            client.request_full_update(true).unwrap();
+            let (rect, pixels, _) = client.capture_frame().unwrap();
+            assert_eq!(rect, Rect::new(0, 0, 2, 2));
+            assert_eq!(pixels, [1; 16]);
+            server.join().unwrap();
+        }
+    }
+
+    #[cfg(feature = "image")]
+    #[test]
+    fn capture_image() {
+        let (stream, server) = mock_server(|mut stream| {
+            handshake(&mut stream, 2, 1, test_format());
+            read_update_request(&mut stream);
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 2, height: 1,
+                encoding: protocol::Encoding::Raw
+            }, &[1, 2, 3, 0, 4, 5, 6, 0])]);
+            stream
+        });
+        let mut client = Builder::new().connect(stream, |_| Some(AuthChoice::None)).unwrap();
+        let image = client.capture_image().unwrap();
+        assert_eq!(image.dimensions(), (2, 1));
+        assert_eq!(image.get_pixel(1, 0), &image::Rgba([6, 5, 4, 255]));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn tight_security() {
+        let (stream, server) = mock_server(|mut stream| {
+            stream.write_all(b"RFB 003.008\n").unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            protocol::SecurityTypes(vec![protocol::SecurityType::Tight])
+                .write_to(&mut stream).unwrap();
+            assert_eq!(protocol::SecurityType::read_from(&mut stream).unwrap(),
+                       protocol::SecurityType::Tight);
+            protocol::TightCapabilities(vec![protocol::TightCapability::NO_TUNNEL])
+                .write_to(&mut stream).unwrap();
+            let mut tunnel = [0; 4];
+            stream.read_exact(&mut tunnel).unwrap();
+            protocol::TightCapabilities(vec![protocol::TightCapability::AUTH_NONE,
+                                             protocol::TightCapability::AUTH_VNC])
+                .write_to(&mut stream).unwrap();
+            let mut auth_type = [0; 4];
+            stream.read_exact(&mut auth_type).unwrap();
+            stream.write_all(&[0; 16]).unwrap();
+            stream.read_exact(&mut [0; 16]).unwrap();
+            protocol::SecurityResult::Succeeded.write_to(&mut stream).unwrap();
+            protocol::ClientInit::read_from(&mut stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       test_format(),
+                name:               String::from("test")
+            }.write_to(&mut stream).unwrap();
+            protocol::TightInteractionCapabilities {
+                server_messages: vec![],
+                client_messages: vec![],
+                encodings:       vec![protocol::TightCapability {
+                    code: 7, vendor: *b"TGHT", name: *b"TIGHT___"
+                }],
+            }.write_to(&mut stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            (tunnel, auth_type)
+        });
+        let mut client = Builder::new().connect(stream, |methods| {
+            match methods {
+                [AuthMethod::Tight] =>
+                    Some(AuthChoice::Tight(Box::new(AuthChoice::Password([0; 8])))),
+                _ => None
+            }
+        }).unwrap();
+        // The interaction capabilities must not be mistaken for a server message.
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(server.join().unwrap(), ([0, 0, 0, 0], [0, 0, 0, 2]));
+    }
+
+    #[test]
+    fn native_endian() {
+        fn decode(bits_per_pixel: u8, pixels: &[u8]) -> Vec<u8> {
+            let format = protocol::PixelFormat {
+                bits_per_pixel, big_endian: true, ..test_format()
+            };
+            let mut update = Vec::new();
+            protocol::S2C::FramebufferUpdate { count: 1 }.write_to(&mut update).unwrap();
+            protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 2, height: 1,
+                encoding: protocol::Encoding::Raw
+            }.write_to(&mut update).unwrap();
+            update.extend_from_slice(pixels);
+
+            let mut decoder = Decoder::new();
+            decoder.set_native_endian(true);
+            let mut result = None;
+            decode_update(&mut &update[..], format, &mut decoder, |event| {
+                if let Event::PutPixels(_, pixels) = event { result = Some(pixels) }
+                true
+            }).unwrap();
+            result.unwrap()
+        }
+
+        let swap = cfg!(target_endian = "little");
+        assert_eq!(decode(16, &[0x12, 0x34, 0x56, 0x78]),
+                   if swap { [0x34, 0x12, 0x78, 0x56] } else { [0x12, 0x34, 0x56, 0x78] });
+        assert_eq!(decode(32, &[1, 2, 3, 4, 5, 6, 7, 8]),
+                   if swap { [4, 3, 2, 1, 8, 7, 6, 5] } else { [1, 2, 3, 4, 5, 6, 7, 8] });
+    }
+
+    #[test]
+    fn rgba_pixels() {
+        let rgb565 = protocol::PixelFormat {
+            bits_per_pixel: 16, depth: 16, big_endian: true,
+            red_max: 31, green_max: 63, blue_max: 31,
+            red_shift: 11, green_shift: 5, blue_shift: 0,
+            ..test_format()
+        };
+        assert_eq!(super::rgba_pixels(rgb565, &[], &[0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f,
+                                                      0x84, 0x10]),
+                   [255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, 131, 129, 131, 255]);
+        let rgb565 = protocol::PixelFormat { big_endian: false, ..rgb565 };
+        assert_eq!(super::rgba_pixels(rgb565, &[], &[0x1f, 0x00]), [0, 0, 255, 255]);
+
+        let indexed = protocol::PixelFormat {
+            bits_per_pixel: 8, depth: 8, true_colour: false,
+            red_max: 0, green_max: 0, blue_max: 0,
+            red_shift: 0, green_shift: 0, blue_shift: 0,
+            ..test_format()
+        };
+        let palette = [Colour { red: 0xffff, green: 0x8000, blue: 0 },
+                       Colour { red: 0x1234, green: 0x5678, blue: 0x9abc }];
+        assert_eq!(super::rgba_pixels(indexed, &palette, &[1, 0, 2]),
+                   [0x12, 0x56, 0x9a, 255, 0xff, 0x80, 0x00, 255, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn rgba_output() {
+        let format = protocol::PixelFormat {
+            bits_per_pixel: 8, depth: 8, big_endian: false, true_colour: false,
+            red_max: 0, green_max: 0, blue_max: 0,
+            red_shift: 0, green_shift: 0, blue_shift: 0
+        };
+        let builder = Builder::new().rgba_output(true);
+        let (mut client, server) = connect(builder, format, |mut stream| {
+            protocol::S2C::SetColourMapEntries {
+                first_colour: 1, colours: vec![Colour { red: 0xffff, green: 0x8000, blue: 0 }]
+            }.write_to(&mut stream).unwrap();
+            write_update(&mut stream, &[(protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 2, height: 1,
+                encoding: protocol::Encoding::Raw
+            }, &[1, 0])]);
+            stream
+        });
+
+        match wait_event(&mut client) {
+            Event::SetColourMap { first_colour: 1, .. } => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::PutPixels(_, pixels) =>
+                assert_eq!(pixels, [0xff, 0x80, 0x00, 255, 0, 0, 0, 255]),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn strict_encodings() {
+        fn run(builder: Builder) -> Event {
+            let (mut client, server) = connect(builder, test_format(), |mut stream| {
+                match protocol::C2S::read_from(&mut stream).unwrap() {
+                    protocol::C2S::SetEncodings(encodings) =>
+                        assert_eq!(encodings, [protocol::Encoding::Zrle,
+                                               protocol::Encoding::Raw]),
+                    message => panic!("unexpected {:?}", message)
+                }
+                // A solid tile, as if the server had never received SetEncodings.
+                write_update(&mut stream, &[(protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 2, height: 1,
+                    encoding: protocol::Encoding::Hextile
+                }, &[0x02, 1, 2, 3, 0])]);
+                stream
+            });
+            client.set_encodings(&[protocol::Encoding::Zrle, protocol::Encoding::Raw]).unwrap();
+            let event = wait_event(&mut client);
+            server.join().unwrap();
+            event
+        }
+
+        match run(Builder::new().strict_encodings(false)) {
+            Event::PutPixels(rect, pixels) => {
+                assert_eq!(rect, Rect::new(0, 0, 2, 1));
+                assert_eq!(pixels, [1, 2, 3, 0, 1, 2, 3, 0]);
+            }
+            event => panic!("unexpected {:?}", event)
+        }
+        match run(Builder::new()) {
+            Event::Disconnected(Some(Error::Unexpected("encoding"))) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+    }
+
+    #[test]
+    fn pause() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            let key_event = protocol::C2S::read_from(&mut stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            (key_event, read_update_request(&mut stream))
+        });
+        client.pause();
+        assert!(client.is_paused());
+        client.send_key_event(true, 0x61).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(client.poll_event().is_none());
+        // Not sent at all.
+        client.request_update(Rect::new(0, 0, 10, 10), true).unwrap();
+        match client.capture_frame() {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        client.resume().unwrap();
+        assert!(!client.is_paused());
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match server.join().unwrap() {
+            (protocol::C2S::KeyEvent { down: true, key: 0x61 }, (false, rect)) =>
+                assert_eq!(rect, Rect::new(0, 0, 100, 100)),
+            messages => panic!("unexpected {:?}", messages)
+        }
+    }
+
+    #[test]
+    fn stats() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[0; 4]),
+                (protocol::Rectangle {
+                    x_position: 1, y_position: 0, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[0; 4]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 1, width: 2, height: 1,
+                    encoding: protocol::Encoding::CopyRect
+                }, &[0; 4]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 50, height: 50,
+                    encoding: protocol::Encoding::DesktopSize
+                }, &[]),
+            ]);
+            stream
+        });
+        loop {
+            if let Event::EndOfFrame = wait_event(&mut client) { break }
+        }
+        let stats = client.stats();
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.raw_rectangles, 2);
+        assert_eq!(stats.copy_rect_rectangles, 1);
+        assert_eq!(stats.zrle_rectangles, 0);
+        assert_eq!(stats.pseudo_rectangles, 1);
+        // The update message itself is 4 bytes and each rectangle header 12.
+        assert_eq!(stats.bytes_received, 4 + 4 * 12 + 4 + 4 + 4);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_timeout() {
+        let (stream, server) = mock_server(|mut stream| {
+            stream.write_all(b"RFB 003.").unwrap();
+            // Keep the connection open until the client gives up.
+            let _ = stream.read(&mut [0; 1]);
+        });
+        let builder = Builder::new().handshake_timeout(Duration::from_millis(100));
+        match builder.connect(stream, |_| Some(AuthChoice::None)) {
+            Err(Error::Timeout) => (),
+            result => panic!("unexpected {:?}", result.err())
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn end_of_continuous_updates() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::EndOfContinuousUpdates.write_to(&mut stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::ContinuousUpdatesEnded => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn clipboard_utf8() {
+        let utf8 = "h\u{e9}llo \u{2713}";
+        let (mut client, server) = connect(Builder::new(), test_format(), move |mut stream| {
+            for _ in 0..2 {
+                stream.write_all(&[3, 0, 0, 0, 0, 0, 0, utf8.len() as u8]).unwrap();
+                stream.write_all(utf8.as_bytes()).unwrap();
+            }
+            let mut received = Vec::new();
+            for _ in 0..2 {
+                match protocol::C2S::read_from(&mut stream).unwrap() {
+                    protocol::C2S::CutText(text) =>
+                        received.push(text.chars().map(|c| c as u8).collect::<Vec<u8>>()),
+                    message => panic!("unexpected {:?}", message)
+                }
+            }
+            received
+        });
+
+        match wait_event(&mut client) {
+            Event::Clipboard(ref text) => assert_eq!(text, "h\u{c3}\u{a9}llo \u{e2}\u{9c}\u{93}"),
+            event => panic!("unexpected {:?}", event)
+        }
+        client.set_clipboard_utf8(true);
+        match wait_event(&mut client) {
+            Event::Clipboard(ref text) => assert_eq!(text, utf8),
+            event => panic!("unexpected {:?}", event)
+        }
+
+        client.update_clipboard(utf8).unwrap();
+        client.set_clipboard_utf8(false);
+        client.update_clipboard(utf8).unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received[0], utf8.as_bytes());
+        assert_eq!(received[1], b"h\xe9llo ?");
+    }
+
+    #[test]
+    fn pending_events() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            for _ in 0..3 {
+                protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            }
+            stream
+        });
+        for _ in 0..5000 {
+            if client.pending_events() == 3 { break }
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(client.pending_events(), 3);
+        client.poll_event().unwrap();
+        assert_eq!(client.pending_events(), 2);
+        client.poll_event().unwrap();
+        client.poll_event().unwrap();
+        assert!(!client.has_pending());
+        assert!(client.poll_event().is_none());
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn event_fd() {
+        use std::os::unix::io::AsRawFd;
+
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            stream
+        });
+        assert_eq!(client.event_fd(), client.wakeup.as_raw_fd());
+        while !client.has_pending() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        // Give the event thread a moment to write the wakeup after queueing the event.
+        thread::sleep(Duration::from_millis(10));
+        let mut buffer = [0; 1];
+        assert_eq!((&client.wakeup).read(&mut buffer).unwrap(), 1);
+
+        match client.poll_event() {
+            Some(Event::Bell) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!((&client.wakeup).read(&mut buffer).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn normalize_encodings() {
+        use crate::protocol::Encoding;
+
+        assert_eq!(super::normalize_encodings(
+                       &[Encoding::Zrle, Encoding::Cursor, Encoding::Zrle,
+                         Encoding::CompressionLevel(1), Encoding::Raw, Encoding::Cursor],
+                       &[Encoding::CompressionLevel(9), Encoding::JpegQuality(5)]).unwrap(),
+                   [Encoding::Zrle, Encoding::Raw,
+                    Encoding::Cursor, Encoding::CompressionLevel(1), Encoding::JpegQuality(5)]);
+        assert_eq!(super::normalize_encodings(
+                       &[Encoding::Unknown(-1000), Encoding::Unknown(1000), Encoding::Raw],
+                       &[]).unwrap(),
+                   [Encoding::Unknown(1000), Encoding::Raw, Encoding::Unknown(-1000)]);
+        match super::normalize_encodings(&[], &[Encoding::CompressionLevel(9)]) {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn send_scroll() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            let mut masks = Vec::new();
+            for _ in 0..7 {
+                match protocol::C2S::read_from(&mut stream).unwrap() {
+                    protocol::C2S::PointerEvent { button_mask, x_position: 10, y_position: 20 } =>
+                        masks.push(button_mask),
+                    message => panic!("unexpected {:?}", message)
+                }
+            }
+            masks
+        });
+        client.send_scroll(1, 0, 10, 20).unwrap();
+        client.send_pointer_event(PointerButtons::LEFT, 10, 20).unwrap();
+        client.send_scroll(-1, -1, 10, 20).unwrap();
+        assert_eq!(server.join().unwrap(),
+                   [0x40, 0x00, 0x01, 0x11, 0x01, 0x21, 0x01]);
+    }
+
+    #[test]
+    fn cursor_position() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 12, y_position: 34, width: 0, height: 0,
+                    encoding: protocol::Encoding::CursorPosition
+                }, &[]),
+            ]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::CursorPosition { x: 12, y: 34 } => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::EndOfFrame => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn desktop_name_led_state_options() {
+        for (builder, expected) in [
+                (Builder::new().desktop_name(true), protocol::Encoding::DesktopName),
+                (Builder::new().led_state(true), protocol::Encoding::QemuLedState)] {
+            let (mut client, server) = connect(builder, test_format(), |mut stream| {
+                protocol::C2S::read_from(&mut stream).unwrap()
+            });
+            client.set_encodings(&[protocol::Encoding::Raw]).unwrap();
+            match server.join().unwrap() {
+                protocol::C2S::SetEncodings(encodings) =>
+                    assert_eq!(encodings, [protocol::Encoding::Raw, expected]),
+                message => panic!("unexpected {:?}", message)
+            }
+        }
+    }
+
+    #[test]
+    fn desktop_name_led_state() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 0, height: 0,
+                    encoding: protocol::Encoding::DesktopName
+                }, b"\0\0\0\x05caf\xc3\xa9"),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 0, height: 0,
+                    encoding: protocol::Encoding::QemuLedState
+                }, &[0b110]),
+            ]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::DesktopName(ref name) if name == "caf\u{e9}" => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(client.name(), "caf\u{e9}");
+        match wait_event(&mut client) {
+            Event::LedState(0b110) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn initial_format() {
+        let format = protocol::PixelFormat { red_shift: 0, blue_shift: 16, ..test_format() };
+        let (mut client, server) = connect(Builder::new().initial_format(format), test_format(),
+                                           |mut stream| {
+            (0..2).map(|_| protocol::C2S::read_from(&mut stream).unwrap()).collect::<Vec<_>>()
+        });
+        assert_eq!(client.format(), format);
+        client.request_update(Rect::new(0, 0, 100, 100), false).unwrap();
+        match &server.join().unwrap()[..] {
+            [protocol::C2S::SetPixelFormat(sent),
+             protocol::C2S::FramebufferUpdateRequest { incremental: false, .. }]
+                    if *sent == format => (),
+            messages => panic!("unexpected {:?}", messages)
+        }
+    }
+
+    #[test]
+    fn set_format_resize() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            match protocol::C2S::read_from(&mut stream).unwrap() {
+                protocol::C2S::FramebufferUpdateRequest { incremental: false, .. } => (),
+                message => panic!("unexpected {:?}", message)
+            }
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 1, height: 2,
+                    encoding: protocol::Encoding::DesktopSize
+                }, &[]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 1, height: 2,
+                    encoding: protocol::Encoding::Raw
+                }, &[0; 8]),
+            ]);
+            protocol::C2S::read_from(&mut stream).unwrap()
+        });
+        while !client.has_pending() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match &client.drain_events()[..] {
+            [Event::Bell] => (),
+            events => panic!("unexpected {:?}", events)
+        }
+
+        let mut format = test_format();
+        format.big_endian = true;
+        client.set_format(format).unwrap();
+        assert_eq!(client.size(), (1, 2));
+        // Nothing received while waiting for the full update is lost.
+        match &client.drain_events()[..] {
+            [Event::Resize(1, 2), Event::PutPixels(rect, _), ..]
+                if *rect == Rect::new(0, 0, 1, 2) => (),
+            events => panic!("unexpected {:?}", events)
+        }
+        match server.join().unwrap() {
+            protocol::C2S::SetPixelFormat(pixel_format) => assert_eq!(pixel_format, format),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn relative_pointer() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            match protocol::C2S::read_from(&mut stream).unwrap() {
+                protocol::C2S::SetEncodings(ref encodings)
+                    if encodings == &[protocol::Encoding::Raw,
+                                      protocol::Encoding::QemuPointerMotionChange] => (),
+                message => panic!("unexpected {:?}", message)
+            }
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 100, height: 100,
+                    encoding: protocol::Encoding::QemuPointerMotionChange
+                }, &[]),
+            ]);
+            protocol::C2S::read_from(&mut stream).unwrap()
+        });
+        client.set_relative_pointer(true);
+        client.set_encodings(&[protocol::Encoding::Raw]).unwrap();
+        assert!(!client.is_relative_pointer());
+        assert!(client.send_relative_pointer_event(PointerButtons::NONE, 1, 1).is_err());
+        match wait_event(&mut client) {
+            Event::RelativePointer(true) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert!(client.is_relative_pointer());
+        client.send_relative_pointer_event(PointerButtons::LEFT, -5, 3).unwrap();
+        match server.join().unwrap() {
+            protocol::C2S::PointerEvent { button_mask: 1, x_position: 0x7ffa,
+                                          y_position: 0x8002 } => (),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn format_changed() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::C2S::read_from(&mut stream).unwrap();
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 100, height: 100,
+                    encoding: protocol::Encoding::Raw
+                }, &[0; 100 * 100 * 4]),
+            ]);
+            protocol::C2S::read_from(&mut stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+        });
+        let format = protocol::PixelFormat { big_endian: true, ..test_format() };
+        client.set_format(format).unwrap();
+        // set_format returns as soon as it sees the pixels, before the end of the update.
+        match client.poll_event() {
+            Some(Event::PutPixels(..)) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::EndOfFrame => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::FormatChanged(changed) => assert_eq!(changed, format),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn coalesce_frames() {
+        let builder = Builder::new().coalesce_frames(true);
+        let (mut client, server) = connect(builder, test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 1, y_position: 1, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[1; 4]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[2; 4]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 2, height: 2,
+                    encoding: protocol::Encoding::Raw
+                }, &[3; 16]),
+                (protocol::Rectangle {
+                    x_position: 1, y_position: 0, width: 2, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[4; 8]),
+            ]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::Frame(rects) =>
+                assert_eq!(rects, [(Rect::new(0, 0, 2, 2), vec![3; 16]),
+                                   (Rect::new(1, 0, 2, 1), vec![4; 8])]),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::EndOfFrame => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn colour_map_overflow() {
+        let format = protocol::PixelFormat {
+            bits_per_pixel: 8, depth: 8, true_colour: false, ..test_format()
+        };
+        let (mut client, server) = connect(Builder::new(), format, |mut stream| {
+            protocol::S2C::SetColourMapEntries {
+                first_colour: 0xfffe,
+                colours:      vec![Colour { red: 1, green: 2, blue: 3 }; 5]
+            }.write_to(&mut stream).unwrap();
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::SetColourMap { first_colour: 0xfffe, ref colours } =>
+                assert_eq!(colours.len(), 2),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(client.palette().unwrap().len(), 0x10000);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn colour_map_for_true_colour() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::SetColourMapEntries {
+                first_colour: 0, colours: vec![Colour { red: 1, green: 2, blue: 3 }]
+            }.write_to(&mut stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::ProtocolWarning(_) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert_eq!(client.palette(), None);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shared_flag() {
+        for &shared in &[false, true] {
+            let (stream, server) = mock_server(|mut stream| {
+                stream.write_all(b"RFB 003.008\n").unwrap();
+                stream.read_exact(&mut [0; 12]).unwrap();
+                protocol::SecurityTypes(vec![protocol::SecurityType::None])
+                    .write_to(&mut stream).unwrap();
+                protocol::SecurityType::read_from(&mut stream).unwrap();
+                protocol::SecurityResult::Succeeded.write_to(&mut stream).unwrap();
+                let mut client_init = [0; 1];
+                stream.read_exact(&mut client_init).unwrap();
+                protocol::ServerInit {
+                    framebuffer_width:  100,
+                    framebuffer_height: 100,
+                    pixel_format:       test_format(),
+                    name:               String::from("test")
+                }.write_to(&mut stream).unwrap();
+                client_init
+            });
+            let client = Builder::new().shared(shared)
+                .connect(stream, |_| Some(AuthChoice::None)).unwrap();
+            assert_eq!(client.is_shared(), shared);
+            assert_eq!(server.join().unwrap(), [shared as u8]);
+        }
+    }
+
+    #[test]
+    fn accept_reverse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            handshake(&mut stream, 100, 100, test_format());
+            stream
+        });
+        let client = Client::accept_reverse(&listener, |_| Some(AuthChoice::None)).unwrap();
+        assert_eq!(client.name(), "test");
+        assert_eq!(client.size(), (100, 100));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn event_queue_bound() {
+        let builder = Builder::new().event_queue_bound(2);
+        let (mut client, server) = connect(builder, test_format(), |mut stream| {
+            for _ in 0..5 {
+                protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            }
+            stream
+        });
+        let stream = server.join().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        // Two events are queued and the event thread is blocked sending the third one.
+        assert_eq!(client.pending_events(), 3);
+        for _ in 0..5 {
+            match wait_event(&mut client) {
+                Event::Bell => (),
+                event => panic!("unexpected {:?}", event)
+            }
+        }
+        drop(stream);
+    }
+
+    #[test]
+    fn custom_decoder() {
+        use std::io::Read;
+        use super::RectangleDecoder;
+
+        // One byte per pixel, which becomes a grey pixel in the client's format.
+        struct Grey;
+
+        impl RectangleDecoder for Grey {
+            fn decode(&mut self, rect: Rect, reader: &mut dyn Read,
+                      format: protocol::PixelFormat,
+                      emit: &mut dyn FnMut(Event) -> bool) -> crate::Result<bool> {
+                let mut levels = vec![0; rect.area()];
+                reader.read_exact(&mut levels)?;
+                let mut pixels = Vec::new();
+                for level in levels {
+                    let level = level as u16;
+                    pixels.extend_from_slice(
+                        &format.pack_rgb(level, level, level).to_le_bytes());
+                }
+                Ok(emit(Event::PutPixels(rect, pixels)))
+            }
+        }
+
+        let builder = Builder::new()
+            .register_decoder(protocol::Encoding::Unknown(0x4752), || Box::new(Grey));
+        let (mut client, server) = connect(builder, test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 2, height: 1,
+                    encoding: protocol::Encoding::Unknown(0x4752)
+                }, &[0x10, 0x20]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 1, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[1, 2, 3, 0]),
+            ]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::PutPixels(rect, ref pixels) if rect == Rect::new(0, 0, 2, 1) =>
+                assert_eq!(pixels[..], [0x10, 0x10, 0x10, 0, 0x20, 0x20, 0x20, 0]),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::PutPixels(rect, _) if rect == Rect::new(0, 1, 1, 1) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn extended_key_event() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 0, height: 0,
+                    encoding: protocol::Encoding::ExtendedKeyEvent
+                }, &[]),
+            ]);
+            protocol::C2S::read_from(&mut stream).unwrap()
+        });
+        assert!(!client.supports_extended_key_event());
+        match client.send_extended_key_event(true, 0x61, 0x1e) {
+            Err(Error::Unexpected(_)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match wait_event(&mut client) {
+            Event::ExtendedKeyEventSupported => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        assert!(client.supports_extended_key_event());
+        client.send_extended_key_event(true, 0x61, 0x1e).unwrap();
+        match server.join().unwrap() {
+            protocol::C2S::ExtendedKeyEvent { down: true, key: 0x61, keycode: 0x1e } => (),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn truncated_update() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::FramebufferUpdate { count: 2 }.write_to(&mut stream).unwrap();
+            protocol::Rectangle {
+                x_position: 0, y_position: 0, width: 2, height: 2,
+                encoding: protocol::Encoding::Raw
+            }.write_to(&mut stream).unwrap();
+            stream.write_all(&[0; 10]).unwrap();
+        });
+        server.join().unwrap();
+        // The same as if the server closed the connection between two messages.
+        match wait_event(&mut client) {
+            Event::Disconnected(None) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+    }
+
+    #[test]
+    fn truncated_message() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            protocol::S2C::Bell.write_to(&mut stream).unwrap();
+            // SetColourMapEntries, cut off in the middle of the first colour.
+            stream.write_all(&[1, 0, 0, 0, 0, 1, 0xff]).unwrap();
+        });
+        server.join().unwrap();
+        match wait_event(&mut client) {
+            Event::Bell => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        match wait_event(&mut client) {
+            Event::Disconnected(None) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+    }
+
+    #[test]
+    fn truncated_zrle_is_not_disconnect() {
+        // The compressed data is all there, but decompresses to too little for the tile;
+        // the connection stays open, so this is an error in the data.
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            // A raw tile without its pixel.
+            stream.write_all(&[0, 0]).unwrap();
+            stream.write_all(&zrle_frame(1, 1, &[0])).unwrap();
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::Disconnected(Some(Error::Io(ref error)))
+                if error.kind() == io::ErrorKind::UnexpectedEof => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn xcursor() {
+        let (mut client, server) = connect(Builder::new(), test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 2, height: 2,
+                    encoding: protocol::Encoding::XCursor
+                }, &[0x00, 0x00, 0x00, 0xff, 0x80, 0x00,
+                     0b10_000000, 0b11_000000,
+                     0b10_000000, 0b11_000000]),
+            ]);
+            stream
+        });
+        match wait_event(&mut client) {
+            Event::SetCursor { size: (2, 2), hotspot: (0, 0), ref pixels, ref mask_bits } => {
+                assert_eq!(pixels[..], [0x00, 0x00, 0x00, 0, 0x00, 0x80, 0xff, 0,
+                                        0x00, 0x00, 0x00, 0, 0x00, 0x00, 0x00, 0]);
+                assert_eq!(mask_bits[..], [0b10_000000, 0b11_000000]);
+            },
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn frame_stats() {
+        let builder = Builder::new().emit_stats(true);
+        let (mut client, server) = connect(builder, test_format(), |mut stream| {
+            write_update(&mut stream, &[
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 0, width: 1, height: 1,
+                    encoding: protocol::Encoding::Raw
+                }, &[0; 4]),
+                (protocol::Rectangle {
+                    x_position: 0, y_position: 1, width: 2, height: 1,
+                    encoding: protocol::Encoding::CopyRect
+                }, &[0; 4]),
+            ]);
+            stream
+        });
+        loop {
+            match wait_event(&mut client) {
+                Event::FrameStats { rectangles, bytes, ref encodings, .. } => {
+                    assert_eq!(rectangles, 2);
+                    // The update message itself is 4 bytes and each rectangle header 12.
+                    assert_eq!(bytes, 4 + 2 * 12 + 4 + 4);
+                    assert_eq!(encodings[..], [protocol::Encoding::Raw,
+                                               protocol::Encoding::CopyRect]);
+                    break
+                }
+                Event::EndOfFrame => panic!("no FrameStats before EndOfFrame"),
+                _ => ()
+            }
+        }
+        match wait_event(&mut client) {
+            Event::EndOfFrame => (),
+            event => panic!("unexpected {:?}", event)
+        }
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use crate::{PixelFormat, Rect};
+
+/* Finds the regions that differ between two framebuffers by comparing them
+   tile by tile on a fixed grid; changed tiles that are adjacent within a row
+   are merged into a single rectangle. */
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyTracker {
+    tile_size: u16
+}
+
+impl DirtyTracker {
+    pub fn new(tile_size: u16) -> DirtyTracker {
+        assert!(tile_size > 0);
+        DirtyTracker { tile_size }
+    }
+
+    pub fn tile_size(&self) -> u16 { self.tile_size }
+
+    // Both buffers are tightly packed `width` x `height` images in `format`.
+    pub fn diff(&self, format: PixelFormat, width: u16, height: u16,
+                previous: &[u8], current: &[u8]) -> Vec<Rect> {
+        let bpp = format.bits_per_pixel as usize / 8;
+        let stride = width as usize * bpp;
+        assert_eq!(previous.len(), stride * height as usize);
+        assert_eq!(current.len(), stride * height as usize);
+
+        let tile_changed = |tile: Rect| {
+            let start = tile.left as usize * bpp;
+            let end   = start + tile.width as usize * bpp;
+            (tile.top as usize..tile.top as usize + tile.height as usize).any(|y| {
+                let row = y * stride;
+                previous[row + start..row + end] != current[row + start..row + end]
+            })
+        };
+
+        let mut rects = Vec::new();
+        let mut y = 0;
+        while y < height {
+            let tile_height = std::cmp::min(self.tile_size, height - y);
+            let mut run: Option<Rect> = None;
+            let mut x = 0;
+            while x < width {
+                let tile_width = std::cmp::min(self.tile_size, width - x);
+                let tile = Rect::new(x, y, tile_width, tile_height);
+                if tile_changed(tile) {
+                    match run {
+                        Some(ref mut run) => run.width += tile_width,
+                        None => run = Some(tile)
+                    }
+                } else if let Some(run) = run.take() {
+                    rects.push(run)
+                }
+                x += tile_width;
+            }
+            rects.extend(run);
+            y += tile_height;
+        }
+        rects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PixelFormat, Rect};
+    use super::DirtyTracker;
+
+    const FORMAT: PixelFormat = PixelFormat {
+        bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+        red_max: 255, green_max: 255, blue_max: 255,
+        red_shift: 16, green_shift: 8, blue_shift: 0
+    };
+
+    #[test]
+    fn unchanged() {
+        let frame = vec![0x55; 100 * 50 * 4];
+        assert_eq!(DirtyTracker::new(16).diff(FORMAT, 100, 50, &frame, &frame), vec![]);
+    }
+
+    #[test]
+    fn single_tile() {
+        let previous = vec![0; 100 * 50 * 4];
+        let mut current = previous.clone();
+        current[(20 * 100 + 40) * 4] = 1;
+        assert_eq!(DirtyTracker::new(16).diff(FORMAT, 100, 50, &previous, &current),
+                   vec![Rect::new(32, 16, 16, 16)]);
+    }
+
+    #[test]
+    fn partial_tiles() {
+        let previous = vec![0; 100 * 50 * 4];
+        let mut current = previous.clone();
+        current[(49 * 100 + 99) * 4 + 3] = 1;
+        current[(49 * 100 + 90) * 4] = 1;
+        current[(49 * 100 + 70) * 4] = 1;
+        assert_eq!(DirtyTracker::new(16).diff(FORMAT, 100, 50, &previous, &current),
+                   vec![Rect::new(64, 48, 36, 2)]);
+    }
+}
@@ -0,0 +1,26 @@
+//! Decoders and encoders for individual encodings, for programs that read or write
+//! rectangle data themselves rather than through `client::Decoder`.
+//!
+//! ```
+//! use vnc::{PixelFormat, Rect};
+//! use vnc::encoding::ZrleDecoder;
+//!
+//! let format = PixelFormat {
+//!     bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+//!     red_max: 255, green_max: 255, blue_max: 255,
+//!     red_shift: 16, green_shift: 8, blue_shift: 0
+//! };
+//! // A 2x2 rectangle filled with #112233: one solid tile, compressed with zlib.
+//! let data = [0x78, 0x9c, 0x62, 0x34, 0x56, 0x12, 0x04, 0x00, 0x00, 0x00, 0xff, 0xff];
+//!
+//! let mut decoder = ZrleDecoder::new();
+//! let mut tiles = Vec::new();
+//! decoder.decode(format, Rect::new(0, 0, 2, 2), &data, |tile, pixels| {
+//!     tiles.push((tile, pixels));
+//!     Ok(true)
+//! }).unwrap();
+//! assert_eq!(tiles, [(Rect::new(0, 0, 2, 2), [0x33, 0x22, 0x11, 0].repeat(4))]);
+//! ```
+
+pub use crate::zrle::{Decoder as ZrleDecoder, Encoder as ZrleEncoder};
+pub use crate::hextile::encode as encode_hextile;
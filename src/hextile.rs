@@ -0,0 +1,282 @@
+use std::cmp;
+use std::io::Read;
+use byteorder::ReadBytesExt;
+use crate::{protocol, Error, Result};
+
+const RAW:                  u8 = 1 << 0;
+const BACKGROUND_SPECIFIED: u8 = 1 << 1;
+const FOREGROUND_SPECIFIED: u8 = 1 << 2;
+const ANY_SUBRECTS:         u8 = 1 << 3;
+const SUBRECTS_COLOURED:    u8 = 1 << 4;
+
+// Reads a Hextile rectangle and returns its pixels, tightly packed. The background
+// and foreground colours carry over from one tile to the next, and start out as zero.
+pub fn decode<R: Read>(reader: &mut R, bpp: usize, width: u16, height: u16,
+                       max_length: usize) -> Result<Vec<u8>> {
+    let stride = width as usize * bpp;
+    let mut pixels = vec![0; protocol::pixel_data_length(width, height, bpp, max_length)?];
+    let fill = |pixels: &mut [u8], x: usize, y: usize, w: usize, h: usize,
+                colour: &[u8]| {
+        for row in y..y + h {
+            for pixel in pixels[row * stride + x * bpp..row * stride + (x + w) * bpp]
+                    .chunks_mut(bpp) {
+                pixel.copy_from_slice(colour)
+            }
+        }
+    };
+
+    let (mut background, mut foreground) = (vec![0; bpp], vec![0; bpp]);
+    let mut colour = vec![0; bpp];
+    for tile_y in (0..height as usize).step_by(16) {
+        for tile_x in (0..width as usize).step_by(16) {
+            let tile_width  = cmp::min(width as usize - tile_x, 16);
+            let tile_height = cmp::min(height as usize - tile_y, 16);
+
+            let flags = reader.read_u8()?;
+            if flags & RAW != 0 {
+                for row in tile_y..tile_y + tile_height {
+                    let start = row * stride + tile_x * bpp;
+                    reader.read_exact(&mut pixels[start..start + tile_width * bpp])?;
+                }
+                continue
+            }
+
+            if flags & BACKGROUND_SPECIFIED != 0 {
+                reader.read_exact(&mut background)?;
+            }
+            fill(&mut pixels, tile_x, tile_y, tile_width, tile_height, &background);
+            if flags & FOREGROUND_SPECIFIED != 0 {
+                reader.read_exact(&mut foreground)?;
+            }
+            if flags & ANY_SUBRECTS == 0 { continue }
+
+            for _ in 0..reader.read_u8()? {
+                if flags & SUBRECTS_COLOURED != 0 {
+                    reader.read_exact(&mut colour)?;
+                } else {
+                    colour.copy_from_slice(&foreground);
+                }
+                let (position, size) = (reader.read_u8()?, reader.read_u8()?);
+                let (x, y) = ((position >> 4) as usize, (position & 15) as usize);
+                let (w, h) = ((size >> 4) as usize + 1, (size & 15) as usize + 1);
+                if x + w > tile_width || y + h > tile_height {
+                    return Err(Error::Unexpected("Hextile subrectangle"))
+                }
+                fill(&mut pixels, tile_x + x, tile_y + y, w, h, &colour);
+            }
+        }
+    }
+    Ok(pixels)
+}
+
+// The counterpart of decode. Every tile is sent either raw or as a background with
+// subrectangles, whichever is smaller; colours carried over from the previous tile
+// are not repeated.
+pub fn encode(bpp: usize, width: u16, height: u16, pixels: &[u8]) -> Result<Vec<u8>> {
+    let stride = width as usize * bpp;
+    if pixels.len() != stride * height as usize {
+        return Err(Error::Unexpected("Hextile pixel data length"))
+    }
+
+    let mut data = Vec::new();
+    // The colours the decoder holds, where known.
+    let (mut background, mut foreground): (Option<&[u8]>, Option<&[u8]>) = (None, None);
+    for tile_y in (0..height as usize).step_by(16) {
+        for tile_x in (0..width as usize).step_by(16) {
+            let tile_width  = cmp::min(width as usize - tile_x, 16);
+            let tile_height = cmp::min(height as usize - tile_y, 16);
+            let pixel = |x: usize, y: usize| {
+                let start = (tile_y + y) * stride + (tile_x + x) * bpp;
+                &pixels[start..start + bpp]
+            };
+
+            // The most common colour becomes the background.
+            let mut colours: Vec<(&[u8], usize)> = Vec::new();
+            for y in 0..tile_height {
+                for x in 0..tile_width {
+                    match colours.iter_mut().find(|&&mut (colour, _)| colour == pixel(x, y)) {
+                        Some(&mut (_, ref mut count)) => *count += 1,
+                        None => colours.push((pixel(x, y), 1))
+                    }
+                }
+            }
+            let tile_background = colours.iter().max_by_key(|&&(_, count)| count).unwrap().0;
+
+            // Every other pixel is covered by the largest run to its right, extended down.
+            let mut covered = [[false; 16]; 16];
+            let mut subrects = Vec::new();
+            for y in 0..tile_height {
+                for x in 0..tile_width {
+                    let colour = pixel(x, y);
+                    if covered[y][x] || colour == tile_background { continue }
+                    let mut w = 1;
+                    while x + w < tile_width && !covered[y][x + w] && pixel(x + w, y) == colour {
+                        w += 1
+                    }
+                    let mut h = 1;
+                    while y + h < tile_height &&
+                            (x..x + w).all(|x| !covered[y + h][x] && pixel(x, y + h) == colour) {
+                        h += 1
+                    }
+                    for row in &mut covered[y..y + h] {
+                        for pixel_covered in &mut row[x..x + w] { *pixel_covered = true }
+                    }
+                    subrects.push((colour, x, y, w, h));
+                }
+            }
+
+            let coloured = colours.len() > 2;
+            let tile_foreground = if coloured { None } else { subrects.first().map(|s| s.0) };
+            let mut flags = 0;
+            if background != Some(tile_background) { flags |= BACKGROUND_SPECIFIED }
+            if tile_foreground.is_some() && foreground != tile_foreground {
+                flags |= FOREGROUND_SPECIFIED
+            }
+            if !subrects.is_empty() { flags |= ANY_SUBRECTS }
+            if coloured { flags |= SUBRECTS_COLOURED }
+
+            let colour_bytes = (flags & (BACKGROUND_SPECIFIED | FOREGROUND_SPECIFIED))
+                .count_ones() as usize * bpp;
+            let subrect_bytes = if subrects.is_empty() { 0 } else {
+                1 + subrects.len() * (2 + if coloured { bpp } else { 0 })
+            };
+            let raw_bytes = tile_width * tile_height * bpp;
+            if subrects.len() > 255 || raw_bytes < colour_bytes + subrect_bytes {
+                data.push(RAW);
+                for y in 0..tile_height {
+                    let start = (tile_y + y) * stride + tile_x * bpp;
+                    data.extend_from_slice(&pixels[start..start + tile_width * bpp]);
+                }
+                // Decoders are not required to keep the colours across a raw tile.
+                background = None;
+                foreground = None;
+                continue
+            }
+
+            data.push(flags);
+            if flags & BACKGROUND_SPECIFIED != 0 { data.extend_from_slice(tile_background) }
+            if flags & FOREGROUND_SPECIFIED != 0 {
+                data.extend_from_slice(tile_foreground.unwrap())
+            }
+            if !subrects.is_empty() {
+                data.push(subrects.len() as u8);
+                for &(colour, x, y, w, h) in &subrects {
+                    if coloured { data.extend_from_slice(colour) }
+                    data.push((x << 4 | y) as u8);
+                    data.push(((w - 1) << 4 | (h - 1)) as u8);
+                }
+            }
+            background = Some(tile_background);
+            if coloured {
+                foreground = None
+            } else if tile_foreground.is_some() {
+                foreground = tile_foreground
+            }
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{protocol, Error};
+    use super::{decode, encode};
+
+    fn round_trip(bpp: usize, width: u16, height: u16, pixels: &[u8]) -> Vec<u8> {
+        let data = encode(bpp, width, height, pixels).unwrap();
+        let mut reader = &data[..];
+        assert_eq!(decode(&mut reader, bpp, width, height, protocol::DEFAULT_MAX_LENGTH).unwrap(),
+                   pixels);
+        assert!(reader.is_empty());
+        data
+    }
+
+    #[test]
+    fn subencodings() {
+        // Tile 1: background 1, foreground 2, one subrectangle at (1, 0), 1x2.
+        // Tile 2: both colours carried over, a 1x1 subrectangle. Tile 3: raw.
+        let data = [0x0e, 1, 2, 1, 0x10, 0x01,
+                    0x08, 1, 0x00, 0x00,
+                    0x01, 9, 8, 7, 6];
+        let mut reader = &data[..];
+        assert_eq!(decode(&mut reader, 1, 34, 2, protocol::DEFAULT_MAX_LENGTH).unwrap(),
+                   [&[1, 2][..], &[1; 14], &[2], &[1; 15], &[9, 8],
+                    &[1, 2],     &[1; 14], &[1], &[1; 15], &[7, 6]].concat());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn coloured_subrects() {
+        let data = [0x1a, 0x11, 0x22, 2, 0x33, 0x44, 0x00, 0x00, 0x55, 0x66, 0x10, 0x00];
+        assert_eq!(decode(&mut &data[..], 2, 2, 1, protocol::DEFAULT_MAX_LENGTH).unwrap(),
+                   [0x33, 0x44, 0x55, 0x66]);
+    }
+
+    #[test]
+    fn encode_subencodings() {
+        // One solid tile, then the same colour carried over.
+        assert_eq!(round_trip(1, 32, 16, &[5; 32 * 16]), [0x02, 5, 0x00]);
+        // Two colours: a foreground subrectangle, then both colours carried over.
+        let mut pixels = [1; 16 * 16];
+        pixels[16 * 2 + 3..16 * 2 + 5].copy_from_slice(&[2, 2]);
+        let pixels = [pixels, pixels].concat();
+        assert_eq!(round_trip(1, 16, 32, &pixels), [0x0e, 1, 2, 1, 0x32, 0x10,
+                                                    0x08, 1, 0x32, 0x10]);
+        // Three colours: coloured subrectangles.
+        let mut pixels = [0; 4 * 4];
+        pixels[0] = 7;
+        pixels[15] = 8;
+        assert_eq!(round_trip(1, 4, 4, &pixels), [0x1a, 0, 2, 7, 0x00, 0x00, 8, 0x33, 0x00]);
+        // No two neighbours alike: raw.
+        let pixels = (0..16 * 16 * 2).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+        let data = round_trip(2, 16, 16, &pixels);
+        assert_eq!(data[0], 0x01);
+        assert_eq!(data.len(), 1 + pixels.len());
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for &bpp in &[1, 2, 4] {
+            // Partial tiles at the edges, with stripes, blocks and noise mixed in.
+            let (width, height) = (37, 21);
+            let pixels = (0..width * height).flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let value = match x / 10 {
+                    0 => (y / 3) as u32,
+                    1 => (x / 4 + y / 4) as u32 % 3,
+                    2 => (x * 31 + y * 17) as u32 * 0x010203,
+                    _ => 9
+                };
+                value.to_le_bytes()[..bpp].to_vec()
+            }).collect::<Vec<_>>();
+            round_trip(bpp, width as u16, height as u16, &pixels);
+            round_trip(bpp, 1, 1, &pixels[..bpp]);
+        }
+        assert!(round_trip(4, 0, 0, &[]).is_empty());
+    }
+
+    #[test]
+    fn encode_invalid() {
+        match encode(4, 2, 2, &[0; 15]) {
+            Err(Error::Unexpected("Hextile pixel data length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        // A 2x2 subrectangle in a 1x1 tile.
+        match decode(&mut &[0x08, 1, 0x00, 0x11][..], 1, 1, 1, protocol::DEFAULT_MAX_LENGTH) {
+            Err(Error::Unexpected("Hextile subrectangle")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match decode(&mut &[0x01, 0][..], 1, 2, 1, protocol::DEFAULT_MAX_LENGTH) {
+            Err(Error::Io(_)) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match decode(&mut &[][..], 4, 0xffff, 0xffff, protocol::DEFAULT_MAX_LENGTH) {
+            Err(Error::Unexpected("message length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
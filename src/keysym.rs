@@ -0,0 +1,99 @@
+/* X11 keysyms, as used by the KeyEvent message.
+
+   Latin-1 characters map directly onto keysyms with the same value. Every other
+   Unicode codepoint is encoded as 0x01000000 plus the codepoint; this is what
+   the X11 "Unicode keysym" convention (and every VNC server in existence)
+   expects. A handful of control characters have dedicated keysyms instead. */
+
+pub const XK_BACKSPACE: u32 = 0xff08;
+pub const XK_TAB:       u32 = 0xff09;
+pub const XK_LINEFEED:  u32 = 0xff0a;
+pub const XK_RETURN:    u32 = 0xff0d;
+pub const XK_ESCAPE:    u32 = 0xff1b;
+pub const XK_SHIFT_L:   u32 = 0xffe1;
+pub const XK_DELETE:    u32 = 0xffff;
+
+const UNICODE_OFFSET: u32 = 0x01000000;
+
+const CONTROL_KEYSYMS: [(char, u32); 6] = [
+    ('\x08', XK_BACKSPACE),
+    ('\t',   XK_TAB),
+    ('\r',   XK_RETURN),
+    ('\n',   XK_RETURN),
+    ('\x1b', XK_ESCAPE),
+    ('\x7f', XK_DELETE),
+];
+
+fn is_latin1_printable(codepoint: u32) -> bool {
+    (0x20..=0x7e).contains(&codepoint) || (0xa0..=0xff).contains(&codepoint)
+}
+
+pub fn char_to_keysym(c: char) -> u32 {
+    for &(control, keysym) in &CONTROL_KEYSYMS {
+        if control == c { return keysym }
+    }
+
+    let codepoint = c as u32;
+    if is_latin1_printable(codepoint) {
+        codepoint
+    } else {
+        UNICODE_OFFSET + codepoint
+    }
+}
+
+// Whether typing `c` takes Shift on a US keyboard. Servers that translate keysyms back
+// into keycodes often press the key unshifted otherwise.
+pub fn needs_shift(c: char) -> bool {
+    c.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(c)
+}
+
+pub fn keysym_to_char(keysym: u32) -> Option<char> {
+    if keysym == XK_LINEFEED { return Some('\n') }
+    for &(control, control_keysym) in &CONTROL_KEYSYMS {
+        if control_keysym == keysym { return Some(control) }
+    }
+
+    if is_latin1_printable(keysym) {
+        std::char::from_u32(keysym)
+    } else if (UNICODE_OFFSET + 0x100..=UNICODE_OFFSET + 0x10ffff).contains(&keysym) {
+        std::char::from_u32(keysym - UNICODE_OFFSET)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{char_to_keysym, keysym_to_char, needs_shift, XK_RETURN};
+
+    #[test]
+    fn test_ascii() {
+        assert_eq!(char_to_keysym('a'), 0x61);
+        assert_eq!(char_to_keysym('!'), 0x21);
+        assert_eq!(char_to_keysym('\n'), XK_RETURN);
+        assert_eq!(keysym_to_char(0x41), Some('A'));
+        assert_eq!(keysym_to_char(XK_RETURN), Some('\r'));
+    }
+
+    #[test]
+    fn test_shift() {
+        assert!(needs_shift('A') && needs_shift('!') && needs_shift('"'));
+        assert!(!needs_shift('a') && !needs_shift('1') && !needs_shift('\'') && !needs_shift('é'));
+    }
+
+    #[test]
+    fn test_latin1() {
+        assert_eq!(char_to_keysym('é'), 0xe9);
+        assert_eq!(char_to_keysym('§'), 0xa7);
+        assert_eq!(keysym_to_char(0xdf), Some('ß'));
+        assert_eq!(keysym_to_char(0x9f), None);
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(char_to_keysym('€'), 0x010020ac);
+        assert_eq!(char_to_keysym('ж'), 0x01000436);
+        assert_eq!(keysym_to_char(0x010020ac), Some('€'));
+        assert_eq!(keysym_to_char(0x0100_00e9), None);
+    }
+}
@@ -8,16 +8,39 @@ extern crate octavo;
 #[cfg(feature = "apple-auth")]
 extern crate crypto;
 
+// Logs a C2S or S2C message; with the trace-messages feature, as a short summary
+// at trace level rather than the whole message at debug level.
+#[cfg(feature = "trace-messages")]
+macro_rules! log_message {
+    ($prefix:expr, $message:expr) => (trace!(concat!($prefix, " {}"), $message.summary()))
+}
+#[cfg(not(feature = "trace-messages"))]
+macro_rules! log_message {
+    ($prefix:expr, $message:expr) => (debug!(concat!($prefix, " {:?}"), $message))
+}
+
+// cargo fuzz builds with --cfg fuzzing, and the message parsers are a fuzz target.
+#[cfg(fuzzing)]
+pub mod protocol;
+#[cfg(not(fuzzing))]
 mod protocol;
 mod zrle;
+mod hextile;
 mod security;
+mod dirty;
 
+pub mod keysym;
+pub mod encoding;
+pub mod tight;
 pub mod client;
 pub mod proxy;
+pub mod reconnect;
 
-pub use protocol::{PixelFormat, Colour, Encoding};
+pub use protocol::{Version, PixelFormat, Colour, Encoding, SecurityType};
 pub use client::Client;
-pub use proxy::Proxy;
+pub use proxy::{Proxy, ProxyBuilder};
+pub use dirty::DirtyTracker;
+pub use security::vnc_auth_response;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct Rect {
@@ -27,16 +50,118 @@ pub struct Rect {
     pub height: u16
 }
 
+impl Rect {
+    pub fn new(left: u16, top: u16, width: u16, height: u16) -> Rect {
+        Rect { left, top, width, height }
+    }
+
+    /* Edges are computed in u32, as left + width may not fit in a u16. */
+    fn right(&self) -> u32 { self.left as u32 + self.width as u32 }
+    fn bottom(&self) -> u32 { self.top as u32 + self.height as u32 }
+
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let left   = std::cmp::max(self.left, other.left);
+        let top    = std::cmp::max(self.top, other.top);
+        let right  = std::cmp::min(self.right(), other.right());
+        let bottom = std::cmp::min(self.bottom(), other.bottom());
+        if right <= left as u32 || bottom <= top as u32 {
+            None
+        } else {
+            Some(Rect::new(left, top, (right - left as u32) as u16, (bottom - top as u32) as u16))
+        }
+    }
+
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.left && (x as u32) < self.right() &&
+            y >= self.top && (y as u32) < self.bottom()
+    }
+
+    pub fn area(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Screen {
+    pub id:     u32,
+    pub x:      u16,
+    pub y:      u16,
+    pub width:  u16,
+    pub height: u16,
+    pub flags:  u32
+}
+
+// A pointer button mask, as sent in a PointerEvent; bit N is button N + 1.
+// Buttons 4 to 7 are the scroll wheel, which clicks them once per step.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct PointerButtons(pub u8);
+
+impl PointerButtons {
+    pub const NONE:        PointerButtons = PointerButtons(0);
+    pub const LEFT:        PointerButtons = PointerButtons(1 << 0);
+    pub const MIDDLE:      PointerButtons = PointerButtons(1 << 1);
+    pub const RIGHT:       PointerButtons = PointerButtons(1 << 2);
+    pub const WHEEL_UP:    PointerButtons = PointerButtons(1 << 3);
+    pub const WHEEL_DOWN:  PointerButtons = PointerButtons(1 << 4);
+    pub const WHEEL_LEFT:  PointerButtons = PointerButtons(1 << 5);
+    pub const WHEEL_RIGHT: PointerButtons = PointerButtons(1 << 6);
+    pub const BUTTON_8:    PointerButtons = PointerButtons(1 << 7);
+
+    pub fn bits(self) -> u8 { self.0 }
+
+    pub fn is_empty(self) -> bool { self.0 == 0 }
+
+    pub fn contains(self, other: PointerButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for PointerButtons {
+    type Output = PointerButtons;
+    fn bitor(self, other: PointerButtons) -> PointerButtons { PointerButtons(self.0 | other.0) }
+}
+
+impl std::ops::BitAnd for PointerButtons {
+    type Output = PointerButtons;
+    fn bitand(self, other: PointerButtons) -> PointerButtons { PointerButtons(self.0 & other.0) }
+}
+
+impl std::ops::Not for PointerButtons {
+    type Output = PointerButtons;
+    fn not(self) -> PointerButtons { PointerButtons(!self.0) }
+}
+
+impl std::ops::BitOrAssign for PointerButtons {
+    fn bitor_assign(&mut self, other: PointerButtons) { self.0 |= other.0 }
+}
+
+impl std::ops::BitAndAssign for PointerButtons {
+    fn bitand_assign(&mut self, other: PointerButtons) { self.0 &= other.0 }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(std::io::Error),
     Unexpected(&'static str),
     Server(String),
     AuthenticationUnavailable,
-    AuthenticationFailure(String),
+    // `reason` is empty before RFB 3.8, where the server does not send one.
+    AuthenticationFailure { reason: String, security_type: Option<SecurityType> },
+    Timeout,
     Disconnected
 }
 
+impl Error {
+    // The kind of the underlying I/O error, if any, e.g. to tell a reset connection
+    // apart from a broken pipe when deciding whether to reconnect.
+    pub fn io_kind(&self) -> Option<std::io::ErrorKind> {
+        match self {
+            Error::Io(ref inner) => Some(inner.kind()),
+            _ => None
+        }
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
         match self {
@@ -45,9 +170,22 @@ impl std::fmt::Display for Error {
                 write!(f, "unexpected {}", descr),
             Error::Server(ref descr) =>
                 write!(f, "server error: {}", descr),
-            Error::AuthenticationFailure(ref descr) =>
-                write!(f, "authentication failure: {}", descr),
-            _ => f.write_str(&self.to_string())
+            Error::AuthenticationUnavailable =>
+                f.write_str("no supported authentication method"),
+            Error::AuthenticationFailure { ref reason, security_type } => {
+                f.write_str("authentication failure")?;
+                if let Some(security_type) = security_type {
+                    write!(f, " ({:?})", security_type)?;
+                }
+                if !reason.is_empty() {
+                    write!(f, ": {}", reason)?;
+                }
+                Ok(())
+            },
+            Error::Timeout =>
+                f.write_str("timed out"),
+            Error::Disconnected =>
+                f.write_str("disconnected")
         }
     }
 }
@@ -66,3 +204,90 @@ impl From<std::io::Error> for Error {
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::{Rect, PointerButtons, Error, SecurityType};
+
+    #[test]
+    fn error_display() {
+        assert_eq!(Error::Disconnected.to_string(), "disconnected");
+        assert_eq!(Error::AuthenticationFailure {
+            reason: String::new(), security_type: None
+        }.to_string(), "authentication failure");
+        assert_eq!(Error::AuthenticationFailure {
+            reason: String::from("too many attempts"),
+            security_type: Some(SecurityType::VncAuthentication)
+        }.to_string(), "authentication failure (VncAuthentication): too many attempts");
+    }
+
+    #[test]
+    fn error_io_kind() {
+        use std::io;
+
+        for &kind in &[io::ErrorKind::ConnectionReset, io::ErrorKind::BrokenPipe,
+                       io::ErrorKind::UnexpectedEof] {
+            let error = Error::from(io::Error::new(kind, "test"));
+            assert_eq!(error.io_kind(), Some(kind));
+        }
+        assert_eq!(Error::Disconnected.io_kind(), None);
+        assert_eq!(Error::Timeout.io_kind(), None);
+        assert_eq!(Error::Unexpected("test").io_kind(), None);
+    }
+
+    #[test]
+    fn rect_intersect() {
+        let a = Rect::new(0, 0, 100, 100);
+        assert_eq!(a.intersect(&Rect::new(50, 60, 100, 100)), Some(Rect::new(50, 60, 50, 40)));
+        assert_eq!(a.intersect(&Rect::new(10, 10, 5, 5)), Some(Rect::new(10, 10, 5, 5)));
+        assert_eq!(a.intersect(&Rect::new(100, 0, 10, 10)), None);
+        assert_eq!(a.intersect(&Rect::new(10, 10, 0, 5)), None);
+
+        let b = Rect::new(65000, 65000, 65535, 65535);
+        assert_eq!(b.intersect(&Rect::new(65500, 0, 100, 65535)),
+                   Some(Rect::new(65500, 65000, 100, 535)));
+    }
+
+    #[test]
+    fn rect_contains() {
+        let a = Rect::new(10, 20, 30, 40);
+        assert!(a.contains(10, 20));
+        assert!(a.contains(39, 59));
+        assert!(!a.contains(40, 59));
+        assert!(!a.contains(9, 30));
+        assert!(Rect::new(65535, 65535, 65535, 65535).contains(65535, 65535));
+        assert!(!Rect::new(0, 0, 0, 0).contains(0, 0));
+    }
+
+    #[test]
+    fn pointer_buttons() {
+        use crate::protocol::{C2S, Message};
+
+        let buttons = PointerButtons::LEFT | PointerButtons::RIGHT | PointerButtons::WHEEL_DOWN;
+        assert_eq!(buttons.bits(), 0x15);
+        assert!(buttons.contains(PointerButtons::LEFT | PointerButtons::RIGHT));
+        assert!(!buttons.contains(PointerButtons::MIDDLE));
+        assert_eq!(buttons & !PointerButtons::RIGHT, PointerButtons(0x11));
+        assert!(PointerButtons::default().is_empty());
+
+        for &(buttons, byte) in &[(PointerButtons::NONE, 0x00), (PointerButtons::LEFT, 0x01),
+                                  (PointerButtons::MIDDLE, 0x02), (PointerButtons::RIGHT, 0x04),
+                                  (PointerButtons::WHEEL_UP, 0x08),
+                                  (PointerButtons::WHEEL_DOWN, 0x10),
+                                  (PointerButtons::WHEEL_LEFT, 0x20),
+                                  (PointerButtons::WHEEL_RIGHT, 0x40),
+                                  (PointerButtons::BUTTON_8, 0x80),
+                                  (PointerButtons::MIDDLE | PointerButtons::BUTTON_8, 0x82)] {
+            let mut data = Vec::new();
+            C2S::PointerEvent { button_mask: buttons.bits(), x_position: 1, y_position: 2 }
+                .write_to(&mut data).unwrap();
+            assert_eq!(data, [5, byte, 0, 1, 0, 2]);
+        }
+    }
+
+    #[test]
+    fn rect_area() {
+        assert_eq!(Rect::new(1, 2, 3, 4).area(), 12);
+        assert_eq!(Rect::new(0, 0, 65535, 65535).area(), 65535 * 65535);
+    }
+}
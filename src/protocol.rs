@@ -1,18 +1,52 @@
+use std::fmt;
+use std::convert::TryFrom;
 use std::io::{ErrorKind as IoErrorKind, Read, Write};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use crate::{Error, Result};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use crate::{Screen, Error, Result};
 
 pub trait Message {
     fn read_from<R: Read>(reader: &mut R) -> Result<Self> where Self: Sized;
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()>;
 }
 
+/* Lengths of variable-sized data come straight from the peer, so they are
+   checked against a limit before anything is allocated for them. */
+pub const DEFAULT_MAX_LENGTH: usize = 16 << 20;
+
+pub fn check_length(length: usize, max_length: usize) -> Result<usize> {
+    if length > max_length {
+        Err(Error::Unexpected("message length"))
+    } else {
+        Ok(length)
+    }
+}
+
+/* The size of `width` x `height` pixels; does not overflow even where usize is 32 bits. */
+pub fn pixel_data_length(width: u16, height: u16, bytes_per_pixel: usize,
+                         max_length: usize) -> Result<usize> {
+    let length = (width as usize).checked_mul(height as usize)
+        .and_then(|area| area.checked_mul(bytes_per_pixel))
+        .ok_or(Error::Unexpected("message length"))?;
+    check_length(length, max_length)
+}
+
+pub fn read_bytes<R: Read>(reader: &mut R, max_length: usize) -> Result<Vec<u8>> {
+    let length = check_length(reader.read_u32::<BigEndian>()? as usize, max_length)?;
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/* All strings in VNC are either ASCII or Latin-1, both of which
+   are embedded in Unicode. */
+pub fn read_string<R: Read>(reader: &mut R, max_length: usize) -> Result<String> {
+    let string = read_bytes(reader, max_length)?;
+    Ok(string.iter().map(|c| *c as char).collect())
+}
+
 impl Message for Vec<u8> {
     fn read_from<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
-        let length = reader.read_u32::<BigEndian>()?;
-        let mut buffer = vec![0; length as usize];
-        reader.read_exact(&mut buffer)?;
-        Ok(buffer)
+        read_bytes(reader, DEFAULT_MAX_LENGTH)
     }
 
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
@@ -23,25 +57,22 @@ impl Message for Vec<u8> {
     }
 }
 
-/* All strings in VNC are either ASCII or Latin-1, both of which
-   are embedded in Unicode. */
 impl Message for String {
     fn read_from<R: Read>(reader: &mut R) -> Result<String> {
-        let length = reader.read_u32::<BigEndian>()?;
-        let mut string = vec![0; length as usize];
-        reader.read_exact(&mut string)?;
-        Ok(string.iter().map(|c| *c as char).collect())
+        read_string(reader, DEFAULT_MAX_LENGTH)
     }
 
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
-        let length = self.len() as u32; // TODO: check?
-        writer.write_u32::<BigEndian>(length)?;
-        writer.write_all(&self.chars().map(|c| c as u8).collect::<Vec<u8>>())?;
+        // Characters outside of Latin-1 have no representation at all.
+        let latin1 = self.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+                                 .collect::<Vec<u8>>();
+        writer.write_u32::<BigEndian>(latin1.len() as u32)?;
+        writer.write_all(&latin1)?;
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Version {
     Rfb33,
     Rfb37,
@@ -80,6 +111,9 @@ pub enum SecurityType {
     None,
     VncAuthentication,
     // extensions
+    RsaAes,
+    RsaAesUnencrypted,
+    Tight,
     AppleRemoteDesktop,
 }
 
@@ -90,6 +124,9 @@ impl Message for SecurityType {
             0  => Ok(SecurityType::Invalid),
             1  => Ok(SecurityType::None),
             2  => Ok(SecurityType::VncAuthentication),
+            5  => Ok(SecurityType::RsaAes),
+            6  => Ok(SecurityType::RsaAesUnencrypted),
+            16 => Ok(SecurityType::Tight),
             30 => Ok(SecurityType::AppleRemoteDesktop),
             n  => Ok(SecurityType::Unknown(n))
         }
@@ -100,6 +137,9 @@ impl Message for SecurityType {
             SecurityType::Invalid => 0,
             SecurityType::None => 1,
             SecurityType::VncAuthentication => 2,
+            SecurityType::RsaAes => 5,
+            SecurityType::RsaAesUnencrypted => 6,
+            SecurityType::Tight => 16,
             SecurityType::AppleRemoteDesktop => 30,
             SecurityType::Unknown(n) => *n
         };
@@ -108,6 +148,28 @@ impl Message for SecurityType {
     }
 }
 
+/* In RFB 3.3, the server picks the security type and sends it as a U32. */
+#[derive(Debug)]
+pub struct SecurityTypeRfb33(pub SecurityType);
+
+impl Message for SecurityTypeRfb33 {
+    fn read_from<R: Read>(reader: &mut R) -> Result<SecurityTypeRfb33> {
+        let security_type = reader.read_u32::<BigEndian>()?;
+        if security_type > 0xff {
+            return Err(Error::Unexpected("security type"))
+        }
+        let security_type = SecurityType::read_from(&mut &[security_type as u8][..])?;
+        Ok(SecurityTypeRfb33(security_type))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut security_type = Vec::new();
+        self.0.write_to(&mut security_type)?;
+        writer.write_u32::<BigEndian>(security_type[0] as u32)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct SecurityTypes(pub Vec<SecurityType>);
 
@@ -131,6 +193,107 @@ impl Message for SecurityTypes {
     }
 }
 
+/* The Tight security type negotiates tunnels and authentication schemes with lists
+   of capabilities, each identified by a code, a vendor and a name. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TightCapability {
+    pub code:   i32,
+    pub vendor: [u8; 4],
+    pub name:   [u8; 8],
+}
+
+impl TightCapability {
+    pub const NO_TUNNEL: TightCapability =
+        TightCapability { code: 0, vendor: *b"TGHT", name: *b"NOTUNNEL" };
+    pub const AUTH_NONE: TightCapability =
+        TightCapability { code: 1, vendor: *b"STDV", name: *b"NOAUTH__" };
+    pub const AUTH_VNC:  TightCapability =
+        TightCapability { code: 2, vendor: *b"STDV", name: *b"VNCAUTH_" };
+}
+
+impl Message for TightCapability {
+    fn read_from<R: Read>(reader: &mut R) -> Result<TightCapability> {
+        let mut capability = TightCapability {
+            code:   reader.read_i32::<BigEndian>()?,
+            vendor: [0; 4],
+            name:   [0; 8],
+        };
+        reader.read_exact(&mut capability.vendor)?;
+        reader.read_exact(&mut capability.name)?;
+        Ok(capability)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_i32::<BigEndian>(self.code)?;
+        writer.write_all(&self.vendor)?;
+        writer.write_all(&self.name)?;
+        Ok(())
+    }
+}
+
+fn read_tight_capabilities<R: Read>(reader: &mut R, count: usize) ->
+        Result<Vec<TightCapability>> {
+    check_length(count.saturating_mul(16), DEFAULT_MAX_LENGTH)?;
+    let mut capabilities = Vec::with_capacity(count);
+    for _ in 0..count {
+        capabilities.push(TightCapability::read_from(reader)?)
+    }
+    Ok(capabilities)
+}
+
+/* The tunnel and authentication lists, prefixed with a U32 count. */
+#[derive(Debug)]
+pub struct TightCapabilities(pub Vec<TightCapability>);
+
+impl Message for TightCapabilities {
+    fn read_from<R: Read>(reader: &mut R) -> Result<TightCapabilities> {
+        let count = reader.read_u32::<BigEndian>()? as usize;
+        Ok(TightCapabilities(read_tight_capabilities(reader, count)?))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.0.len() as u32)?;
+        for capability in &self.0 {
+            capability.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
+/* Follows ServerInit if the Tight security type was used. */
+#[derive(Debug)]
+pub struct TightInteractionCapabilities {
+    pub server_messages: Vec<TightCapability>,
+    pub client_messages: Vec<TightCapability>,
+    pub encodings:       Vec<TightCapability>,
+}
+
+impl Message for TightInteractionCapabilities {
+    fn read_from<R: Read>(reader: &mut R) -> Result<TightInteractionCapabilities> {
+        let server_messages = reader.read_u16::<BigEndian>()? as usize;
+        let client_messages = reader.read_u16::<BigEndian>()? as usize;
+        let encodings       = reader.read_u16::<BigEndian>()? as usize;
+        reader.read_u16::<BigEndian>()?;
+        Ok(TightInteractionCapabilities {
+            server_messages: read_tight_capabilities(reader, server_messages)?,
+            client_messages: read_tight_capabilities(reader, client_messages)?,
+            encodings:       read_tight_capabilities(reader, encodings)?,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.server_messages.len() as u16)?;
+        writer.write_u16::<BigEndian>(self.client_messages.len() as u16)?;
+        writer.write_u16::<BigEndian>(self.encodings.len() as u16)?;
+        writer.write_u16::<BigEndian>(0)?;
+        for capability in self.server_messages.iter()
+                .chain(&self.client_messages).chain(&self.encodings) {
+            capability.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SecurityResult {
     Succeeded,
@@ -237,6 +400,39 @@ pub struct PixelFormat {
     pub blue_shift:     u8,
 }
 
+/* Pixel values here are numbers, i.e. already decoded from big_endian or
+   little_endian bytes, so they do not depend on the byte order. */
+impl PixelFormat {
+    /* The only sizes the spec allows; every decoder relies on a pixel being 1 to 4 bytes. */
+    pub fn check(&self) -> Result<()> {
+        match self.bits_per_pixel {
+            8 | 16 | 32 => Ok(()),
+            _ => Err(Error::Unexpected("bits per pixel"))
+        }
+    }
+
+    pub fn max_pixel_value(&self) -> u32 {
+        if self.bits_per_pixel >= 32 { u32::MAX } else { (1 << self.bits_per_pixel) - 1 }
+    }
+
+    pub fn extract_rgb(&self, pixel: u32) -> (u16, u16, u16) {
+        let channel = |shift: u8, max: u16|
+            (pixel.checked_shr(shift as u32).unwrap_or(0) & max as u32) as u16;
+        (channel(self.red_shift, self.red_max),
+         channel(self.green_shift, self.green_max),
+         channel(self.blue_shift, self.blue_max))
+    }
+
+    // Channel values above the respective maximum are clamped to it.
+    pub fn pack_rgb(&self, red: u16, green: u16, blue: u16) -> u32 {
+        let channel = |value: u16, shift: u8, max: u16|
+            (value.min(max) as u32).checked_shl(shift as u32).unwrap_or(0);
+        channel(red, self.red_shift, self.red_max) |
+            channel(green, self.green_shift, self.green_max) |
+            channel(blue, self.blue_shift, self.blue_max)
+    }
+}
+
 impl Message for PixelFormat {
     fn read_from<R: Read>(reader: &mut R) -> Result<PixelFormat> {
         let pixel_format = PixelFormat {
@@ -252,6 +448,7 @@ impl Message for PixelFormat {
             blue_shift:     reader.read_u8()?,
         };
         reader.read_exact(&mut [0u8; 3])?;
+        pixel_format.check()?;
         Ok(pixel_format)
     }
 
@@ -319,6 +516,29 @@ impl Message for CopyRect {
     }
 }
 
+impl Message for Screen {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Screen> {
+        Ok(Screen {
+            id:     reader.read_u32::<BigEndian>()?,
+            x:      reader.read_u16::<BigEndian>()?,
+            y:      reader.read_u16::<BigEndian>()?,
+            width:  reader.read_u16::<BigEndian>()?,
+            height: reader.read_u16::<BigEndian>()?,
+            flags:  reader.read_u32::<BigEndian>()?
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u32::<BigEndian>(self.id)?;
+        writer.write_u16::<BigEndian>(self.x)?;
+        writer.write_u16::<BigEndian>(self.y)?;
+        writer.write_u16::<BigEndian>(self.width)?;
+        writer.write_u16::<BigEndian>(self.height)?;
+        writer.write_u32::<BigEndian>(self.flags)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Encoding {
     Unknown(i32),
@@ -331,6 +551,30 @@ pub enum Encoding {
     Cursor,
     DesktopSize,
     // extensions
+    XCursor,
+    CursorPosition,
+    DesktopName,
+    QemuLedState,
+    ExtendedKeyEvent,
+    QemuPointerMotionChange,
+    Tight,
+    CompressionLevel(u8),
+    JpegQuality(u8),
+    Gii,
+    ExtendedDesktopSize,
+    ContinuousUpdates,
+}
+
+impl Encoding {
+    // Pseudo-encodings advertise capabilities rather than a way to encode pixels.
+    pub fn is_pseudo(&self) -> bool {
+        match self {
+            Encoding::Raw | Encoding::CopyRect | Encoding::Rre | Encoding::Hextile |
+            Encoding::Zrle | Encoding::Tight => false,
+            Encoding::Unknown(n) => *n < 0,
+            _ => true
+        }
+    }
 }
 
 impl Message for Encoding {
@@ -341,9 +585,21 @@ impl Message for Encoding {
             1    => Ok(Encoding::CopyRect),
             2    => Ok(Encoding::Rre),
             5    => Ok(Encoding::Hextile),
+            7    => Ok(Encoding::Tight),
             16   => Ok(Encoding::Zrle),
             -239 => Ok(Encoding::Cursor),
+            -240 => Ok(Encoding::XCursor),
             -223 => Ok(Encoding::DesktopSize),
+            -232 => Ok(Encoding::CursorPosition),
+            -257 => Ok(Encoding::QemuPointerMotionChange),
+            -258 => Ok(Encoding::ExtendedKeyEvent),
+            -261 => Ok(Encoding::QemuLedState),
+            -307 => Ok(Encoding::DesktopName),
+            -305 => Ok(Encoding::Gii),
+            -308 => Ok(Encoding::ExtendedDesktopSize),
+            -313 => Ok(Encoding::ContinuousUpdates),
+            -256..=-247 => Ok(Encoding::CompressionLevel((encoding + 256) as u8)),
+            -32..=-23   => Ok(Encoding::JpegQuality((encoding + 32) as u8)),
             n    => Ok(Encoding::Unknown(n))
         }
     }
@@ -354,9 +610,23 @@ impl Message for Encoding {
             Encoding::CopyRect => 1,
             Encoding::Rre => 2,
             Encoding::Hextile => 5,
+            Encoding::Tight => 7,
             Encoding::Zrle => 16,
             Encoding::Cursor => -239,
+            Encoding::XCursor => -240,
             Encoding::DesktopSize => -223,
+            Encoding::CursorPosition => -232,
+            Encoding::QemuPointerMotionChange => -257,
+            Encoding::ExtendedKeyEvent => -258,
+            Encoding::QemuLedState => -261,
+            Encoding::DesktopName => -307,
+            Encoding::Gii => -305,
+            Encoding::ExtendedDesktopSize => -308,
+            Encoding::ContinuousUpdates => -313,
+            Encoding::CompressionLevel(level) if *level <= 9 => -256 + *level as i32,
+            Encoding::JpegQuality(quality) if *quality <= 9 => -32 + *quality as i32,
+            Encoding::CompressionLevel(_) => return Err(Error::Unexpected("compression level")),
+            Encoding::JpegQuality(_) => return Err(Error::Unexpected("JPEG quality")),
             Encoding::Unknown(n) => *n
         };
         writer.write_i32::<BigEndian>(encoding)?;
@@ -364,6 +634,384 @@ impl Message for Encoding {
     }
 }
 
+/* The gii extension lets the sender pick the byte order of every message,
+   so all of its fields go through these helpers. */
+fn read_u16_gii<R: Read>(reader: &mut R, big_endian: bool) -> Result<u16> {
+    Ok(if big_endian { reader.read_u16::<BigEndian>()? }
+       else { reader.read_u16::<LittleEndian>()? })
+}
+
+fn read_u32_gii<R: Read>(reader: &mut R, big_endian: bool) -> Result<u32> {
+    Ok(if big_endian { reader.read_u32::<BigEndian>()? }
+       else { reader.read_u32::<LittleEndian>()? })
+}
+
+fn read_i32_gii<R: Read>(reader: &mut R, big_endian: bool) -> Result<i32> {
+    Ok(read_u32_gii(reader, big_endian)? as i32)
+}
+
+fn write_u16_gii<W: Write>(writer: &mut W, big_endian: bool, value: u16) -> Result<()> {
+    if big_endian { writer.write_u16::<BigEndian>(value)? }
+    else { writer.write_u16::<LittleEndian>(value)? }
+    Ok(())
+}
+
+fn write_u32_gii<W: Write>(writer: &mut W, big_endian: bool, value: u32) -> Result<()> {
+    if big_endian { writer.write_u32::<BigEndian>(value)? }
+    else { writer.write_u32::<LittleEndian>(value)? }
+    Ok(())
+}
+
+fn write_i32_gii<W: Write>(writer: &mut W, big_endian: bool, value: i32) -> Result<()> {
+    write_u32_gii(writer, big_endian, value as u32)
+}
+
+/* Fixed-size, NUL-padded Latin-1 strings. */
+fn read_fixed_string<R: Read>(reader: &mut R, length: usize) -> Result<String> {
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer.iter().take_while(|c| **c != 0).map(|c| *c as char).collect())
+}
+
+fn write_fixed_string<W: Write>(writer: &mut W, length: usize, string: &str) -> Result<()> {
+    let mut buffer = vec![0; length];
+    for (i, c) in string.chars().take(length - 1).enumerate() {
+        buffer[i] = c as u8;
+    }
+    writer.write_all(&buffer)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GiiValuator {
+    pub index:        u32,
+    pub long_name:    String,
+    pub short_name:   String,
+    pub range_min:    i32,
+    pub range_center: i32,
+    pub range_max:    i32,
+    pub si_unit:      u32,
+    pub si_add:       i32,
+    pub si_mul:       i32,
+    pub si_div:       i32,
+    pub si_shift:     i32,
+}
+
+impl GiiValuator {
+    fn read_from<R: Read>(reader: &mut R, big_endian: bool) -> Result<GiiValuator> {
+        Ok(GiiValuator {
+            index:        read_u32_gii(reader, big_endian)?,
+            long_name:    read_fixed_string(reader, 75)?,
+            short_name:   read_fixed_string(reader, 5)?,
+            range_min:    read_i32_gii(reader, big_endian)?,
+            range_center: read_i32_gii(reader, big_endian)?,
+            range_max:    read_i32_gii(reader, big_endian)?,
+            si_unit:      read_u32_gii(reader, big_endian)?,
+            si_add:       read_i32_gii(reader, big_endian)?,
+            si_mul:       read_i32_gii(reader, big_endian)?,
+            si_div:       read_i32_gii(reader, big_endian)?,
+            si_shift:     read_i32_gii(reader, big_endian)?,
+        })
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, big_endian: bool) -> Result<()> {
+        write_u32_gii(writer, big_endian, self.index)?;
+        write_fixed_string(writer, 75, &self.long_name)?;
+        write_fixed_string(writer, 5, &self.short_name)?;
+        write_i32_gii(writer, big_endian, self.range_min)?;
+        write_i32_gii(writer, big_endian, self.range_center)?;
+        write_i32_gii(writer, big_endian, self.range_max)?;
+        write_u32_gii(writer, big_endian, self.si_unit)?;
+        write_i32_gii(writer, big_endian, self.si_add)?;
+        write_i32_gii(writer, big_endian, self.si_mul)?;
+        write_i32_gii(writer, big_endian, self.si_div)?;
+        write_i32_gii(writer, big_endian, self.si_shift)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GiiEvent {
+    KeyPressed {
+        device_origin: u32,
+        modifiers:     u32,
+        symbol:        u32,
+        label:         u32,
+        button:        u32,
+    },
+    KeyReleased {
+        device_origin: u32,
+        modifiers:     u32,
+        symbol:        u32,
+        label:         u32,
+        button:        u32,
+    },
+    KeyRepeat {
+        device_origin: u32,
+        modifiers:     u32,
+        symbol:        u32,
+        label:         u32,
+        button:        u32,
+    },
+    PointerRelative {
+        device_origin: u32,
+        x:             i32,
+        y:             i32,
+        z:             i32,
+        wheel:         i32,
+    },
+    PointerAbsolute {
+        device_origin: u32,
+        x:             i32,
+        y:             i32,
+        z:             i32,
+        wheel:         i32,
+    },
+    ButtonPressed {
+        device_origin: u32,
+        button:        u32,
+    },
+    ButtonReleased {
+        device_origin: u32,
+        button:        u32,
+    },
+    ValuatorRelative {
+        device_origin: u32,
+        first:         u32,
+        values:        Vec<i32>,
+    },
+    ValuatorAbsolute {
+        device_origin: u32,
+        first:         u32,
+        values:        Vec<i32>,
+    },
+}
+
+impl GiiEvent {
+    fn read_from<R: Read>(reader: &mut R, big_endian: bool) -> Result<GiiEvent> {
+        let event_size = reader.read_u8()? as usize;
+        let event_type = reader.read_u8()?;
+        if event_size < 4 {
+            return Err(Error::Unexpected("gii event size"))
+        }
+        let mut event = vec![0; event_size - 2];
+        reader.read_exact(&mut event)?;
+
+        let mut reader = &event[2..]; // padding
+        let device_origin = read_u32_gii(&mut reader, big_endian)?;
+        let result = match event_type {
+            5..=7 => {
+                let modifiers = read_u32_gii(&mut reader, big_endian)?;
+                let symbol    = read_u32_gii(&mut reader, big_endian)?;
+                let label     = read_u32_gii(&mut reader, big_endian)?;
+                let button    = read_u32_gii(&mut reader, big_endian)?;
+                match event_type {
+                    5 => GiiEvent::KeyPressed { device_origin, modifiers, symbol, label, button },
+                    6 => GiiEvent::KeyReleased { device_origin, modifiers, symbol, label, button },
+                    _ => GiiEvent::KeyRepeat { device_origin, modifiers, symbol, label, button },
+                }
+            },
+            8 | 9 => {
+                let x     = read_i32_gii(&mut reader, big_endian)?;
+                let y     = read_i32_gii(&mut reader, big_endian)?;
+                let z     = read_i32_gii(&mut reader, big_endian)?;
+                let wheel = read_i32_gii(&mut reader, big_endian)?;
+                match event_type {
+                    8 => GiiEvent::PointerRelative { device_origin, x, y, z, wheel },
+                    _ => GiiEvent::PointerAbsolute { device_origin, x, y, z, wheel },
+                }
+            },
+            10 | 11 => {
+                let button = read_u32_gii(&mut reader, big_endian)?;
+                match event_type {
+                    10 => GiiEvent::ButtonPressed { device_origin, button },
+                    _  => GiiEvent::ButtonReleased { device_origin, button },
+                }
+            },
+            12 | 13 => {
+                let first = read_u32_gii(&mut reader, big_endian)?;
+                let count = read_u32_gii(&mut reader, big_endian)?;
+                let mut values = Vec::new();
+                for _ in 0..count {
+                    values.push(read_i32_gii(&mut reader, big_endian)?);
+                }
+                match event_type {
+                    12 => GiiEvent::ValuatorRelative { device_origin, first, values },
+                    _  => GiiEvent::ValuatorAbsolute { device_origin, first, values },
+                }
+            },
+            _ => return Err(Error::Unexpected("gii event type"))
+        };
+        Ok(result)
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, big_endian: bool) -> Result<()> {
+        let mut event = Vec::new();
+        let event_type = match self {
+            GiiEvent::KeyPressed { device_origin, modifiers, symbol, label, button } |
+            GiiEvent::KeyReleased { device_origin, modifiers, symbol, label, button } |
+            GiiEvent::KeyRepeat { device_origin, modifiers, symbol, label, button } => {
+                write_u32_gii(&mut event, big_endian, *device_origin)?;
+                write_u32_gii(&mut event, big_endian, *modifiers)?;
+                write_u32_gii(&mut event, big_endian, *symbol)?;
+                write_u32_gii(&mut event, big_endian, *label)?;
+                write_u32_gii(&mut event, big_endian, *button)?;
+                match self {
+                    GiiEvent::KeyPressed { .. } => 5,
+                    GiiEvent::KeyReleased { .. } => 6,
+                    _ => 7
+                }
+            },
+            GiiEvent::PointerRelative { device_origin, x, y, z, wheel } |
+            GiiEvent::PointerAbsolute { device_origin, x, y, z, wheel } => {
+                write_u32_gii(&mut event, big_endian, *device_origin)?;
+                write_i32_gii(&mut event, big_endian, *x)?;
+                write_i32_gii(&mut event, big_endian, *y)?;
+                write_i32_gii(&mut event, big_endian, *z)?;
+                write_i32_gii(&mut event, big_endian, *wheel)?;
+                match self {
+                    GiiEvent::PointerRelative { .. } => 8,
+                    _ => 9
+                }
+            },
+            GiiEvent::ButtonPressed { device_origin, button } |
+            GiiEvent::ButtonReleased { device_origin, button } => {
+                write_u32_gii(&mut event, big_endian, *device_origin)?;
+                write_u32_gii(&mut event, big_endian, *button)?;
+                match self {
+                    GiiEvent::ButtonPressed { .. } => 10,
+                    _ => 11
+                }
+            },
+            GiiEvent::ValuatorRelative { device_origin, first, ref values } |
+            GiiEvent::ValuatorAbsolute { device_origin, first, ref values } => {
+                write_u32_gii(&mut event, big_endian, *device_origin)?;
+                write_u32_gii(&mut event, big_endian, *first)?;
+                write_u32_gii(&mut event, big_endian, values.len() as u32)?;
+                for value in values {
+                    write_i32_gii(&mut event, big_endian, *value)?;
+                }
+                match self {
+                    GiiEvent::ValuatorRelative { .. } => 12,
+                    _ => 13
+                }
+            }
+        };
+        if event.len() > 0xff - 4 {
+            return Err(Error::Unexpected("gii event size"))
+        }
+        writer.write_u8(4 + event.len() as u8)?;
+        writer.write_u8(event_type)?;
+        writer.write_all(&[0u8; 2])?;
+        writer.write_all(&event)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GiiMessage {
+    InjectEvents(Vec<GiiEvent>),
+    Version(u16),
+    CreateDevice {
+        name:          String,
+        vendor_id:     u32,
+        product_id:    u32,
+        event_mask:    u32,
+        num_registers: u32,
+        num_buttons:   u32,
+        valuators:     Vec<GiiValuator>,
+    },
+    DestroyDevice {
+        device_origin: u32,
+    },
+}
+
+impl GiiMessage {
+    fn read_from<R: Read>(reader: &mut R) -> Result<(bool, GiiMessage)> {
+        let endian_and_sub_type = reader.read_u8()?;
+        let big_endian = endian_and_sub_type & 0x80 != 0;
+        let length = read_u16_gii(reader, big_endian)?;
+        let mut payload = vec![0; length as usize];
+        reader.read_exact(&mut payload)?;
+
+        let mut reader = &payload[..];
+        let message = match endian_and_sub_type & 0x7f {
+            0 => {
+                let mut events = Vec::new();
+                while !reader.is_empty() {
+                    events.push(GiiEvent::read_from(&mut reader, big_endian)?);
+                }
+                GiiMessage::InjectEvents(events)
+            },
+            1 => GiiMessage::Version(read_u16_gii(&mut reader, big_endian)?),
+            2 => {
+                let name          = read_fixed_string(&mut reader, 31)?;
+                reader.read_exact(&mut [0u8; 1])?;
+                let vendor_id     = read_u32_gii(&mut reader, big_endian)?;
+                let product_id    = read_u32_gii(&mut reader, big_endian)?;
+                let event_mask    = read_u32_gii(&mut reader, big_endian)?;
+                let num_registers = read_u32_gii(&mut reader, big_endian)?;
+                let num_valuators = read_u32_gii(&mut reader, big_endian)?;
+                let num_buttons   = read_u32_gii(&mut reader, big_endian)?;
+                let mut valuators = Vec::new();
+                for _ in 0..num_valuators {
+                    valuators.push(GiiValuator::read_from(&mut reader, big_endian)?);
+                }
+                GiiMessage::CreateDevice {
+                    name, vendor_id, product_id, event_mask, num_registers, num_buttons, valuators
+                }
+            },
+            3 => GiiMessage::DestroyDevice {
+                device_origin: read_u32_gii(&mut reader, big_endian)?
+            },
+            _ => return Err(Error::Unexpected("gii message sub-type"))
+        };
+        Ok((big_endian, message))
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, big_endian: bool) -> Result<()> {
+        let mut payload = Vec::new();
+        let sub_type = match self {
+            GiiMessage::InjectEvents(ref events) => {
+                for event in events {
+                    event.write_to(&mut payload, big_endian)?;
+                }
+                0
+            },
+            GiiMessage::Version(version) => {
+                write_u16_gii(&mut payload, big_endian, *version)?;
+                1
+            },
+            GiiMessage::CreateDevice { ref name, vendor_id, product_id, event_mask,
+                                       num_registers, num_buttons, ref valuators } => {
+                write_fixed_string(&mut payload, 31, name)?;
+                payload.write_all(&[0u8; 1])?;
+                write_u32_gii(&mut payload, big_endian, *vendor_id)?;
+                write_u32_gii(&mut payload, big_endian, *product_id)?;
+                write_u32_gii(&mut payload, big_endian, *event_mask)?;
+                write_u32_gii(&mut payload, big_endian, *num_registers)?;
+                write_u32_gii(&mut payload, big_endian, valuators.len() as u32)?;
+                write_u32_gii(&mut payload, big_endian, *num_buttons)?;
+                for valuator in valuators {
+                    valuator.write_to(&mut payload, big_endian)?;
+                }
+                2
+            },
+            GiiMessage::DestroyDevice { device_origin } => {
+                write_u32_gii(&mut payload, big_endian, *device_origin)?;
+                3
+            }
+        };
+        if payload.len() > 0xffff {
+            return Err(Error::Unexpected("gii message length"))
+        }
+        writer.write_u8(if big_endian { 0x80 } else { 0 } | sub_type)?;
+        write_u16_gii(writer, big_endian, payload.len() as u16)?;
+        writer.write_all(&payload)?;
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum C2S {
     // core spec
@@ -387,10 +1035,31 @@ pub enum C2S {
     },
     CutText(String),
     // extensions
+    SetDesktopSize {
+        width:       u16,
+        height:      u16,
+        screens:     Vec<Screen>,
+    },
+    Gii {
+        big_endian:  bool,
+        message:     GiiMessage,
+    },
+    /* QEMU extended key event; `keycode` is an XT scancode, with 0xe0-prefixed
+       ones as 0x80 | code */
+    ExtendedKeyEvent {
+        down:        bool,
+        key:         u32,
+        keycode:     u32,
+    },
+    /* A message we can frame but not interpret, e.g. TightVNC file transfer */
+    Unsupported {
+        message_type: u8,
+        payload:      Vec<u8>,
+    },
 }
 
-impl Message for C2S {
-    fn read_from<R: Read>(reader: &mut R) -> Result<C2S> {
+impl C2S {
+    pub fn read_limited<R: Read>(reader: &mut R, max_length: usize) -> Result<C2S> {
         let message_type =
             match reader.read_u8() {
                 Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof =>
@@ -435,11 +1104,69 @@ impl Message for C2S {
             },
             6 => {
                 reader.read_exact(&mut [0u8; 3])?;
-                Ok(C2S::CutText(String::read_from(reader)?))
+                Ok(C2S::CutText(read_string(reader, max_length)?))
+            },
+            251 => {
+                reader.read_exact(&mut [0u8; 1])?;
+                let width  = reader.read_u16::<BigEndian>()?;
+                let height = reader.read_u16::<BigEndian>()?;
+                let count  = reader.read_u8()?;
+                reader.read_exact(&mut [0u8; 1])?;
+                let mut screens = Vec::new();
+                for _ in 0..count {
+                    screens.push(Screen::read_from(reader)?);
+                }
+                Ok(C2S::SetDesktopSize { width, height, screens })
+            },
+            253 => {
+                let (big_endian, message) = GiiMessage::read_from(reader)?;
+                Ok(C2S::Gii { big_endian, message })
+            },
+            255 => {
+                match reader.read_u8()? {
+                    0 => Ok(C2S::ExtendedKeyEvent {
+                        down:    reader.read_u16::<BigEndian>()? != 0,
+                        key:     reader.read_u32::<BigEndian>()?,
+                        keycode: reader.read_u32::<BigEndian>()?,
+                    }),
+                    _ => Err(Error::Unexpected("QEMU client message type"))
+                }
+            },
+            130..=136 => {
+                // TightVNC file transfer; every message is a 4-byte header whose last
+                // field is the length of the trailing data, except that the download
+                // and upload requests have a 4-byte file position in between, and
+                // an empty upload data message carries a 4-byte modification time.
+                let mut payload = vec![0u8; 3];
+                reader.read_exact(&mut payload)?;
+                let length = match message_type {
+                    133 => {
+                        let mut compressed_size = [0u8; 2];
+                        reader.read_exact(&mut compressed_size)?;
+                        payload.extend_from_slice(&compressed_size);
+                        let real_size = (&payload[1..3]).read_u16::<BigEndian>()?;
+                        let compressed_size = (&compressed_size[..]).read_u16::<BigEndian>()?;
+                        if real_size == 0 && compressed_size == 0 { 4 }
+                        else { compressed_size as usize }
+                    },
+                    131 | 132 => (&payload[1..3]).read_u16::<BigEndian>()? as usize + 4,
+                    _ => (&payload[1..3]).read_u16::<BigEndian>()? as usize
+                };
+                let start = payload.len();
+                payload.resize(start + length, 0);
+                reader.read_exact(&mut payload[start..])?;
+                Ok(C2S::Unsupported { message_type, payload })
             },
             _ => Err(Error::Unexpected("client to server message type"))
         }
     }
+}
+
+impl Message for C2S {
+    fn read_from<R: Read>(reader: &mut R) -> Result<C2S> {
+        C2S::read_limited(reader, DEFAULT_MAX_LENGTH)
+    }
+
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         match self {
             C2S::SetPixelFormat(ref pixel_format) => {
@@ -476,7 +1203,38 @@ impl Message for C2S {
                 writer.write_u16::<BigEndian>(*y_position)?;
             },
             C2S::CutText(ref text) => {
+                writer.write_u8(6)?;
+                writer.write_all(&[0u8; 3])?;
                 String::write_to(text, writer)?;
+            },
+            C2S::SetDesktopSize { width, height, ref screens } => {
+                if screens.len() > 255 {
+                    return Err(Error::Unexpected("screen count"))
+                }
+                writer.write_u8(251)?;
+                writer.write_all(&[0u8; 1])?;
+                writer.write_u16::<BigEndian>(*width)?;
+                writer.write_u16::<BigEndian>(*height)?;
+                writer.write_u8(screens.len() as u8)?;
+                writer.write_all(&[0u8; 1])?;
+                for screen in screens {
+                    screen.write_to(writer)?;
+                }
+            },
+            C2S::Gii { big_endian, ref message } => {
+                writer.write_u8(253)?;
+                message.write_to(writer, *big_endian)?;
+            },
+            C2S::ExtendedKeyEvent { down, key, keycode } => {
+                writer.write_u8(255)?;
+                writer.write_u8(0)?;
+                writer.write_u16::<BigEndian>(if *down { 1 } else { 0 })?;
+                writer.write_u32::<BigEndian>(*key)?;
+                writer.write_u32::<BigEndian>(*keycode)?;
+            },
+            C2S::Unsupported { message_type, ref payload } => {
+                writer.write_u8(*message_type)?;
+                writer.write_all(payload)?;
             }
         }
         Ok(())
@@ -513,7 +1271,7 @@ impl Message for Rectangle {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Colour {
     pub red:   u16,
     pub green: u16,
@@ -551,10 +1309,11 @@ pub enum S2C {
     Bell,
     CutText(String),
     // extensions
+    EndOfContinuousUpdates,
 }
 
-impl Message for S2C {
-    fn read_from<R: Read>(reader: &mut R) -> Result<S2C> {
+impl S2C {
+    pub fn read_limited<R: Read>(reader: &mut R, max_length: usize) -> Result<S2C> {
         let message_type =
             match reader.read_u8() {
                 Err(ref e) if e.kind() == IoErrorKind::UnexpectedEof =>
@@ -583,11 +1342,20 @@ impl Message for S2C {
             },
             3 => {
                 reader.read_exact(&mut [0u8; 3])?;
-                Ok(S2C::CutText(String::read_from(reader)?))
+                Ok(S2C::CutText(read_string(reader, max_length)?))
+            },
+            150 => {
+                Ok(S2C::EndOfContinuousUpdates)
             },
             _ => Err(Error::Unexpected("server to client message type"))
         }
     }
+}
+
+impl Message for S2C {
+    fn read_from<R: Read>(reader: &mut R) -> Result<S2C> {
+        S2C::read_limited(reader, DEFAULT_MAX_LENGTH)
+    }
 
     fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
         match self {
@@ -597,9 +1365,12 @@ impl Message for S2C {
                 writer.write_u16::<BigEndian>(*count)?;
             },
             S2C::SetColourMapEntries { first_colour, ref colours } => {
+                let count = u16::try_from(colours.len())
+                    .map_err(|_| Error::Unexpected("colour count"))?;
                 writer.write_u8(1)?;
                 writer.write_all(&[0u8; 1])?;
                 writer.write_u16::<BigEndian>(*first_colour)?;
+                writer.write_u16::<BigEndian>(count)?;
                 for colour in colours {
                     Colour::write_to(colour, writer)?;
                 }
@@ -611,8 +1382,434 @@ impl Message for S2C {
                 writer.write_u8(3)?;
                 writer.write_all(&[0u8; 3])?;
                 String::write_to(text, writer)?;
+            },
+            S2C::EndOfContinuousUpdates => {
+                writer.write_u8(150)?;
             }
         }
         Ok(())
     }
 }
+
+/* A one-line rendering of a message for logging. Unlike Debug, it is bounded in length
+   and never includes pixel data, clipboard contents or other payloads. */
+pub struct Summary<'a, M>(&'a M);
+
+#[cfg_attr(not(feature = "trace-messages"), allow(dead_code))]
+impl C2S {
+    pub fn summary(&self) -> Summary<'_, C2S> { Summary(self) }
+}
+
+#[cfg_attr(not(feature = "trace-messages"), allow(dead_code))]
+impl S2C {
+    pub fn summary(&self) -> Summary<'_, S2C> { Summary(self) }
+}
+
+impl<'a> fmt::Display for Summary<'a, C2S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            C2S::SetEncodings(ref encodings) =>
+                write!(f, "SetEncodings({} encodings)", encodings.len()),
+            C2S::CutText(ref text) =>
+                write!(f, "CutText({} bytes)", text.len()),
+            C2S::SetDesktopSize { width, height, ref screens } =>
+                write!(f, "SetDesktopSize({}x{}, {} screens)", width, height, screens.len()),
+            C2S::Gii { .. } =>
+                write!(f, "Gii"),
+            C2S::Unsupported { message_type, ref payload } =>
+                write!(f, "Unsupported(type {}, {} bytes)", message_type, payload.len()),
+            // The rest are small and fixed-size.
+            message => write!(f, "{:?}", message)
+        }
+    }
+}
+
+impl<'a> fmt::Display for Summary<'a, S2C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            S2C::FramebufferUpdate { count } =>
+                write!(f, "FramebufferUpdate({} rects)", count),
+            S2C::SetColourMapEntries { first_colour, ref colours } =>
+                write!(f, "SetColourMapEntries(first {}, {} colours)",
+                       first_colour, colours.len()),
+            S2C::Bell =>
+                write!(f, "Bell"),
+            S2C::CutText(ref text) =>
+                write!(f, "CutText({} bytes)", text.len()),
+            S2C::EndOfContinuousUpdates =>
+                write!(f, "EndOfContinuousUpdates"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn tight_capabilities() {
+        let mut buffer = vec![0, 0, 0, 2];
+        buffer.extend_from_slice(b"\x00\x00\x00\x01STDVNOAUTH__");
+        buffer.extend_from_slice(b"\x00\x00\x00\x02STDVVNCAUTH_");
+        let capabilities = TightCapabilities::read_from(&mut &buffer[..]).unwrap();
+        assert_eq!(capabilities.0, [TightCapability::AUTH_NONE, TightCapability::AUTH_VNC]);
+        let mut written = Vec::new();
+        capabilities.write_to(&mut written).unwrap();
+        assert_eq!(written, buffer);
+
+        let mut buffer = vec![0, 1, 0, 0, 0, 2, 0, 0];
+        buffer.extend_from_slice(b"\x00\x00\x00\xfcTGHTFTS_LSDT");
+        buffer.extend_from_slice(b"\xff\xff\xff\x09TGHTCOMPRLVL");
+        buffer.extend_from_slice(b"\xff\xff\xff\xe0TGHTJPEGQLVL");
+        let capabilities = TightInteractionCapabilities::read_from(&mut &buffer[..]).unwrap();
+        assert_eq!(capabilities.server_messages.len(), 1);
+        assert_eq!(capabilities.server_messages[0].code, 252);
+        assert!(capabilities.client_messages.is_empty());
+        assert_eq!(capabilities.encodings.iter().map(|c| c.code).collect::<Vec<_>>(),
+                   [-247, -32]);
+        let mut written = Vec::new();
+        capabilities.write_to(&mut written).unwrap();
+        assert_eq!(written, buffer);
+    }
+
+    #[test]
+    fn tight_pseudo_encodings() {
+        for level in 0..10 {
+            for &(encoding, number) in &[(Encoding::CompressionLevel(level), -256 + level as i32),
+                                         (Encoding::JpegQuality(level), -32 + level as i32)] {
+                let mut buffer = Vec::new();
+                encoding.write_to(&mut buffer).unwrap();
+                assert_eq!(buffer, number.to_be_bytes());
+                assert_eq!(Encoding::read_from(&mut &buffer[..]).unwrap(), encoding);
+            }
+        }
+
+        assert!(Encoding::CompressionLevel(10).write_to(&mut Vec::new()).is_err());
+        assert!(Encoding::JpegQuality(10).write_to(&mut Vec::new()).is_err());
+        assert_eq!(Encoding::read_from(&mut &(-246i32).to_be_bytes()[..]).unwrap(),
+                   Encoding::Unknown(-246));
+    }
+
+    #[test]
+    fn cursor_position_encoding() {
+        let mut buffer = Vec::new();
+        Encoding::CursorPosition.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, (-232i32).to_be_bytes());
+        assert_eq!(Encoding::read_from(&mut &buffer[..]).unwrap(), Encoding::CursorPosition);
+        assert!(Encoding::CursorPosition.is_pseudo());
+    }
+
+    #[test]
+    fn qemu_pointer_motion_change_encoding() {
+        let mut buffer = Vec::new();
+        Encoding::QemuPointerMotionChange.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, (-257i32).to_be_bytes());
+        assert_eq!(Encoding::read_from(&mut &buffer[..]).unwrap(),
+                   Encoding::QemuPointerMotionChange);
+        assert!(Encoding::QemuPointerMotionChange.is_pseudo());
+    }
+
+    #[test]
+    fn pixel_format_rgb() {
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16, depth: 16, big_endian: true, true_colour: true,
+            red_max: 31, green_max: 63, blue_max: 31,
+            red_shift: 11, green_shift: 5, blue_shift: 0
+        };
+        assert_eq!(rgb565.max_pixel_value(), 0xffff);
+        assert_eq!(rgb565.pack_rgb(31, 0, 31), 0xf81f);
+        assert_eq!(rgb565.pack_rgb(100, 100, 100), 0xffff);
+        assert_eq!(rgb565.extract_rgb(0xf81f), (31, 0, 31));
+        assert_eq!(rgb565.extract_rgb(rgb565.pack_rgb(1, 2, 3)), (1, 2, 3));
+        // The byte order does not change the packed value.
+        let rgb565_le = PixelFormat { big_endian: false, ..rgb565 };
+        assert_eq!(rgb565_le.pack_rgb(1, 2, 3), rgb565.pack_rgb(1, 2, 3));
+
+        let rgb888 = PixelFormat {
+            bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+            red_max: 255, green_max: 255, blue_max: 255,
+            red_shift: 16, green_shift: 8, blue_shift: 0
+        };
+        assert_eq!(rgb888.max_pixel_value(), 0xffffffff);
+        assert_eq!(rgb888.pack_rgb(0x12, 0x34, 0x56), 0x123456);
+        assert_eq!(rgb888.extract_rgb(0xff123456), (0x12, 0x34, 0x56));
+    }
+
+    #[test]
+    fn extended_key_event() {
+        let message = C2S::ExtendedKeyEvent { down: true, key: 0xff0d, keycode: 0x9c };
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, [255, 0, 0, 1, 0, 0, 0xff, 0x0d, 0, 0, 0, 0x9c]);
+        match C2S::read_from(&mut &buffer[..]).unwrap() {
+            C2S::ExtendedKeyEvent { down: true, key: 0xff0d, keycode: 0x9c } => (),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn bell() {
+        let mut buffer = Vec::new();
+        S2C::Bell.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, [2]);
+        // Nothing after the type byte belongs to the bell.
+        buffer.push(2);
+        let mut reader = &buffer[..];
+        match S2C::read_from(&mut reader).unwrap() {
+            S2C::Bell => (),
+            message => panic!("unexpected {:?}", message)
+        }
+        assert_eq!(reader, [2]);
+    }
+
+    #[test]
+    fn gii_create_device() {
+        let message = C2S::Gii {
+            big_endian: true,
+            message: GiiMessage::CreateDevice {
+                name:          String::from("tablet"),
+                vendor_id:     0x1234,
+                product_id:    0x5678,
+                event_mask:    0x3f00,
+                num_registers: 0,
+                num_buttons:   3,
+                valuators:     vec![GiiValuator {
+                    index:        0,
+                    long_name:    String::from("Pressure"),
+                    short_name:   String::from("p"),
+                    range_min:    0,
+                    range_center: 512,
+                    range_max:    1023,
+                    si_unit:      0,
+                    si_add:       0,
+                    si_mul:       1,
+                    si_div:       1,
+                    si_shift:     0,
+                }]
+            }
+        };
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        assert_eq!(&buffer[..4], &[253, 0x82, 0, 56 + 116]);
+        assert_eq!(buffer.len(), 4 + 56 + 116);
+        assert_eq!(&buffer[4 + 31..4 + 36], &[0, 0, 0, 0x12, 0x34]);
+
+        match C2S::read_from(&mut &buffer[..]).unwrap() {
+            C2S::Gii { big_endian: true, message: read_message } =>
+                match message {
+                    C2S::Gii { message, .. } => assert_eq!(read_message, message),
+                    _ => unreachable!()
+                },
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn gii_inject_events() {
+        let events = vec![
+            GiiEvent::ButtonPressed { device_origin: 7, button: 2 },
+            GiiEvent::ValuatorAbsolute { device_origin: 7, first: 0, values: vec![100, -1] },
+        ];
+        let message = C2S::Gii {
+            big_endian: false,
+            message:    GiiMessage::InjectEvents(events.clone())
+        };
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        assert_eq!(buffer, [
+            253, 0x00, 36, 0,
+            12, 10, 0, 0, 7, 0, 0, 0, 2, 0, 0, 0,
+            24, 13, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0,
+            100, 0, 0, 0, 0xff, 0xff, 0xff, 0xff,
+        ]);
+
+        match C2S::read_from(&mut &buffer[..]).unwrap() {
+            C2S::Gii { big_endian: false, message: GiiMessage::InjectEvents(read_events) } =>
+                assert_eq!(read_events, events),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn gii_oversized() {
+        let event = GiiEvent::ValuatorRelative { device_origin: 1, first: 0, values: vec![0; 60] };
+        match (C2S::Gii { big_endian: false, message: GiiMessage::InjectEvents(vec![event]) })
+                .write_to(&mut Vec::new()) {
+            Err(Error::Unexpected("gii event size")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        let event = GiiEvent::ButtonPressed { device_origin: 1, button: 1 };
+        match (C2S::Gii { big_endian: false, message: GiiMessage::InjectEvents(vec![event; 6000]) })
+                .write_to(&mut Vec::new()) {
+            Err(Error::Unexpected("gii message length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn set_desktop_size() {
+        let screens = vec![
+            Screen { id: 1, x: 0,    y: 0, width: 1920, height: 1080, flags: 0 },
+            Screen { id: 2, x: 1920, y: 0, width: 1280, height: 1024, flags: 0 },
+        ];
+        let message = C2S::SetDesktopSize { width: 3200, height: 1080, screens: screens.clone() };
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        assert_eq!(&buffer[..8], &[251, 0, 0x0c, 0x80, 0x04, 0x38, 2, 0]);
+        assert_eq!(&buffer[24..40], &[0, 0, 0, 2, 0x07, 0x80, 0, 0,
+                                      0x05, 0x00, 0x04, 0x00, 0, 0, 0, 0]);
+        assert_eq!(buffer.len(), 8 + 2 * 16);
+
+        match C2S::read_from(&mut &buffer[..]).unwrap() {
+            C2S::SetDesktopSize { width: 3200, height: 1080, screens: read_screens } =>
+                assert_eq!(read_screens, screens),
+            message => panic!("unexpected {:?}", message)
+        }
+
+        let screen = Screen { id: 0, x: 0, y: 0, width: 1, height: 1, flags: 0 };
+        let message = C2S::SetDesktopSize { width: 1, height: 1, screens: vec![screen; 256] };
+        let mut buffer = Vec::new();
+        match message.write_to(&mut buffer) {
+            Err(Error::Unexpected("screen count")) => assert!(buffer.is_empty()),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn tight_file_transfer() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(&[130, 0, 0, 4]);
+        buffer.extend_from_slice(b"/tmp");
+        buffer.extend_from_slice(&[131, 0, 0, 1, 0, 0, 0, 0, b'a']);
+        buffer.extend_from_slice(&[133, 0, 0, 0, 0, 0, 0x5e, 0x0b, 0xe1, 0x00]);
+        C2S::KeyEvent { down: true, key: 0x61 }.write_to(&mut buffer).unwrap();
+
+        let mut reader = &buffer[..];
+        for &(expected_type, expected_length) in &[(130, 7), (131, 8), (133, 9)] {
+            match C2S::read_from(&mut reader).unwrap() {
+                C2S::Unsupported { message_type, ref payload } => {
+                    assert_eq!(message_type, expected_type);
+                    assert_eq!(payload.len(), expected_length);
+                },
+                message => panic!("unexpected {:?}", message)
+            }
+        }
+        match C2S::read_from(&mut reader).unwrap() {
+            C2S::KeyEvent { down: true, key: 0x61 } => (),
+            message => panic!("unexpected {:?}", message)
+        }
+    }
+
+    #[test]
+    fn length_limit() {
+        let mut buffer = Vec::new();
+        S2C::CutText(String::from("clipboard")).write_to(&mut buffer).unwrap();
+        assert!(S2C::read_limited(&mut &buffer[..], 9).is_ok());
+        match S2C::read_limited(&mut &buffer[..], 8) {
+            Err(Error::Unexpected("message length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+
+        let huge = [0xff, 0xff, 0xff, 0xff];
+        match Vec::<u8>::read_from(&mut &huge[..]) {
+            Err(Error::Unexpected("message length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn set_colour_map_entries() {
+        let colours = vec![Colour { red: 0xffff, green: 0x8000, blue: 0 }; 2];
+        let message = S2C::SetColourMapEntries { first_colour: 16, colours: colours.clone() };
+        let mut buffer = Vec::new();
+        message.write_to(&mut buffer).unwrap();
+        assert_eq!(&buffer[..6], &[1, 0, 0, 16, 0, 2]);
+        assert_eq!(buffer.len(), 6 + 2 * 6);
+        match S2C::read_from(&mut &buffer[..]).unwrap() {
+            S2C::SetColourMapEntries { first_colour: 16, colours: read_colours } =>
+                assert_eq!(read_colours, colours),
+            message => panic!("unexpected {:?}", message)
+        }
+
+        let colours = vec![Colour { red: 0, green: 0, blue: 0 }; 65536];
+        let mut buffer = Vec::new();
+        match (S2C::SetColourMapEntries { first_colour: 0, colours }).write_to(&mut buffer) {
+            Err(Error::Unexpected("colour count")) => assert!(buffer.is_empty()),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+
+    #[test]
+    fn summary_is_bounded() {
+        let colours = vec![Colour { red: 0, green: 0, blue: 0 }; 65535];
+        let text = "x".repeat(1 << 20);
+        let summaries = vec![
+            S2C::FramebufferUpdate { count: 3 }.summary().to_string(),
+            S2C::SetColourMapEntries { first_colour: 0, colours }.summary().to_string(),
+            S2C::CutText(text.clone()).summary().to_string(),
+            C2S::CutText(text).summary().to_string(),
+            C2S::SetEncodings(vec![Encoding::Raw; 1000]).summary().to_string(),
+            C2S::Unsupported { message_type: 252, payload: vec![0; 1 << 20] }
+                .summary().to_string(),
+        ];
+        assert_eq!(summaries[0], "FramebufferUpdate(3 rects)");
+        assert_eq!(summaries[2], "CutText(1048576 bytes)");
+        for summary in &summaries {
+            assert!(summary.len() < 80, "{:?} is too long", summary);
+        }
+    }
+
+    // A xorshift generator, so that failures are reproducible without extra dependencies.
+    pub fn random_bytes(seed: &mut u64, max_length: usize) -> Vec<u8> {
+        let mut next = || {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *seed
+        };
+        let length = next() as usize % (max_length + 1);
+        // Plenty of zeroes, so that lengths and counts are small often enough to get
+        // past them to whatever follows.
+        (0..length).map(|_| match next() % 3 { 0 => 0, _ => next() as u8 }).collect()
+    }
+
+    #[test]
+    fn arbitrary_messages() {
+        let mut seed = 1;
+        for _ in 0..20000 {
+            let mut data = random_bytes(&mut seed, 48);
+            // Every valid message type of either direction, and a few invalid ones.
+            if let Some(message_type) = data.first_mut() {
+                *message_type = [0, 1, 2, 3, 4, 5, 6, 7, 130, 133, 150, 250, 251, 252, 253, 254,
+                                 255][*message_type as usize % 17];
+            }
+            let _ = S2C::read_from(&mut &data[..]);
+            let _ = C2S::read_from(&mut &data[..]);
+            let _ = ServerInit::read_from(&mut &data[..]);
+            let _ = TightCapabilities::read_from(&mut &data[..]);
+        }
+    }
+
+    #[test]
+    fn pixel_data_length_overflow() {
+        assert_eq!(pixel_data_length(100, 100, 4, DEFAULT_MAX_LENGTH).unwrap(), 40000);
+        assert!(pixel_data_length(0xffff, 0xffff, 4, usize::MAX).is_ok() ||
+                cfg!(target_pointer_width = "32"));
+        assert!(pixel_data_length(0xffff, 0xffff, 4, DEFAULT_MAX_LENGTH).is_err());
+        assert!(pixel_data_length(0xffff, 0xffff, usize::MAX, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn pixel_format_bits_per_pixel() {
+        let mut buffer = Vec::new();
+        PixelFormat { bits_per_pixel: 24, depth: 24, big_endian: false, true_colour: true,
+                      red_max: 255, green_max: 255, blue_max: 255,
+                      red_shift: 16, green_shift: 8, blue_shift: 0 }
+            .write_to(&mut buffer).unwrap();
+        match PixelFormat::read_from(&mut &buffer[..]) {
+            Err(Error::Unexpected("bits per pixel")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
@@ -1,24 +1,117 @@
 use std::io::{Read, Write, Cursor};
 use std::net::{TcpStream, Shutdown};
 use std::thread;
+use std::sync::{Arc, Mutex};
 use crate::{Error, Result};
 use crate::protocol::{self, Message};
+use crate::client::{self, AuthMethod, AuthChoice};
 
 pub struct Proxy {
     c2s_thread: thread::JoinHandle<Result<()>>,
     s2c_thread: thread::JoinHandle<Result<()>>,
+    // Every message in either direction is written whole while holding these locks,
+    // so that injected messages never end up in the middle of forwarded ones.
+    server_stream: Arc<Mutex<TcpStream>>,
+    client_stream: Arc<Mutex<TcpStream>>,
+    name:   String,
+    size:   (u16, u16),
+    format: protocol::PixelFormat,
+}
+
+fn send_message<M: Message>(stream: &Mutex<TcpStream>, message: &M) -> Result<()> {
+    let mut buffer = Vec::new();
+    message.write_to(&mut buffer)?;
+    stream.lock().unwrap().write_all(&buffer)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyBuilder {
+    client_version: Option<protocol::Version>,
+}
+
+impl ProxyBuilder {
+    pub fn new() -> ProxyBuilder { ProxyBuilder::default() }
+
+    // Presents `version` to the client instead of forwarding the server's, e.g. 3.3 to
+    // clients that predate 3.7. Both handshakes are then performed separately: the proxy
+    // authenticates with the server itself and offers the client no authentication at all,
+    // so anyone who can connect to the proxy gets in with the proxy's credentials.
+    // Only use this where access to the proxy is otherwise restricted.
+    pub fn force_client_version(mut self, version: protocol::Version) -> ProxyBuilder {
+        self.client_version = Some(version);
+        self
+    }
+
+    // `auth` is only consulted when the handshake is terminated at the proxy.
+    pub fn connect<Auth>(self, mut server_stream: TcpStream, mut client_stream: TcpStream,
+                         auth: Auth) -> Result<Proxy>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        let server_init = match self.client_version {
+            None => Proxy::forward_handshake(&mut server_stream, &mut client_stream)?,
+            Some(version) =>
+                Proxy::terminate_handshake(&mut server_stream, &mut client_stream,
+                                           version, auth)?
+        };
+        Ok(Proxy::start(server_stream, client_stream, server_init))
+    }
 }
 
 impl Proxy {
-    pub fn from_tcp_streams(mut server_stream: TcpStream, mut client_stream: TcpStream) ->
+    pub fn from_tcp_streams(server_stream: TcpStream, client_stream: TcpStream) ->
             Result<Proxy> {
-        let server_version = protocol::Version::read_from(&mut server_stream)?;
+        ProxyBuilder::new().connect(server_stream, client_stream, |_| None)
+    }
+
+    fn terminate_handshake<Auth>(server_stream: &mut TcpStream, client_stream: &mut TcpStream,
+                                 version: protocol::Version, auth: Auth) ->
+            Result<protocol::ServerInit>
+            where Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+        debug!("c<-! {:?}", version);
+        version.write_to(client_stream)?;
+        let client_version = protocol::Version::read_from(client_stream)?;
+        debug!("c->! {:?}", client_version);
+        if client_version > version {
+            return Err(Error::Unexpected("client protocol version"))
+        }
+
+        match client_version {
+            protocol::Version::Rfb33 =>
+                protocol::SecurityTypeRfb33(protocol::SecurityType::None)
+                    .write_to(client_stream)?,
+            _ => {
+                protocol::SecurityTypes(vec![protocol::SecurityType::None])
+                    .write_to(client_stream)?;
+                let security_type = protocol::SecurityType::read_from(client_stream)?;
+                debug!("c->! SecurityType::{:?}", security_type);
+                if security_type != protocol::SecurityType::None {
+                    return Err(Error::Unexpected("security type"))
+                }
+                if client_version == protocol::Version::Rfb38 {
+                    protocol::SecurityResult::Succeeded.write_to(client_stream)?;
+                }
+            }
+        }
+
+        let client_init = protocol::ClientInit::read_from(client_stream)?;
+        debug!("c->! {:?}", client_init);
+
+        let server_init = client::Builder::new().shared(client_init.shared)
+            .handshake(server_stream, auth)?;
+        debug!("c<-s {:?}", server_init);
+        server_init.write_to(client_stream)?;
+        Ok(server_init)
+    }
+
+    fn forward_handshake(server_stream: &mut TcpStream, client_stream: &mut TcpStream) ->
+            Result<protocol::ServerInit> {
+        let server_version = protocol::Version::read_from(server_stream)?;
         debug!("c<-s {:?}", server_version);
-        protocol::Version::write_to(&server_version, &mut client_stream)?;
+        protocol::Version::write_to(&server_version, client_stream)?;
 
-        let client_version = protocol::Version::read_from(&mut client_stream)?;
+        let client_version = protocol::Version::read_from(client_stream)?;
         debug!("c->s {:?}", client_version);
-        protocol::Version::write_to(&client_version, &mut server_stream)?;
+        protocol::Version::write_to(&client_version, server_stream)?;
 
         fn security_type_supported(security_type: &protocol::SecurityType) -> bool {
             match security_type {
@@ -32,7 +125,8 @@ impl Proxy {
 
         let security_types = match client_version {
             protocol::Version::Rfb33 => {
-                let mut security_type = protocol::SecurityType::read_from(&mut server_stream)?;
+                let protocol::SecurityTypeRfb33(mut security_type) =
+                    protocol::SecurityTypeRfb33::read_from(server_stream)?;
                 debug!("!<-s SecurityType::{:?}", security_type);
 
                 // Filter out security types we can't handle
@@ -41,7 +135,7 @@ impl Proxy {
                 }
 
                 debug!("c<-! SecurityType::{:?}", security_type);
-                protocol::SecurityType::write_to(&security_type, &mut client_stream)?;
+                protocol::SecurityTypeRfb33(security_type).write_to(client_stream)?;
 
                 if security_type == protocol::SecurityType::Invalid {
                     vec![]
@@ -51,23 +145,23 @@ impl Proxy {
             },
             _ => {
                 let mut security_types =
-                    protocol::SecurityTypes::read_from(&mut server_stream)?;
+                    protocol::SecurityTypes::read_from(server_stream)?;
                 debug!("!<-s {:?}", security_types);
 
                 // Filter out security types we can't handle
                 security_types.0.retain(security_type_supported);
 
                 debug!("c<-! {:?}", security_types);
-                protocol::SecurityTypes::write_to(&security_types, &mut client_stream)?;
+                protocol::SecurityTypes::write_to(&security_types, client_stream)?;
 
                 security_types.0
             }
         };
 
         if security_types.is_empty() {
-            let reason = String::read_from(&mut server_stream)?;
+            let reason = String::read_from(server_stream)?;
             debug!("c<-s {:?}", reason);
-            String::write_to(&reason, &mut client_stream)?;
+            String::write_to(&reason, client_stream)?;
 
             return Err(Error::Server(reason))
         }
@@ -76,9 +170,9 @@ impl Proxy {
             protocol::Version::Rfb33 => security_types[0],
             _ => {
                 let used_security_type =
-                    protocol::SecurityType::read_from(&mut client_stream)?;
+                    protocol::SecurityType::read_from(client_stream)?;
                 debug!("c->s SecurityType::{:?}", used_security_type);
-                protocol::SecurityType::write_to(&used_security_type, &mut server_stream)?;
+                protocol::SecurityType::write_to(&used_security_type, server_stream)?;
 
                 used_security_type
             }
@@ -93,38 +187,49 @@ impl Proxy {
         }
 
         if !skip_security_result {
-            let security_result = protocol::SecurityResult::read_from(&mut server_stream)?;
+            let security_result = protocol::SecurityResult::read_from(server_stream)?;
             debug!("c<-s SecurityResult::{:?}", security_result);
-            protocol::SecurityResult::write_to(&security_result, &mut client_stream)?;
+            protocol::SecurityResult::write_to(&security_result, client_stream)?;
 
             if security_result == protocol::SecurityResult::Failed {
                 match client_version {
                     protocol::Version::Rfb33 | protocol::Version::Rfb37 =>
-                        return Err(Error::AuthenticationFailure(String::from(""))),
+                        return Err(Error::AuthenticationFailure {
+                            reason:        String::new(),
+                            security_type: Some(used_security_type)
+                        }),
                     protocol::Version::Rfb38 => {
-                        let reason = String::read_from(&mut server_stream)?;
+                        let reason = String::read_from(server_stream)?;
                         debug!("c<-s {:?}", reason);
-                        String::write_to(&reason, &mut client_stream)?;
-                        return Err(Error::AuthenticationFailure(reason))
+                        String::write_to(&reason, client_stream)?;
+                        return Err(Error::AuthenticationFailure {
+                            reason,
+                            security_type: Some(used_security_type)
+                        })
                     }
                 }
             }
         }
 
-        let client_init = protocol::ClientInit::read_from(&mut client_stream)?;
+        let client_init = protocol::ClientInit::read_from(client_stream)?;
         debug!("c->s {:?}", client_init);
-        protocol::ClientInit::write_to(&client_init, &mut server_stream)?;
+        protocol::ClientInit::write_to(&client_init, server_stream)?;
 
-        let server_init = protocol::ServerInit::read_from(&mut server_stream)?;
+        let server_init = protocol::ServerInit::read_from(server_stream)?;
         debug!("c<-s {:?}", server_init);
-        protocol::ServerInit::write_to(&server_init, &mut client_stream)?;
+        protocol::ServerInit::write_to(&server_init, client_stream)?;
+        Ok(server_init)
+    }
 
-        let (mut c2s_server_stream, mut c2s_client_stream) =
-            (server_stream.try_clone().unwrap(), client_stream.try_clone().unwrap());
-        let (mut s2c_server_stream, mut s2c_client_stream) =
-            (server_stream.try_clone().unwrap(), client_stream.try_clone().unwrap());
+    fn start(server_stream: TcpStream, client_stream: TcpStream,
+             server_init: protocol::ServerInit) -> Proxy {
 
-        fn forward_c2s(server_stream: &mut TcpStream, client_stream: &mut TcpStream) ->
+        let c2s_server_stream = Arc::new(Mutex::new(server_stream.try_clone().unwrap()));
+        let mut c2s_client_stream = client_stream.try_clone().unwrap();
+        let mut s2c_server_stream = server_stream.try_clone().unwrap();
+        let s2c_client_stream = Arc::new(Mutex::new(client_stream.try_clone().unwrap()));
+
+        fn forward_c2s(server_stream: &Mutex<TcpStream>, client_stream: &mut TcpStream) ->
                 Result<()> {
             fn encoding_supported(encoding: &protocol::Encoding) -> bool {
                 match encoding {
@@ -157,13 +262,17 @@ impl Proxy {
                         // how to handle it properly, so defer for now.
                         panic!("proxying SetPixelFormat is not implemented!")
                     },
-                    ref message => debug!("c->s {:?}", message)
+                    protocol::C2S::Unsupported { message_type, .. } => {
+                        warn!("message type {} is not supported", message_type);
+                        continue
+                    },
+                    ref message => log_message!("c->s", message)
                 }
-                protocol::C2S::write_to(&message, server_stream)?
+                send_message(server_stream, &message)?
             }
         }
 
-        fn forward_s2c(server_stream: &mut TcpStream, client_stream: &mut TcpStream,
+        fn forward_s2c(server_stream: &mut TcpStream, client_stream: &Mutex<TcpStream>,
                        format: protocol::PixelFormat) ->
                 Result<()> {
 
@@ -171,7 +280,7 @@ impl Proxy {
                 let mut buffer_stream = Cursor::new(Vec::new());
 
                 let message = protocol::S2C::read_from(server_stream)?;
-                debug!("c<-s {:?}", message);
+                log_message!("c<-s", message);
                 protocol::S2C::write_to(&message, &mut buffer_stream)?;
 
                 match message {
@@ -183,9 +292,10 @@ impl Proxy {
 
                             match rectangle.encoding {
                                 protocol::Encoding::Raw => {
-                                    let mut pixels = vec![0; (rectangle.width as usize) *
-                                                             (rectangle.height as usize) *
-                                                             (format.bits_per_pixel as usize / 8)];
+                                    let mut pixels = vec![0; protocol::pixel_data_length(
+                                                             rectangle.width, rectangle.height,
+                                                             format.bits_per_pixel as usize / 8,
+                                                             protocol::DEFAULT_MAX_LENGTH)?];
                                     server_stream.read_exact(&mut pixels)?;
                                     debug!("c<-s ...raw pixels");
                                     buffer_stream.write_all(&pixels)?;
@@ -203,13 +313,16 @@ impl Proxy {
                                     Vec::<u8>::write_to(&zrle, &mut buffer_stream)?;
                                 }
                                 protocol::Encoding::Cursor => {
-                                    let mut pixels    = vec![0; (rectangle.width as usize) *
-                                                                (rectangle.height as usize) *
-                                                                (format.bits_per_pixel as usize / 8)];
+                                    let mut pixels    = vec![0; protocol::pixel_data_length(
+                                                                rectangle.width, rectangle.height,
+                                                                format.bits_per_pixel as usize / 8,
+                                                                protocol::DEFAULT_MAX_LENGTH)?];
                                     server_stream.read_exact(&mut pixels)?;
                                     buffer_stream.write_all(&pixels)?;
-                                    let mut mask_bits = vec![0; ((rectangle.width as usize + 7) / 8) *
-                                                                (rectangle.height as usize)];
+                                    let mut mask_bits = vec![0; protocol::check_length(
+                                                                ((rectangle.width as usize + 7) / 8) *
+                                                                (rectangle.height as usize),
+                                                                protocol::DEFAULT_MAX_LENGTH)?];
                                     server_stream.read_exact(&mut mask_bits)?;
                                     buffer_stream.write_all(&mask_bits)?;
                                 },
@@ -222,25 +335,67 @@ impl Proxy {
                 }
 
                 let buffer = buffer_stream.into_inner();
-                client_stream.write_all(&buffer)?;
+                client_stream.lock().unwrap().write_all(&buffer)?;
             }
         }
 
-        Ok(Proxy {
+        let server_stream = c2s_server_stream.clone();
+        let client_stream = s2c_client_stream.clone();
+        let name = server_init.name.clone();
+        let size = (server_init.framebuffer_width, server_init.framebuffer_height);
+        let format = server_init.pixel_format;
+        Proxy {
             c2s_thread: thread::spawn(move || {
-                let result = forward_c2s(&mut c2s_server_stream, &mut c2s_client_stream);
-                let _ = c2s_server_stream.shutdown(Shutdown::Both);
+                let result = forward_c2s(&c2s_server_stream, &mut c2s_client_stream);
+                let _ = c2s_server_stream.lock().unwrap().shutdown(Shutdown::Both);
                 let _ = c2s_client_stream.shutdown(Shutdown::Both);
                 result
             }),
             s2c_thread: thread::spawn(move || {
-                let result = forward_s2c(&mut s2c_server_stream, &mut s2c_client_stream,
+                let result = forward_s2c(&mut s2c_server_stream, &s2c_client_stream,
                                          server_init.pixel_format);
                 let _ = s2c_server_stream.shutdown(Shutdown::Both);
-                let _ = s2c_client_stream.shutdown(Shutdown::Both);
+                let _ = s2c_client_stream.lock().unwrap().shutdown(Shutdown::Both);
                 result
-            })
-        })
+            }),
+            server_stream,
+            client_stream,
+            name,
+            size,
+            format,
+        }
+    }
+
+    // As sent by the server in ServerInit; the proxy does not track later changes.
+    pub fn name(&self) -> &str { &self.name }
+    pub fn size(&self) -> (u16, u16) { self.size }
+    pub fn format(&self) -> protocol::PixelFormat { self.format }
+
+    // These may be called from any thread while the proxy is running; the injected
+    // message is sent between two messages forwarded in the same direction.
+    pub fn inject_key(&self, down: bool, key: u32) -> Result<()> {
+        let key_event = protocol::C2S::KeyEvent { down, key };
+        log_message!("!->s", key_event);
+        send_message(&self.server_stream, &key_event)
+    }
+
+    pub fn inject_pointer(&self, buttons: u8, x: u16, y: u16) -> Result<()> {
+        let pointer_event = protocol::C2S::PointerEvent {
+            button_mask: buttons, x_position: x, y_position: y
+        };
+        log_message!("!->s", pointer_event);
+        send_message(&self.server_stream, &pointer_event)
+    }
+
+    pub fn notify_client_bell(&self) -> Result<()> {
+        debug!("c<-! Bell");
+        send_message(&self.client_stream, &protocol::S2C::Bell)
+    }
+
+    pub fn set_client_clipboard(&self, text: &str) -> Result<()> {
+        let cut_text = protocol::S2C::CutText(String::from(text));
+        log_message!("c<-!", cut_text);
+        send_message(&self.client_stream, &cut_text)
     }
 
     pub fn join(self) -> Result<()> {
@@ -252,3 +407,206 @@ impl Proxy {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+    use crate::protocol::{self, Message};
+    use crate::client::{Builder, AuthChoice, Event};
+    use crate::security::vnc_auth_response;
+    use super::{Proxy, ProxyBuilder};
+
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (stream, listener.accept().unwrap().0)
+    }
+
+    fn server_handshake(stream: &mut TcpStream) {
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        stream.read_exact(&mut [0; 12]).unwrap();
+        protocol::SecurityTypes(vec![protocol::SecurityType::None]).write_to(stream).unwrap();
+        protocol::SecurityType::read_from(stream).unwrap();
+        protocol::SecurityResult::Succeeded.write_to(stream).unwrap();
+        protocol::ClientInit::read_from(stream).unwrap();
+        protocol::ServerInit {
+            framebuffer_width:  100,
+            framebuffer_height: 100,
+            pixel_format:       protocol::PixelFormat {
+                bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+                red_max: 255, green_max: 255, blue_max: 255,
+                red_shift: 16, green_shift: 8, blue_shift: 0
+            },
+            name:               String::from("test")
+        }.write_to(stream).unwrap();
+    }
+
+    fn client_handshake(stream: &mut TcpStream) {
+        stream.read_exact(&mut [0; 12]).unwrap();
+        stream.write_all(b"RFB 003.008\n").unwrap();
+        protocol::SecurityTypes::read_from(stream).unwrap();
+        protocol::SecurityType::None.write_to(stream).unwrap();
+        protocol::SecurityResult::read_from(stream).unwrap();
+        protocol::ClientInit { shared: true }.write_to(stream).unwrap();
+        protocol::ServerInit::read_from(stream).unwrap();
+    }
+
+    fn start<F, T>(server: F) -> (Proxy, TcpStream, thread::JoinHandle<T>)
+            where F: FnOnce(TcpStream) -> T + Send + 'static, T: Send + 'static {
+        let (mut server_stream, proxy_server) = stream_pair();
+        let (mut client_stream, proxy_client) = stream_pair();
+        let server = thread::spawn(move || {
+            server_handshake(&mut server_stream);
+            server(server_stream)
+        });
+        let client = thread::spawn(move || {
+            client_handshake(&mut client_stream);
+            client_stream
+        });
+        let proxy = Proxy::from_tcp_streams(proxy_server, proxy_client).unwrap();
+        (proxy, client.join().unwrap(), server)
+    }
+
+    // RFB 3.3 with no authentication has no SecurityResult; forwarding one anyway
+    // would shift everything after it by four bytes.
+    #[test]
+    fn rfb33_no_security_result() {
+        let (mut server_stream, proxy_server) = stream_pair();
+        let (client_stream, proxy_client) = stream_pair();
+        let server = thread::spawn(move || {
+            server_stream.write_all(b"RFB 003.003\n").unwrap();
+            server_stream.read_exact(&mut [0; 12]).unwrap();
+            protocol::SecurityTypeRfb33(protocol::SecurityType::None)
+                .write_to(&mut server_stream).unwrap();
+            protocol::ClientInit::read_from(&mut server_stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       protocol::PixelFormat {
+                    bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+                    red_max: 255, green_max: 255, blue_max: 255,
+                    red_shift: 16, green_shift: 8, blue_shift: 0
+                },
+                name:               String::from("rfb33")
+            }.write_to(&mut server_stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut server_stream).unwrap();
+            server_stream
+        });
+        let client = thread::spawn(move || {
+            Builder::new().max_version(protocol::Version::Rfb33)
+                .connect(client_stream, |_| Some(AuthChoice::None)).unwrap()
+        });
+        let proxy = Proxy::from_tcp_streams(proxy_server, proxy_client).unwrap();
+        assert_eq!(proxy.name(), "rfb33");
+        let mut client = client.join().unwrap();
+        assert_eq!(client.name(), "rfb33");
+        server.join().unwrap();
+        while !client.has_pending() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match client.poll_event() {
+            Some(Event::Bell) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+    }
+
+    #[test]
+    fn force_client_version() {
+        let (mut server_stream, proxy_server) = stream_pair();
+        let (client_stream, proxy_client) = stream_pair();
+        let server = thread::spawn(move || {
+            server_stream.write_all(b"RFB 003.008\n").unwrap();
+            server_stream.read_exact(&mut [0; 12]).unwrap();
+            protocol::SecurityTypes(vec![protocol::SecurityType::VncAuthentication])
+                .write_to(&mut server_stream).unwrap();
+            protocol::SecurityType::read_from(&mut server_stream).unwrap();
+            let challenge = [7; 16];
+            server_stream.write_all(&challenge).unwrap();
+            let mut response = [0; 16];
+            server_stream.read_exact(&mut response).unwrap();
+            assert_eq!(response, vnc_auth_response(&challenge, b"secret\0\0"));
+            protocol::SecurityResult::Succeeded.write_to(&mut server_stream).unwrap();
+            let client_init = protocol::ClientInit::read_from(&mut server_stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       protocol::PixelFormat {
+                    bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+                    red_max: 255, green_max: 255, blue_max: 255,
+                    red_shift: 16, green_shift: 8, blue_shift: 0
+                },
+                name:               String::from("rfb38")
+            }.write_to(&mut server_stream).unwrap();
+            protocol::S2C::Bell.write_to(&mut server_stream).unwrap();
+            (server_stream, client_init.shared)
+        });
+        let client = thread::spawn(move || {
+            Builder::new().max_version(protocol::Version::Rfb33).shared(false)
+                .connect(client_stream, |_| Some(AuthChoice::None)).unwrap()
+        });
+        let proxy = ProxyBuilder::new().force_client_version(protocol::Version::Rfb33)
+            .connect(proxy_server, proxy_client, |methods| {
+                assert_eq!(methods.len(), 1);
+                Some(AuthChoice::Password(*b"secret\0\0"))
+            }).unwrap();
+        assert_eq!(proxy.name(), "rfb38");
+        let mut client = client.join().unwrap();
+        assert_eq!(client.name(), "rfb38");
+        let (_server_stream, shared) = server.join().unwrap();
+        assert!(!shared);
+        while !client.has_pending() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match client.poll_event() {
+            Some(Event::Bell) => (),
+            event => panic!("unexpected {:?}", event)
+        }
+    }
+
+    #[test]
+    fn server_info() {
+        let (proxy, _client, server) = start(|_| ());
+        server.join().unwrap();
+        assert_eq!(proxy.name(), "test");
+        assert_eq!(proxy.size(), (100, 100));
+        assert_eq!(proxy.format().bits_per_pixel, 32);
+    }
+
+    #[test]
+    fn inject_events() {
+        let (proxy, mut client, server) = start(|mut server| {
+            (0..3).map(|_| protocol::C2S::read_from(&mut server).unwrap())
+                  .collect::<Vec<_>>()
+        });
+        proxy.inject_key(true, 0x61).unwrap();
+        proxy.inject_pointer(1, 10, 20).unwrap();
+        protocol::C2S::KeyEvent { down: false, key: 0x61 }.write_to(&mut client).unwrap();
+
+        // The injected events are written before the client's is even sent to the proxy.
+        match &server.join().unwrap()[..] {
+            [protocol::C2S::KeyEvent { down: true, key: 0x61 },
+             protocol::C2S::PointerEvent { button_mask: 1, x_position: 10, y_position: 20 },
+             protocol::C2S::KeyEvent { down: false, key: 0x61 }] => (),
+            messages => panic!("unexpected {:?}", messages)
+        }
+    }
+
+    #[test]
+    fn notify_client() {
+        let (proxy, mut client, server) = start(|stream| stream);
+        proxy.notify_client_bell().unwrap();
+        proxy.set_client_clipboard("blocked").unwrap();
+        match protocol::S2C::read_from(&mut client).unwrap() {
+            protocol::S2C::Bell => (),
+            message => panic!("unexpected {:?}", message)
+        }
+        match protocol::S2C::read_from(&mut client).unwrap() {
+            protocol::S2C::CutText(ref text) if text == "blocked" => (),
+            message => panic!("unexpected {:?}", message)
+        }
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,145 @@
+use std::cmp;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::{Error, Result};
+use crate::client::{AuthMethod, AuthChoice, Builder, Client};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // Gives up after this many connection attempts; 0 retries forever.
+    pub max_attempts:  u32,
+    // Gives up once the next attempt would start later than this after the first one.
+    pub max_time:      Option<Duration>,
+    // The delay before the second attempt; it doubles after every failure, up to max_delay.
+    pub initial_delay: Duration,
+    pub max_delay:     Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts:  10,
+            max_time:      None,
+            initial_delay: Duration::from_millis(500),
+            max_delay:     Duration::from_secs(30),
+        }
+    }
+}
+
+// Failures that may go away by themselves; there is no point in retrying a rejected
+// password or a server speaking something that is not RFB.
+fn is_transient(error: &Error) -> bool {
+    matches!(error, Error::Io(_) | Error::Timeout | Error::Disconnected | Error::Server(_))
+}
+
+// Connects and completes the handshake, retrying both with exponential backoff.
+// Returns the error of the last attempt once the policy is exhausted.
+pub fn connect_with_retry<A, Auth>(address: A, builder: &Builder, mut auth: Auth,
+                                   policy: &RetryPolicy) -> Result<Client>
+        where A: ToSocketAddrs, Auth: FnMut(&[AuthMethod]) -> Option<AuthChoice> {
+    let started = Instant::now();
+    let mut delay = policy.initial_delay;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error =
+            match TcpStream::connect(&address) {
+                Ok(stream) =>
+                    match builder.clone().connect(stream, &mut auth) {
+                        Ok(client) => return Ok(client),
+                        Err(error) => error
+                    },
+                Err(error) => Error::Io(error)
+            };
+
+        if !is_transient(&error) ||
+                (policy.max_attempts != 0 && attempt >= policy.max_attempts) ||
+                policy.max_time.is_some_and(|max_time| started.elapsed() + delay > max_time) {
+            return Err(error)
+        }
+        warn!("connection attempt {} failed: {}, retrying in {:?}", attempt, error, delay);
+        thread::sleep(delay);
+        delay = cmp::min(delay * 2, policy.max_delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use crate::protocol::{self, Message};
+    use crate::client::{AuthChoice, Builder};
+    use crate::Error;
+    use super::{RetryPolicy, connect_with_retry};
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay:     Duration::from_millis(4),
+            ..RetryPolicy::default()
+        }
+    }
+
+    // Drops the first `refused` connections right away, then completes a handshake.
+    fn flaky_server(refused: usize) -> (TcpListener, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.try_clone().unwrap();
+        let handle = thread::spawn(move || {
+            for _ in 0..refused {
+                drop(server.accept().unwrap());
+            }
+            let mut stream = server.accept().unwrap().0;
+            stream.write_all(b"RFB 003.008\n").unwrap();
+            stream.read_exact(&mut [0; 12]).unwrap();
+            protocol::SecurityTypes(vec![protocol::SecurityType::None])
+                .write_to(&mut stream).unwrap();
+            protocol::SecurityType::read_from(&mut stream).unwrap();
+            protocol::SecurityResult::Succeeded.write_to(&mut stream).unwrap();
+            protocol::ClientInit::read_from(&mut stream).unwrap();
+            protocol::ServerInit {
+                framebuffer_width:  100,
+                framebuffer_height: 100,
+                pixel_format:       protocol::PixelFormat {
+                    bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+                    red_max: 255, green_max: 255, blue_max: 255,
+                    red_shift: 16, green_shift: 8, blue_shift: 0
+                },
+                name:               String::from("flaky")
+            }.write_to(&mut stream).unwrap();
+        });
+        (listener, handle)
+    }
+
+    #[test]
+    fn retry_until_accepted() {
+        let (listener, server) = flaky_server(3);
+        let client = connect_with_retry(listener.local_addr().unwrap(), &Builder::new(),
+                                        |_| Some(AuthChoice::None), &policy(5)).unwrap();
+        assert_eq!(client.name(), "flaky");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn give_up() {
+        let (listener, _server) = flaky_server(3);
+        match connect_with_retry(listener.local_addr().unwrap(), &Builder::new(),
+                                 |_| Some(AuthChoice::None), &policy(2)) {
+            Err(Error::Io(_)) => (),
+            result => panic!("unexpected {:?}", result.map(|client| client.name().to_owned()))
+        }
+    }
+
+    #[test]
+    fn no_retry_on_authentication() {
+        let (listener, _server) = flaky_server(0);
+        match connect_with_retry(listener.local_addr().unwrap(), &Builder::new(),
+                                 |_| None, &policy(5)) {
+            Err(Error::AuthenticationUnavailable) => (),
+            result => panic!("unexpected {:?}", result.map(|client| client.name().to_owned()))
+        }
+    }
+}
@@ -7,3 +7,77 @@ mod md5;
 mod apple;
 #[cfg(feature = "apple-auth")]
 pub use self::apple::apple_auth;
+
+#[cfg(feature = "rsa-aes")]
+pub mod rsa_aes;
+
+// The response to a VNC authentication challenge, for a password truncated or zero-padded
+// to 8 bytes.
+pub fn vnc_auth_response(challenge: &[u8; 16], password: &[u8; 8]) -> [u8; 16] {
+    // Reverse the bits in every byte of password.
+    // DES is 56-bit and as commonly implemented, it takes a 8-octet key
+    // and ignores LSB of every octet; this of course would be bad for
+    // ASCII passwords.
+    //
+    // I've spent *hours* figuring this out.
+    // I hate every single fucker involved in the chain of decisions that
+    // led to this authentication scheme, and doubly so because it is completely
+    // undocumented in what passes for the specification of the RFB protocol.
+    let mut key = [0; 8];
+    for i in 0..8 {
+        let c = password[i];
+        let mut cs = 0u8;
+        for j in 0..8 { cs |= ((c >> j) & 1) << (7 - j) }
+        key[i] = cs;
+    }
+
+    let mut response = [0; 16];
+    response.copy_from_slice(&des(challenge, &key));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{des, vnc_auth_response};
+
+    fn hex(string: &str) -> Vec<u8> {
+        (0..string.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&string[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn key(string: &str) -> [u8; 8] {
+        let mut key = [0; 8];
+        key.copy_from_slice(&hex(string));
+        key
+    }
+
+    #[test]
+    fn des_known_answers() {
+        for &(k, plaintext, ciphertext) in &[
+            ("0000000000000000", "0000000000000000", "8ca64de9c1b123a7"),
+            ("ffffffffffffffff", "ffffffffffffffff", "7359b2163e4edc58"),
+            ("3000000000000000", "1000000000000001", "958e6e627a05557b"),
+            ("1111111111111111", "1111111111111111", "f40379ab9e0ec533"),
+            ("0123456789abcdef", "1111111111111111", "17668dfc7292532d"),
+            ("fedcba9876543210", "0123456789abcdef", "ed39d950fa74bcc4"),
+            ("0123456789abcdef", "4e6f772069732074", "3fa40e8a984d4815"),
+            ("133457799bbcdff1", "0123456789abcdef", "85e813540f0ab405"),
+        ] {
+            assert_eq!(des(&hex(plaintext), &key(k)), hex(ciphertext),
+                       "key {} plaintext {}", k, plaintext);
+        }
+    }
+
+    #[test]
+    fn vnc_auth_known_answers() {
+        let mut challenge = [0; 16];
+        challenge.copy_from_slice(&hex("000102030405060708090a0b0c0d0e0f"));
+        assert_eq!(vnc_auth_response(&challenge, b"password")[..],
+                   hex("b866924125c8eebb9debc1db61c538e2")[..]);
+
+        challenge.copy_from_slice(&hex("fedcba98765432100123456789abcdef"));
+        assert_eq!(vnc_auth_response(&challenge, b"abc\0\0\0\0\0")[..],
+                   hex("e1a7650fb69cf1b19fe42e121c0606b0")[..]);
+    }
+}
@@ -0,0 +1,102 @@
+// The palette filter of the Tight encoding, for use by callers that decode Tight rectangles
+// themselves; the client does not request Tight, and has no Tight decoder of its own.
+
+use crate::{Error, Result};
+
+// With the palette filter, two colours take one bit per pixel, with every row padded to
+// a whole byte (most significant bit first), and 3 to 256 colours take one byte per pixel.
+pub fn palette_data_length(colours: usize, width: usize, height: usize) -> usize {
+    if colours == 2 { width.div_ceil(8) * height } else { width * height }
+}
+
+// Expands palette-filtered `data` into pixels; `palette` holds the colours back to back,
+// `bytes_per_pixel` (1 to 4) bytes each.
+pub fn apply_palette(data: &[u8], width: usize, height: usize,
+                     palette: &[u8], bytes_per_pixel: usize) -> Result<Vec<u8>> {
+    if !(1..=4).contains(&bytes_per_pixel) {
+        return Err(Error::Unexpected("Tight bytes per pixel"))
+    }
+    let colours = palette.len() / bytes_per_pixel;
+    if !(2..=256).contains(&colours) || palette.len() % bytes_per_pixel != 0 {
+        return Err(Error::Unexpected("Tight palette size"))
+    }
+    if data.len() != palette_data_length(colours, width, height) {
+        return Err(Error::Unexpected("Tight palette data length"))
+    }
+
+    let mut pixels = Vec::with_capacity(width * height * bytes_per_pixel);
+    for y in 0..height {
+        for x in 0..width {
+            let index =
+                if colours == 2 {
+                    let byte = data[y * width.div_ceil(8) + x / 8];
+                    ((byte >> (7 - x % 8)) & 1) as usize
+                } else {
+                    data[y * width + x] as usize
+                };
+            if index >= colours {
+                return Err(Error::Unexpected("Tight palette index"))
+            }
+            pixels.extend_from_slice(&palette[index * bytes_per_pixel..
+                                              (index + 1) * bytes_per_pixel]);
+        }
+    }
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Error;
+    use super::{palette_data_length, apply_palette};
+
+    #[test]
+    fn two_colours() {
+        // 10 pixels wide, so every row takes two bytes and the last 6 bits are padding.
+        let palette = [0x00, 0xff];
+        let data = [0b1010_0000, 0b01_111111,
+                    0b0000_0001, 0b10_000000];
+        assert_eq!(palette_data_length(2, 10, 2), data.len());
+        assert_eq!(apply_palette(&data, 10, 2, &palette, 1).unwrap(),
+                   [0xff, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn two_colours_multibyte() {
+        let palette = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        assert_eq!(apply_palette(&[0b011_00000], 3, 1, &palette, 3).unwrap(),
+                   [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x44, 0x55, 0x66]);
+    }
+
+    #[test]
+    fn byte_per_index() {
+        for &colours in &[3, 4, 16, 17, 255, 256] {
+            let palette = (0..colours).map(|i| (i & 0xff) as u8).collect::<Vec<_>>();
+            let data = (0..9).map(|i| ((i * 37) % colours) as u8).collect::<Vec<_>>();
+            assert_eq!(palette_data_length(colours, 3, 3), 9);
+            assert_eq!(apply_palette(&data, 3, 3, &palette, 1).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        match apply_palette(&[3], 1, 1, &[0, 1, 2], 1) {
+            Err(Error::Unexpected("Tight palette index")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match apply_palette(&[0; 2], 1, 1, &[0, 1, 2], 1) {
+            Err(Error::Unexpected("Tight palette data length")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        match apply_palette(&[0], 1, 1, &[0], 1) {
+            Err(Error::Unexpected("Tight palette size")) => (),
+            result => panic!("unexpected {:?}", result)
+        }
+        for bytes_per_pixel in [0, 5] {
+            match apply_palette(&[0], 1, 1, &[0, 1], bytes_per_pixel) {
+                Err(Error::Unexpected("Tight bytes per pixel")) => (),
+                result => panic!("unexpected {:?}", result)
+            }
+        }
+    }
+}
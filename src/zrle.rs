@@ -1,3 +1,4 @@
+use std::cmp;
 use std::io::Read;
 use byteorder::ReadBytesExt;
 use crate::{protocol, Error, Result, Rect};
@@ -100,15 +101,50 @@ impl<T: Read> Read for BitReader<T> {
     }
 }
 
+// The size of a CPIXEL, and whether it leaves out the first byte of the pixel (rather than
+// the last one) when that is smaller.
+fn compressed_pixel(format: protocol::PixelFormat) -> (usize, bool) {
+    let pixel_mask =
+        (format.red_max   as u32).checked_shl(format.red_shift   as u32).unwrap_or(0) |
+        (format.green_max as u32).checked_shl(format.green_shift as u32).unwrap_or(0) |
+        (format.blue_max  as u32).checked_shl(format.blue_shift  as u32).unwrap_or(0);
+    let fits_in_ls3_bytes = pixel_mask & 0xff000000 == 0;
+    let fits_in_ms3_bytes = pixel_mask & 0x000000ff == 0;
+    // If both do, e.g. with a depth of 16, the first three bytes in memory are kept,
+    // as TigerVNC does.
+    let (fits_in_first, fits_in_last) =
+        if format.big_endian { (fits_in_ms3_bytes, fits_in_ls3_bytes) }
+        else { (fits_in_ls3_bytes, fits_in_ms3_bytes) };
+
+    if format.bits_per_pixel == 32 && format.true_colour && format.depth <= 24 {
+        if fits_in_first {
+            (3, false)
+        } else if fits_in_last {
+            (3, true)
+        } else {
+            (4, false)
+        }
+    } else {
+        (format.bits_per_pixel as usize / 8, false)
+    }
+}
+
 pub struct Decoder {
     decompressor: Option<flate2::Decompress>
 }
 
+impl Default for Decoder {
+    fn default() -> Decoder { Decoder::new() }
+}
+
 impl Decoder {
     pub fn new() -> Decoder {
         Decoder { decompressor: Some(flate2::Decompress::new(/*zlib_header*/true)) }
     }
 
+    // Decodes the compressed data of a ZRLE rectangle at `rect`, without the length that
+    // precedes it, and passes the pixels of every tile to `callback`, stopping early
+    // if it returns false.
     pub fn decode<F>(&mut self, format: protocol::PixelFormat, rect: Rect,
                  input: &[u8], mut callback: F) -> Result<bool>
             where F: FnMut(Rect, Vec<u8>) -> Result<bool> {
@@ -130,39 +166,31 @@ impl Decoder {
             Ok(())
         }
 
-        fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize, index: u8) {
+        fn copy_indexed(palette: &[u8], pixels: &mut Vec<u8>, bpp: usize,
+                        index: u8) -> Result<()> {
             let start = index as usize * bpp;
-            pixels.extend_from_slice(&palette[start..start + bpp])
+            match palette.get(start..start + bpp) {
+                Some(colour) => {
+                    pixels.extend_from_slice(colour);
+                    Ok(())
+                },
+                None => Err(Error::Unexpected("ZRLE palette index"))
+            }
         }
 
         let bpp = format.bits_per_pixel as usize / 8;
-        let pixel_mask =
-            (format.red_max   as u32) << format.red_shift   |
-            (format.green_max as u32) << format.green_shift |
-            (format.blue_max  as u32) << format.blue_shift;
-
-        let (compressed_bpp, pad_pixel) =
-            if format.bits_per_pixel == 32 && format.true_colour && format.depth <= 24 {
-                if pixel_mask & 0x000000ff == 0 {
-                    (3, !format.big_endian)
-                } else if pixel_mask & 0xff000000 == 0 {
-                    (3, format.big_endian)
-                } else {
-                    (4, false)
-                }
-            } else {
-                (bpp, false)
-            };
+        let (compressed_bpp, pad_pixel) = compressed_pixel(format);
 
         let mut palette = Vec::with_capacity(128 * bpp);
         let mut reader  = BitReader::new(ZlibReader::new(self.decompressor.take().unwrap(), input));
 
         let mut y = 0;
         while y < rect.height {
-            let height = if y + 64 > rect.height { rect.height - y } else { 64 };
+            // Not `y + 64`, which overflows for the last row of a 65535 pixel tall rect.
+            let height = cmp::min(rect.height - y, 64);
             let mut x = 0;
             while x < rect.width {
-                let width = if x + 64 > rect.width { rect.width - x } else { 64 };
+                let width = cmp::min(rect.width - x, 64);
                 let pixel_count = height as usize * width as usize;
 
                 let is_rle = reader.read_bit()?;
@@ -184,7 +212,7 @@ impl Decoder {
                     },
                     (false, 1) => { // Color fill
                         for _ in 0..pixel_count {
-                            copy_indexed(&palette, &mut pixels, bpp, 0)
+                            copy_indexed(&palette, &mut pixels, bpp, 0)?
                         }
                     },
                     (false, 2) | (false, 3..=4) | (false, 5..=16) => { // Indexed pixels
@@ -195,7 +223,7 @@ impl Decoder {
                         for _ in 0..height {
                             for _ in 0..width {
                                 let index = reader.read_bits(bits_per_index)?;
-                                copy_indexed(&palette, &mut pixels, bpp, index)
+                                copy_indexed(&palette, &mut pixels, bpp, index)?
                             }
                             reader.align();
                         }
@@ -208,6 +236,9 @@ impl Decoder {
                             copy_true_color(&mut reader, &mut pixel,
                                                  pad_pixel, compressed_bpp, bpp)?;
                             let run_length = read_run_length(&mut reader)?;
+                            if count + run_length > pixel_count {
+                                return Err(Error::Unexpected("ZRLE run length"))
+                            }
                             for _ in 0..run_length {
                                 pixels.extend(&pixel)
                             }
@@ -225,8 +256,11 @@ impl Decoder {
                                 } else {
                                     1
                                 };
+                            if count + run_length > pixel_count {
+                                return Err(Error::Unexpected("ZRLE run length"))
+                            }
                             for _ in 0..run_length {
-                                copy_indexed(&palette, &mut pixels, bpp, index);
+                                copy_indexed(&palette, &mut pixels, bpp, index)?
                             }
                             count += run_length;
                         }
@@ -249,3 +283,347 @@ impl Decoder {
         Ok(true)
     }
 }
+
+// The counterpart of Decoder; likewise, a single Encoder must produce every ZRLE rectangle
+// sent over a given connection, in order.
+pub struct Encoder {
+    compressor: flate2::Compress
+}
+
+impl Default for Encoder {
+    fn default() -> Encoder { Encoder::new() }
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::with_level(6)
+    }
+
+    // Compresses at zlib `level`, from 0 (no compression) to 9 (smallest output); larger
+    // values are clamped to 9. flate2 only has four levels, so the nearest one is used.
+    pub fn with_level(level: u8) -> Encoder {
+        let compression = match level {
+            0 => flate2::Compression::None,
+            1..=3 => flate2::Compression::Fast,
+            4..=7 => flate2::Compression::Default,
+            _ => flate2::Compression::Best
+        };
+        Encoder { compressor: flate2::Compress::new(compression, /*zlib_header*/true) }
+    }
+
+    // Uses the level the client asked for with a CompressionLevel pseudo-encoding in
+    // its SetEncodings message, or the default level if there is none.
+    pub fn for_encodings(encodings: &[protocol::Encoding]) -> Encoder {
+        encodings.iter().find_map(|encoding| match *encoding {
+            protocol::Encoding::CompressionLevel(level) => Some(Encoder::with_level(level)),
+            _ => None
+        }).unwrap_or_default()
+    }
+
+    // Encodes `pixels`, a tightly packed `width` x `height` image in `format`, and returns
+    // the compressed data of a ZRLE rectangle, without the length that precedes it.
+    pub fn encode(&mut self, format: protocol::PixelFormat, width: u16, height: u16,
+                  pixels: &[u8]) -> Vec<u8> {
+        let input = encode_tiles(format, width, height, pixels);
+        let mut output = Vec::with_capacity(input.len() / 2 + 64);
+        let start = self.compressor.total_in();
+        loop {
+            output.reserve(cmp::max(output.capacity() / 2, 1024));
+            let consumed = (self.compressor.total_in() - start) as usize;
+            self.compressor.compress_vec(&input[consumed..], &mut output, flate2::Flush::Sync);
+            // The flush is complete once all of the input is in and some room was left over.
+            if (self.compressor.total_in() - start) as usize == input.len() &&
+                    output.len() < output.capacity() {
+                return output
+            }
+        }
+    }
+}
+
+// Produces the uncompressed tile data, picking the smallest subencoding for every tile.
+fn encode_tiles(format: protocol::PixelFormat, width: u16, height: u16,
+                pixels: &[u8]) -> Vec<u8> {
+    fn write_run_length(data: &mut Vec<u8>, run_length: usize) {
+        let mut rest = run_length - 1;
+        while rest >= 255 {
+            data.push(255);
+            rest -= 255
+        }
+        data.push(rest as u8)
+    }
+
+    let bpp = format.bits_per_pixel as usize / 8;
+    let (compressed_bpp, pad_pixel) = compressed_pixel(format);
+    let cpixel = pad_pixel as usize..pad_pixel as usize + compressed_bpp;
+    let stride = width as usize * bpp;
+    assert_eq!(pixels.len(), stride * height as usize);
+
+    let mut data = Vec::new();
+    let mut y = 0;
+    while y < height {
+        let tile_height = cmp::min(height - y, 64) as usize;
+        let mut x = 0;
+        while x < width {
+            let tile_width = cmp::min(width - x, 64) as usize;
+            let tile = (y as usize..y as usize + tile_height).flat_map(|row| {
+                let start = row * stride + x as usize * bpp;
+                pixels[start..start + tile_width * bpp].chunks(bpp)
+            }).collect::<Vec<_>>();
+
+            // Palettes have at most 127 colours.
+            let mut palette: Vec<&[u8]> = Vec::new();
+            let mut indices = Vec::with_capacity(tile.len());
+            for &pixel in &tile {
+                match palette.iter().position(|&colour| colour == pixel) {
+                    Some(index) => indices.push(index as u8),
+                    None if palette.len() < 127 => {
+                        indices.push(palette.len() as u8);
+                        palette.push(pixel)
+                    }
+                    None => {
+                        palette.clear();
+                        break
+                    }
+                }
+            }
+            let mut runs = Vec::new();
+            for (index, &pixel) in tile.iter().enumerate() {
+                match runs.last_mut() {
+                    Some(&mut (start, ref mut length)) if tile[start] == pixel => *length += 1,
+                    _ => runs.push((index, 1))
+                }
+            }
+
+            if palette.len() == 1 {
+                data.push(1);
+                data.extend_from_slice(&palette[0][cpixel.clone()]);
+            } else {
+                let run_bytes = |length: usize| (length - 1) / 255 + 1;
+                let palette_bytes = palette.len() * compressed_bpp;
+                let bits_per_index = match palette.len() {
+                    2 => 1, 3..=4 => 2, 5..=16 => 4, _ => 0
+                };
+                let packed = if bits_per_index == 0 { usize::MAX } else {
+                    palette_bytes + tile_height * (tile_width * bits_per_index).div_ceil(8)
+                };
+                let palette_rle = if palette.is_empty() { usize::MAX } else {
+                    palette_bytes + runs.iter().map(|&(_, length)|
+                        if length == 1 { 1 } else { 1 + run_bytes(length) }).sum::<usize>()
+                };
+                let rle = runs.iter().map(|&(_, length)|
+                    compressed_bpp + run_bytes(length)).sum::<usize>();
+                let raw = tile.len() * compressed_bpp;
+
+                let smallest = *[packed, palette_rle, rle, raw].iter().min().unwrap();
+                if smallest == packed {
+                    data.push(palette.len() as u8);
+                    for colour in &palette { data.extend_from_slice(&colour[cpixel.clone()]) }
+                    for row in indices.chunks(tile_width) {
+                        let (mut byte, mut used) = (0, 0);
+                        for &index in row {
+                            byte |= index << (8 - bits_per_index - used);
+                            used += bits_per_index;
+                            if used == 8 {
+                                data.push(byte);
+                                byte = 0;
+                                used = 0
+                            }
+                        }
+                        if used > 0 { data.push(byte) }
+                    }
+                } else if smallest == palette_rle {
+                    data.push(128 + palette.len() as u8);
+                    for colour in &palette { data.extend_from_slice(&colour[cpixel.clone()]) }
+                    for &(start, length) in &runs {
+                        if length == 1 {
+                            data.push(indices[start])
+                        } else {
+                            data.push(0x80 | indices[start]);
+                            write_run_length(&mut data, length)
+                        }
+                    }
+                } else if smallest == rle {
+                    data.push(128);
+                    for &(start, length) in &runs {
+                        data.extend_from_slice(&tile[start][cpixel.clone()]);
+                        write_run_length(&mut data, length)
+                    }
+                } else {
+                    data.push(0);
+                    for pixel in &tile { data.extend_from_slice(&pixel[cpixel.clone()]) }
+                }
+            }
+            x += tile_width as u16;
+        }
+        y += tile_height as u16;
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::{protocol, Rect};
+    use super::{Decoder, Encoder, encode_tiles, compressed_pixel};
+
+    fn rgb888() -> protocol::PixelFormat {
+        protocol::PixelFormat {
+            bits_per_pixel: 32, depth: 24, big_endian: false, true_colour: true,
+            red_max: 255, green_max: 255, blue_max: 255,
+            red_shift: 16, green_shift: 8, blue_shift: 0
+        }
+    }
+
+    fn round_trip(encoder: &mut Encoder, decoder: &mut Decoder, format: protocol::PixelFormat,
+                  width: u16, height: u16, pixels: &[u8]) -> Vec<u8> {
+        let bpp = format.bits_per_pixel as usize / 8;
+        let data = encoder.encode(format, width, height, pixels);
+        let mut decoded = vec![0; pixels.len()];
+        assert!(decoder.decode(format, Rect::new(0, 0, width, height), &data, |tile, tile_pixels| {
+            for (row, tile_row) in tile_pixels.chunks(tile.width as usize * bpp).enumerate() {
+                let start = ((tile.top as usize + row) * width as usize +
+                             tile.left as usize) * bpp;
+                decoded[start..start + tile_row.len()].copy_from_slice(tile_row)
+            }
+            Ok(true)
+        }).unwrap());
+        assert_eq!(decoded, pixels);
+        data
+    }
+
+    #[test]
+    fn subencodings() {
+        let format = rgb888();
+        let pixel = |i: usize| [i as u8, (i >> 8) as u8, 0x55, 0];
+        let tile = |colour: &dyn Fn(usize) -> usize| -> Vec<u8> {
+            (0..64 * 64).flat_map(|i| pixel(colour(i)).to_vec()).collect()
+        };
+        let cases: Vec<(Vec<u8>, u8)> = vec![
+            (tile(&|_| 7), 1),
+            (tile(&|i| i % 2), 2),
+            (tile(&|i| i % 16), 16),
+            (tile(&|i| i / 4 % 20), 128 + 20),
+            (tile(&|i| i / 4), 128),
+            (tile(&|i| i), 0),
+        ];
+
+        let (mut encoder, mut decoder) = (Encoder::new(), Decoder::new());
+        for (pixels, subencoding) in &cases {
+            assert_eq!(encode_tiles(format, 64, 64, pixels)[0], *subencoding);
+            round_trip(&mut encoder, &mut decoder, format, 64, 64, pixels);
+        }
+    }
+
+    #[test]
+    fn partial_tiles() {
+        let formats = [
+            rgb888(),
+            protocol::PixelFormat { big_endian: true, ..rgb888() },
+            protocol::PixelFormat { bits_per_pixel: 16, depth: 16,
+                                    red_max: 31, green_max: 63, blue_max: 31,
+                                    red_shift: 11, green_shift: 5, blue_shift: 0,
+                                    ..rgb888() },
+            protocol::PixelFormat { bits_per_pixel: 8, depth: 8, true_colour: false,
+                                    ..rgb888() },
+        ];
+        let (mut encoder, mut decoder) = (Encoder::new(), Decoder::new());
+        for &format in &formats {
+            let bpp = format.bits_per_pixel as usize / 8;
+            // A gradient with long runs at the bottom, spanning 3x2 tiles.
+            let (width, height) = (130, 70);
+            let pixels = (0..width * height).flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                let value = if y < 64 { (x * 3 + y) as u32 } else { (x / 40) as u32 };
+                let mut bytes = [0; 4];
+                for (index, byte) in bytes.iter_mut().take(bpp).enumerate() {
+                    *byte = (value >> (8 * index)) as u8
+                }
+                if format.big_endian { bytes[..bpp].reverse() }
+                if bpp == 4 { bytes[if format.big_endian { 0 } else { 3 }] = 0 }
+                bytes[..bpp].to_vec()
+            }).collect::<Vec<_>>();
+            round_trip(&mut encoder, &mut decoder, format, width as u16, height as u16, &pixels);
+            round_trip(&mut encoder, &mut decoder, format, 1, 1, &pixels[..bpp]);
+        }
+    }
+
+    #[test]
+    fn compression_levels() {
+        // Two identical tiles with no repeated pixels, which only zlib can shrink.
+        let (width, height) = (128, 64);
+        let pixels = (0..width * height).flat_map(|i| {
+            let (x, y) = (i % width % 64, i / width);
+            vec![x as u8, y as u8, (x * y) as u8, 0]
+        }).collect::<Vec<_>>();
+
+        let mut sizes = Vec::new();
+        for level in 0..=9 {
+            let (mut encoder, mut decoder) = (Encoder::with_level(level), Decoder::new());
+            // The second rectangle reuses the zlib stream.
+            let data = round_trip(&mut encoder, &mut decoder, rgb888(), width, height, &pixels);
+            sizes.push(data.len());
+            round_trip(&mut encoder, &mut decoder, rgb888(), width, height, &pixels);
+        }
+        assert!(sizes[0] > pixels.len() * 3 / 4, "{:?}", sizes);
+        assert!(sizes[1] < sizes[0] && sizes[6] <= sizes[1] && sizes[9] <= sizes[6],
+                "{:?}", sizes);
+
+        let encode = |mut encoder: Encoder| encoder.encode(rgb888(), width, height, &pixels);
+        assert_eq!(encode(Encoder::for_encodings(&[protocol::Encoding::Zrle,
+                                                   protocol::Encoding::CompressionLevel(0)])),
+                   encode(Encoder::with_level(0)));
+        assert_eq!(encode(Encoder::for_encodings(&[protocol::Encoding::Zrle])),
+                   encode(Encoder::new()));
+        assert_eq!(encode(Encoder::with_level(200)), encode(Encoder::with_level(9)));
+    }
+
+    #[test]
+    fn compressed_pixels() {
+        let rgb888 = rgb888();
+        let bgr888_high = protocol::PixelFormat {
+            red_shift: 8, green_shift: 16, blue_shift: 24, ..rgb888
+        };
+        let rgb565_in_32 = protocol::PixelFormat {
+            depth: 16, red_max: 31, green_max: 63, blue_max: 31,
+            red_shift: 19, green_shift: 13, blue_shift: 8, ..rgb888
+        };
+        let cases = [
+            // The most significant byte is unused.
+            (rgb888, (3, false), [0x33, 0x22, 0x11, 0]),
+            (protocol::PixelFormat { big_endian: true, ..rgb888 }, (3, true),
+             [0, 0x11, 0x22, 0x33]),
+            // The least significant byte is unused.
+            (bgr888_high, (3, true), [0, 0x11, 0x22, 0x33]),
+            (protocol::PixelFormat { big_endian: true, ..bgr888_high }, (3, false),
+             [0x33, 0x22, 0x11, 0]),
+            // Neither is used.
+            (rgb565_in_32, (3, false), [0x00, 0x11, 0x22, 0]),
+            (protocol::PixelFormat { big_endian: true, ..rgb565_in_32 }, (3, false),
+             [0, 0x22, 0x11, 0x00]),
+            // Both are used, or the depth is too large, or it's not true colour.
+            (protocol::PixelFormat { red_shift: 24, ..rgb888 }, (4, false),
+             [0x11, 0x22, 0x33, 0x44]),
+            (protocol::PixelFormat { depth: 32, ..rgb888 }, (4, false),
+             [0x11, 0x22, 0x33, 0x44]),
+            (protocol::PixelFormat { true_colour: false, ..rgb888 }, (4, false),
+             [0x11, 0x22, 0x33, 0x44]),
+        ];
+        for &(format, (size, pad), pixel) in &cases {
+            assert_eq!(compressed_pixel(format), (size, pad), "{:?}", format);
+
+            // A 1x1 raw tile.
+            let mut tile = vec![0];
+            tile.extend_from_slice(&pixel[pad as usize..pad as usize + size]);
+            let mut zlib = flate2::write::ZlibEncoder::new(Vec::new(),
+                                                           flate2::Compression::Default);
+            zlib.write_all(&tile).unwrap();
+            zlib.flush().unwrap();
+            let mut decoded = None;
+            Decoder::new().decode(format, Rect::new(0, 0, 1, 1), zlib.get_ref(), |_, pixels| {
+                decoded = Some(pixels);
+                Ok(true)
+            }).unwrap();
+            assert_eq!(decoded.unwrap(), pixel, "{:?}", format);
+        }
+    }
+}